@@ -15,7 +15,7 @@ fn main() {
 
         for color in colors {
             let state = LightState {
-                color: color,
+                color,
                 flash_mode: FlashMode::Timed,
                 flash_on_ms: 500,
                 flash_off_ms: 500,
@@ -4,7 +4,7 @@
 
 extern crate gonkhal;
 
-use gonkhal::Vibrator;
+use gonkhal::{VibrationPattern, Vibrator};
 use std::{thread, time};
 
 fn main() {
@@ -16,10 +16,9 @@ fn main() {
         thread::sleep(time::Duration::from_millis(1500));
 
         println!("Sending a morse code S.O.S");
-        Vibrator::pattern(&vibrator,
-                          vec![100, 30, 100, 30, 100, 200, 200, 30, 200, 30, 200, 200, 100, 30,
-                               100, 30, 100]);
-        thread::sleep(time::Duration::from_millis(2000));
+        let sos = VibrationPattern::morse("SOS", time::Duration::from_millis(100)).unwrap();
+        Vibrator::play_pattern(&vibrator, &sos, None);
+        thread::sleep(sos.total_duration() + time::Duration::from_millis(500));
 
         println!("Start a 3s vibration but cancel it after 1s.");
         let mut guard = Vibrator::pattern(&vibrator, vec![3000]);
@@ -28,6 +27,15 @@ fn main() {
         println!("Should stop now!");
         thread::sleep(time::Duration::from_millis(3000));
 
+        if vibrator.supports_amplitude() {
+            println!("Ramping up the amplitude.");
+            Vibrator::amplitude_pattern(&vibrator,
+                                        (1..6)
+                                            .map(|i| (time::Duration::from_millis(200), i * 50))
+                                            .collect());
+            thread::sleep(time::Duration::from_millis(1500));
+        }
+
         println!("All done.");
     } else {
         println!("This device doesn't have a vibrator.");
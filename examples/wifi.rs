@@ -4,10 +4,10 @@
 
 extern crate gonkhal;
 
-use gonkhal::Wifi;
+use gonkhal::WifiInterface;
 
-fn send_command(command: &str) {
-    match Wifi::command(&format!("IFNAME=wlan0 {}", command)) {
+fn send_command(wifi: &WifiInterface, command: &str) {
+    match wifi.command(command) {
         Err(err) => println!("Error sending `{}`: {}", command, err),
         Ok(response) => println!("Response: {}", response),
     }
@@ -16,12 +16,14 @@ fn send_command(command: &str) {
 fn main() {
     println!("GonkHal wifi demo...");
 
-    // send_command("LOGLEVEL DEBUG");
+    let wifi = WifiInterface::new("wlan0");
 
-    if !Wifi::is_driver_loaded() {
+    // send_command(&wifi, "LOGLEVEL DEBUG");
+
+    if !wifi.is_driver_loaded() {
         println!("Loading Wifi driver...");
-        Wifi::load_driver().expect("Failed to load Wifi driver");
-        if !Wifi::is_driver_loaded() {
+        wifi.load_driver().expect("Failed to load Wifi driver");
+        if !wifi.is_driver_loaded() {
             println!("Wifi driver is still not loaded, aborting :(");
             return;
         }
@@ -33,9 +35,10 @@ fn main() {
     // once we call start_supplicant() after.
     // TODO: figure out why we really have to do that since `primary_iface` is
     // a `static char*` ....
-    Wifi::stop_supplicant(false).expect("Failed to stop supplicant");
+    wifi.stop_supplicant(false)
+        .expect("Failed to stop supplicant");
 
-    match Wifi::start_supplicant(false) {
+    match wifi.start_supplicant(false) {
         Ok(()) => println!("Supplicant started."),
         Err(code) => {
             println!("Failed to start supplicant: err={}", code);
@@ -43,7 +46,7 @@ fn main() {
         }
     }
 
-    match Wifi::connect_to_supplicant() {
+    match wifi.connect_to_supplicant() {
         Ok(()) => println!("Connected to supplicant."),
         Err(code) => {
             println!("Failed to connect to supplicant: err={}", code);
@@ -51,25 +54,24 @@ fn main() {
         }
     }
 
-    fn get_event() {
-        match Wifi::wait_for_event() {
-            Err(_) => println!("Error getting event!"),
-            Ok(event) => {
-                if !event.ends_with("CTRL-EVENT-TERMINATING  - connection closed") {
-                    println!("Event: {}", event);
-                }
-            }
-        }
-    }
+    let events = wifi.subscribe();
 
-    let commands = ["STATUS", "SCAN TYPE=ONLY", "SCAN_INTERVAL 15", "AUTOSCAN periodic:15"];
+    let commands = [
+        "STATUS",
+        "SCAN TYPE=ONLY",
+        "SCAN_INTERVAL 15",
+        "AUTOSCAN periodic:15",
+    ];
 
     for command in commands.iter() {
         println!("-> Send {}", command);
-        send_command(command);
+        send_command(&wifi, command);
     }
 
-    loop {
-        get_event();
+    for event in events {
+        if !event.ends_with("CTRL-EVENT-TERMINATING  - connection closed") {
+            println!("Event: {}", event);
+        }
     }
+    println!("Supplicant connection closed.");
 }
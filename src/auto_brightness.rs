@@ -0,0 +1,559 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use backlight::{Backlight, Brightness};
+use lights::BrightnessMode;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where ambient light readings come from, in lux.
+///
+/// Closures returning readings are sources too, which plugs in other
+/// sensors or test feeds.
+pub trait LuxSource: Send {
+    /// Reads the current illuminance.
+    fn read(&mut self) -> io::Result<f32>;
+}
+
+impl<F: FnMut() -> io::Result<f32> + Send> LuxSource for F {
+    fn read(&mut self) -> io::Result<f32> {
+        self()
+    }
+}
+
+fn read_value(path: &Path) -> io::Result<f32> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+    content.trim().parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Invalid value in {}: {}", path.display(), content.trim()),
+        )
+    })
+}
+
+/// An ambient light sensor of the kernel IIO subsystem.
+pub struct IioLightSensor {
+    dir: PathBuf,
+}
+
+impl IioLightSensor {
+    /// Finds the first light sensor in `/sys`, or returns None if the
+    /// device doesn't have one.
+    pub fn find() -> Option<Self> {
+        IioLightSensor::find_with_root("/sys")
+    }
+
+    /// Finds the first light sensor in another sysfs root.
+    pub fn find_with_root<P: AsRef<Path>>(root: P) -> Option<Self> {
+        let mut dirs: Vec<PathBuf> = fs::read_dir(root.as_ref().join("bus/iio/devices"))
+            .ok()?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect();
+        dirs.sort();
+        dirs.into_iter()
+            .find(|dir| {
+                dir.join("in_illuminance_input").exists() || dir.join("in_illuminance_raw").exists()
+            })
+            .map(IioLightSensor::open)
+    }
+
+    /// Uses the sensor of an IIO device directory.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Self {
+        IioLightSensor {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl LuxSource for IioLightSensor {
+    /// Reads the processed value if the driver has one, or scales the raw
+    /// one.
+    fn read(&mut self) -> io::Result<f32> {
+        match read_value(&self.dir.join("in_illuminance_input")) {
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => return result,
+        }
+        let raw = read_value(&self.dir.join("in_illuminance_raw"))?;
+        let offset = read_value(&self.dir.join("in_illuminance_offset")).unwrap_or(0.0);
+        let scale = read_value(&self.dir.join("in_illuminance_scale")).unwrap_or(1.0);
+        Ok((raw + offset) * scale)
+    }
+}
+
+/// Maps lux to brightness levels through control points. The levels follow
+/// a monotone cubic spline, smooth without overshooting the points.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LuxSpline {
+    lux: Vec<f32>,
+    levels: Vec<f32>,
+    // The tangents at the control points.
+    slopes: Vec<f32>,
+}
+
+impl Default for LuxSpline {
+    /// A curve for common phone displays.
+    fn default() -> Self {
+        LuxSpline::new(&[
+            (0.0, 0.05),
+            (10.0, 0.15),
+            (100.0, 0.35),
+            (1000.0, 0.7),
+            (10000.0, 1.0),
+        ])
+        .unwrap()
+    }
+}
+
+impl LuxSpline {
+    /// Creates a spline from `(lux, level)` points, with increasing lux and
+    /// levels from 0 to 1.
+    pub fn new(points: &[(f32, f32)]) -> io::Result<Self> {
+        if points.is_empty() || points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Brightness spline points need increasing lux",
+            ));
+        }
+        if points
+            .iter()
+            .any(|point| !point.0.is_finite() || !point.1.is_finite())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Brightness spline points must be finite",
+            ));
+        }
+        let lux: Vec<f32> = points.iter().map(|point| point.0).collect();
+        let levels: Vec<f32> = points.iter().map(|point| point.1.clamp(0.0, 1.0)).collect();
+
+        // Fritsch-Carlson tangents.
+        let n = points.len();
+        let secants: Vec<f32> = (0..n - 1)
+            .map(|i| (levels[i + 1] - levels[i]) / (lux[i + 1] - lux[i]))
+            .collect();
+        let mut slopes = vec![0.0; n];
+        if n > 1 {
+            slopes[0] = secants[0];
+            slopes[n - 1] = secants[n - 2];
+            for i in 1..n - 1 {
+                if secants[i - 1] * secants[i] > 0.0 {
+                    slopes[i] = (secants[i - 1] + secants[i]) / 2.0;
+                }
+            }
+            for i in 0..n - 1 {
+                if secants[i] == 0.0 {
+                    slopes[i] = 0.0;
+                    slopes[i + 1] = 0.0;
+                    continue;
+                }
+                let a = slopes[i] / secants[i];
+                let b = slopes[i + 1] / secants[i];
+                let h = a * a + b * b;
+                if h > 9.0 {
+                    let t = 3.0 / h.sqrt();
+                    slopes[i] = t * a * secants[i];
+                    slopes[i + 1] = t * b * secants[i];
+                }
+            }
+        }
+
+        Ok(LuxSpline {
+            lux,
+            levels,
+            slopes,
+        })
+    }
+
+    /// The brightness level for some illuminance. Readings that aren't
+    /// finite get the first level.
+    pub fn level(&self, lux: f32) -> Brightness {
+        let last = self.lux.len() - 1;
+        if !lux.is_finite() || lux <= self.lux[0] {
+            return Brightness::from(self.levels[0]);
+        }
+        if lux >= self.lux[last] {
+            return Brightness::from(self.levels[last]);
+        }
+        let i = self.lux.iter().rposition(|&x| x <= lux).unwrap();
+        let h = self.lux[i + 1] - self.lux[i];
+        let t = (lux - self.lux[i]) / h;
+        let (t2, t3) = (t * t, t * t * t);
+        let level = (2.0 * t3 - 3.0 * t2 + 1.0) * self.levels[i]
+            + (t3 - 2.0 * t2 + t) * h * self.slopes[i]
+            + (-2.0 * t3 + 3.0 * t2) * self.levels[i + 1]
+            + (t3 - t2) * h * self.slopes[i + 1];
+        Brightness::from(level)
+    }
+}
+
+/// How an `AutoBrightness` controller follows the light. The default has
+/// the default spline, a 10% brightening and 20% darkening hysteresis,
+/// debounced over 2 and 4 seconds.
+#[derive(Clone, Debug)]
+pub struct AutoBrightnessConfig {
+    spline: LuxSpline,
+    // How much brighter or darker, relatively, the light must get to
+    // change the brightness.
+    brightening: f32,
+    darkening: f32,
+    // How long the light must stay so before changing it.
+    brightening_debounce: Duration,
+    darkening_debounce: Duration,
+    poll_interval: Duration,
+    ramp_duration: Duration,
+}
+
+impl Default for AutoBrightnessConfig {
+    fn default() -> Self {
+        AutoBrightnessConfig {
+            spline: LuxSpline::default(),
+            brightening: 0.1,
+            darkening: 0.2,
+            brightening_debounce: Duration::from_secs(2),
+            darkening_debounce: Duration::from_secs(4),
+            poll_interval: Duration::from_millis(250),
+            ramp_duration: Duration::from_millis(500),
+        }
+    }
+}
+
+impl AutoBrightnessConfig {
+    /// Sets how lux map to brightness levels.
+    pub fn with_spline(mut self, spline: LuxSpline) -> Self {
+        self.spline = spline;
+        self
+    }
+
+    /// Sets how much brighter or darker, as fractions of the illuminance
+    /// the brightness was set for, the light must get to change it.
+    pub fn with_hysteresis(mut self, brightening: f32, darkening: f32) -> Self {
+        self.brightening = brightening;
+        self.darkening = darkening;
+        self
+    }
+
+    /// Sets how long the light must stay brighter or darker before the
+    /// brightness changes.
+    pub fn with_debounce(mut self, brightening: Duration, darkening: Duration) -> Self {
+        self.brightening_debounce = brightening;
+        self.darkening_debounce = darkening;
+        self
+    }
+
+    /// Sets how often the source is read.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Sets how long the brightness takes to reach a new level.
+    pub fn with_ramp_duration(mut self, duration: Duration) -> Self {
+        self.ramp_duration = duration;
+        self
+    }
+}
+
+struct State {
+    config: AutoBrightnessConfig,
+    lux: Option<f32>,
+    // Set when the spline changed, to map the light again right away.
+    reevaluate: bool,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Adjusts the backlight to the ambient light from its own thread, while
+/// the backlight is in `BrightnessMode::Sensor`.
+///
+/// The brightness only follows changes larger than the hysteresis that
+/// last longer than the debounce delay, so it doesn't flicker with passing
+/// shadows.
+pub struct AutoBrightness {
+    shared: Arc<Shared>,
+}
+
+impl AutoBrightness {
+    /// Starts polling a source with the default configuration.
+    pub fn new(backlight: Arc<Backlight>, source: Box<dyn LuxSource>) -> Self {
+        AutoBrightness::with_config(backlight, source, AutoBrightnessConfig::default())
+    }
+
+    /// Starts polling a source with some configuration.
+    pub fn with_config(
+        backlight: Arc<Backlight>,
+        source: Box<dyn LuxSource>,
+        config: AutoBrightnessConfig,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                config,
+                lux: None,
+                reevaluate: false,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("auto brightness".to_owned())
+            .spawn(move || AutoBrightness::run(&backlight, source, &thread_shared))
+            .expect("Failed to start auto brightness thread!");
+        AutoBrightness { shared }
+    }
+
+    /// Changes how lux map to brightness levels. The brightness follows the
+    /// new spline at the next reading, without waiting for the light to
+    /// change.
+    pub fn set_spline(&self, spline: LuxSpline) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.config.spline = spline;
+            state.reevaluate = true;
+        }
+        self.shared.changed.notify_all();
+    }
+
+    /// The last illuminance read, if any.
+    pub fn lux(&self) -> Option<f32> {
+        self.shared.state.lock().unwrap().lux
+    }
+
+    fn run(backlight: &Backlight, mut source: Box<dyn LuxSource>, shared: &Shared) {
+        // The illuminance the brightness was set for, and the change
+        // waiting for its debounce delay: brightening or not, and since when.
+        let mut reference: Option<f32> = None;
+        let mut pending: Option<(bool, Instant)> = None;
+        loop {
+            let lux = source
+                .read()
+                .ok()
+                .filter(|lux| lux.is_finite() && *lux >= 0.0);
+            let mut state = shared.state.lock().unwrap();
+            if state.shutdown {
+                return;
+            }
+            if lux.is_some() {
+                state.lux = lux;
+            }
+            if state.reevaluate {
+                state.reevaluate = false;
+                reference = None;
+                pending = None;
+            }
+
+            if backlight.mode() != BrightnessMode::Sensor {
+                // Follow the light right away when handed over again.
+                reference = None;
+                pending = None;
+            } else if let Some(lux) = lux {
+                let config = &state.config;
+                let apply = match reference {
+                    None => true,
+                    Some(reference) => {
+                        let brighter = lux > reference * (1.0 + config.brightening);
+                        let darker = lux < reference * (1.0 - config.darkening);
+                        if brighter || darker {
+                            let now = Instant::now();
+                            let since = match pending {
+                                Some((brightening, since)) if brightening == brighter => since,
+                                _ => now,
+                            };
+                            pending = Some((brighter, since));
+                            let debounce = if brighter {
+                                config.brightening_debounce
+                            } else {
+                                config.darkening_debounce
+                            };
+                            now - since >= debounce
+                        } else {
+                            pending = None;
+                            false
+                        }
+                    }
+                };
+                // The mode is checked again with the ramp, in case the
+                // user took the brightness back meanwhile.
+                if apply && backlight.sensor_ramp_to(config.spline.level(lux), config.ramp_duration)
+                {
+                    reference = Some(lux);
+                    pending = None;
+                }
+            }
+
+            let interval = state.config.poll_interval;
+            let _ = shared.changed.wait_timeout(state, interval).unwrap();
+        }
+    }
+}
+
+impl Drop for AutoBrightness {
+    /// Stops adjusting the backlight.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backlight::BrightnessCurve;
+    use lights::{Light, LightState};
+    use std::env;
+    use std::process;
+
+    struct FakeLight;
+
+    impl Light for FakeLight {
+        fn set(&self, _state: LightState) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn spline_is_monotone_through_its_points() {
+        let points = [
+            (0.0, 0.1),
+            (5.0, 0.12),
+            (50.0, 0.6),
+            (60.0, 0.6),
+            (1000.0, 1.0),
+        ];
+        let spline = LuxSpline::new(&points).unwrap();
+        for &(lux, level) in &points {
+            assert!((spline.level(lux).fraction() - level).abs() < 1e-5);
+        }
+        let mut previous = 0.0;
+        for i in 0..1200 {
+            let level = spline.level(i as f32).fraction();
+            assert!(level >= previous, "Not monotone at {} lux", i);
+            previous = level;
+        }
+        assert_eq!(spline.level(2000.0).fraction(), 1.0);
+    }
+
+    #[test]
+    fn spline_rejects_and_survives_bad_values() {
+        assert!(LuxSpline::new(&[]).is_err());
+        assert!(LuxSpline::new(&[(10.0, 0.5), (5.0, 0.6)]).is_err());
+        assert!(LuxSpline::new(&[(0.0, 0.5), (f32::NAN, 0.6)]).is_err());
+        assert!(LuxSpline::new(&[(0.0, 0.5), (10.0, f32::INFINITY)]).is_err());
+
+        let spline = LuxSpline::default();
+        assert_eq!(spline.level(f32::NAN), spline.level(0.0));
+        assert_eq!(spline.level(f32::INFINITY), spline.level(0.0));
+    }
+
+    #[test]
+    fn iio_sensor_only_falls_back_to_raw_values_when_missing() {
+        let dir = env::temp_dir().join(format!("gonkhal-iio-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("in_illuminance_raw"), "40\n").unwrap();
+        fs::write(dir.join("in_illuminance_offset"), "10\n").unwrap();
+        fs::write(dir.join("in_illuminance_scale"), "0.5\n").unwrap();
+        let mut sensor = IioLightSensor::open(&dir);
+        assert_eq!(sensor.read().unwrap(), 25.0);
+
+        fs::write(dir.join("in_illuminance_input"), "garbage\n").unwrap();
+        assert_eq!(
+            sensor.read().unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        fs::write(dir.join("in_illuminance_input"), "123.5\n").unwrap();
+        assert_eq!(sensor.read().unwrap(), 123.5);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn wait_for(backlight: &Backlight, level: Brightness) -> bool {
+        for _ in 0..200 {
+            if backlight.brightness() == level {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn brightness_follows_lasting_changes_only() {
+        let lux = Arc::new(Mutex::new(100.0));
+        let source_lux = lux.clone();
+        let backlight =
+            Arc::new(Backlight::new(Box::new(FakeLight)).with_curve(BrightnessCurve::Linear));
+        backlight.set_mode(BrightnessMode::Sensor);
+        let spline = LuxSpline::new(&[(0.0, 0.0), (1000.0, 1.0)]).unwrap();
+        let _auto = AutoBrightness::with_config(
+            backlight.clone(),
+            Box::new(move || Ok(*source_lux.lock().unwrap())),
+            AutoBrightnessConfig::default()
+                .with_spline(spline.clone())
+                .with_debounce(Duration::from_millis(150), Duration::from_millis(150))
+                .with_poll_interval(Duration::from_millis(10))
+                .with_ramp_duration(Duration::from_secs(0)),
+        );
+        let wait_for = |level| wait_for(&backlight, level);
+        assert!(wait_for(spline.level(100.0)));
+
+        // Within the hysteresis.
+        *lux.lock().unwrap() = 105.0;
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(backlight.brightness(), spline.level(100.0));
+
+        // A passing shadow is ignored.
+        *lux.lock().unwrap() = 20.0;
+        thread::sleep(Duration::from_millis(50));
+        *lux.lock().unwrap() = 100.0;
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(backlight.brightness(), spline.level(100.0));
+
+        // A lasting change is followed once debounced.
+        let start = Instant::now();
+        *lux.lock().unwrap() = 500.0;
+        assert!(wait_for(spline.level(500.0)));
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[test]
+    fn spline_changes_apply_in_sensor_mode_only() {
+        let backlight =
+            Arc::new(Backlight::new(Box::new(FakeLight)).with_curve(BrightnessCurve::Linear));
+        backlight.set_mode(BrightnessMode::Sensor);
+        let dim = LuxSpline::new(&[(0.0, 0.0), (1000.0, 0.5)]).unwrap();
+        let bright = LuxSpline::new(&[(0.0, 0.5), (1000.0, 1.0)]).unwrap();
+        // The light never changes for long enough to be followed, so only
+        // the first reading and spline changes set the brightness.
+        let auto = AutoBrightness::with_config(
+            backlight.clone(),
+            Box::new(|| Ok(100.0)),
+            AutoBrightnessConfig::default()
+                .with_spline(dim.clone())
+                .with_debounce(Duration::from_secs(3600), Duration::from_secs(3600))
+                .with_poll_interval(Duration::from_millis(10))
+                .with_ramp_duration(Duration::from_secs(0)),
+        );
+        assert!(wait_for(&backlight, dim.level(100.0)));
+
+        auto.set_spline(bright.clone());
+        assert!(wait_for(&backlight, bright.level(100.0)));
+
+        // The user's brightness is left alone.
+        backlight.set_mode(BrightnessMode::User);
+        backlight.set_brightness(0.3);
+        auto.set_spline(dim.clone());
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(backlight.brightness(), Brightness::from(0.3));
+
+        backlight.set_mode(BrightnessMode::Sensor);
+        assert!(wait_for(&backlight, dim.level(100.0)));
+    }
+}
@@ -0,0 +1,369 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use light_animation::DEFAULT_FRAME_RATE;
+use lights::{open_light, BrightnessMode, Light, LightKind, LightState};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A brightness level, from 0 to 1. It converts from `u8` levels, 255
+/// being the brightest, and from `f32` fractions.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub struct Brightness(f32);
+
+impl Brightness {
+    /// The level, from 0 to 1.
+    pub fn fraction(&self) -> f32 {
+        self.0
+    }
+}
+
+impl From<u8> for Brightness {
+    fn from(level: u8) -> Self {
+        Brightness(level as f32 / 255.0)
+    }
+}
+
+impl From<f32> for Brightness {
+    /// Clamps the fraction between 0 and 1.
+    fn from(fraction: f32) -> Self {
+        Brightness(if fraction > 0.0 {
+            fraction.min(1.0)
+        } else {
+            0.0
+        })
+    }
+}
+
+/// How brightness levels map to the backlight output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrightnessCurve {
+    /// The output is proportional to the level.
+    Linear,
+    /// The output is the level to this power.
+    Gamma(f32),
+    /// Levels are perceived as evenly spaced, following the CIE 1931
+    /// lightness.
+    Perceptual,
+}
+
+impl BrightnessCurve {
+    /// Maps a level to the output, both from 0 to 1.
+    pub fn apply(&self, level: f32) -> f32 {
+        match *self {
+            BrightnessCurve::Linear => level,
+            BrightnessCurve::Gamma(gamma) => level.powf(gamma),
+            BrightnessCurve::Perceptual => {
+                if level <= 0.08 {
+                    level / 9.033
+                } else {
+                    ((level + 0.16) / 1.16).powi(3)
+                }
+            }
+        }
+    }
+}
+
+// A ramp between two levels.
+struct Ramp {
+    from: Brightness,
+    target: Brightness,
+    start: Instant,
+    duration: Duration,
+}
+
+struct State {
+    light: Box<dyn Light>,
+    curve: BrightnessCurve,
+    min: u8,
+    max: u8,
+    level: Brightness,
+    mode: BrightnessMode,
+    ramp: Option<Ramp>,
+    shutdown: bool,
+}
+
+impl State {
+    fn apply(&mut self, level: Brightness) -> bool {
+        self.level = level;
+        let range = (self.max - self.min) as f32;
+        let output = self.min + (self.curve.apply(level.0) * range).round() as u8;
+        self.light.set(LightState {
+            color: (output, output, output),
+            brightness_mode: self.mode,
+            ..LightState::default()
+        })
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// The display backlight, with levels mapped through a curve and clamped to
+/// a range. Ramps play from its own thread.
+pub struct Backlight {
+    shared: Arc<Shared>,
+}
+
+impl Backlight {
+    /// Opens the backlight with `open_light`, or returns None if the device
+    /// doesn't have one.
+    pub fn open() -> Option<Self> {
+        open_light(LightKind::Backlight).map(Backlight::new)
+    }
+
+    /// Drives a light as a backlight, with a perceptual curve and the full
+    /// output range.
+    pub fn new(light: Box<dyn Light>) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                light,
+                curve: BrightnessCurve::Perceptual,
+                min: 0,
+                max: 255,
+                level: Brightness(0.0),
+                mode: BrightnessMode::User,
+                ramp: None,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("backlight ramp".to_owned())
+            .spawn(move || Backlight::run(&thread_shared))
+            .expect("Failed to start backlight ramp thread!");
+        Backlight { shared }
+    }
+
+    /// Sets how levels map to the output.
+    pub fn with_curve(self, curve: BrightnessCurve) -> Self {
+        self.shared.state.lock().unwrap().curve = curve;
+        self
+    }
+
+    /// Clamps the output, for instance to keep the display readable at the
+    /// lowest level.
+    pub fn with_range(self, min: u8, max: u8) -> Self {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.min = min.min(max);
+            state.max = max;
+        }
+        self
+    }
+
+    /// Sets the brightness level, stopping any ramp.
+    /// Returns true if successful.
+    pub fn set_brightness<B: Into<Brightness>>(&self, level: B) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        state.ramp = None;
+        state.apply(level.into())
+    }
+
+    /// The current brightness level, which moves during ramps.
+    pub fn brightness(&self) -> Brightness {
+        self.shared.state.lock().unwrap().level
+    }
+
+    /// Moves smoothly to a brightness level, from the ramp thread. Levels
+    /// change linearly, so the ramp looks even with a perceptual curve.
+    /// Replaces the running ramp.
+    pub fn ramp_to<B: Into<Brightness>>(&self, level: B, duration: Duration) {
+        let mut state = self.shared.state.lock().unwrap();
+        self.start_ramp(&mut state, level.into(), duration);
+    }
+
+    /// Like `ramp_to`, but only while the brightness is in
+    /// `BrightnessMode::Sensor`, checked under the same lock as `set_mode`.
+    /// Returns true if the ramp started.
+    pub(crate) fn sensor_ramp_to(&self, level: Brightness, duration: Duration) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.mode != BrightnessMode::Sensor {
+            return false;
+        }
+        self.start_ramp(&mut state, level, duration);
+        true
+    }
+
+    fn start_ramp(&self, state: &mut State, target: Brightness, duration: Duration) {
+        if duration == Duration::from_secs(0) {
+            state.ramp = None;
+            state.apply(target);
+            return;
+        }
+        state.ramp = Some(Ramp {
+            from: state.level,
+            target,
+            start: Instant::now(),
+            duration,
+        });
+        self.shared.changed.notify_all();
+    }
+
+    /// Hands the brightness over to the light sensor with
+    /// `BrightnessMode::Sensor`, either of the hardware or of an
+    /// `AutoBrightness` controller, or takes it back with
+    /// `BrightnessMode::User`. Stops any ramp.
+    /// Returns true if successful.
+    pub fn set_mode(&self, mode: BrightnessMode) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        state.ramp = None;
+        state.mode = mode;
+        let level = state.level;
+        state.apply(level)
+    }
+
+    /// Who controls the brightness.
+    pub fn mode(&self) -> BrightnessMode {
+        self.shared.state.lock().unwrap().mode
+    }
+
+    /// Turns the backlight off, stopping any ramp. The level is then 0.
+    /// Returns true if successful.
+    pub fn off(&self) -> bool {
+        let mut state = self.shared.state.lock().unwrap();
+        state.ramp = None;
+        state.level = Brightness(0.0);
+        state.light.off()
+    }
+
+    fn run(shared: &Shared) {
+        let interval = Duration::from_secs(1) / DEFAULT_FRAME_RATE;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            if state.shutdown {
+                return;
+            }
+            let (level, next) = match state.ramp {
+                Some(ref ramp) => {
+                    let elapsed = ramp.start.elapsed();
+                    let t = (elapsed.as_secs_f32() / ramp.duration.as_secs_f32()).min(1.0);
+                    let level = Brightness(ramp.from.0 + (ramp.target.0 - ramp.from.0) * t);
+                    // Frames are scheduled from the start so the ramp lasts
+                    // as long as asked.
+                    let frames = (elapsed.as_nanos() / interval.as_nanos()) as u32 + 1;
+                    (
+                        level,
+                        Some(ramp.start + interval * frames).filter(|_| t < 1.0),
+                    )
+                }
+                None => {
+                    state = shared.changed.wait(state).unwrap();
+                    continue;
+                }
+            };
+            state.apply(level);
+            match next {
+                Some(next) => {
+                    let now = Instant::now();
+                    if next > now {
+                        state = shared.changed.wait_timeout(state, next - now).unwrap().0;
+                    }
+                }
+                None => state.ramp = None,
+            }
+        }
+    }
+}
+
+impl Drop for Backlight {
+    /// Stops the ramp thread.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeLight(Arc<Mutex<Vec<u8>>>);
+
+    impl Light for FakeLight {
+        fn set(&self, state: LightState) -> bool {
+            self.0.lock().unwrap().push(state.color.0);
+            true
+        }
+    }
+
+    fn backlight() -> (Backlight, Arc<Mutex<Vec<u8>>>) {
+        let outputs = Arc::new(Mutex::new(vec![]));
+        let backlight = Backlight::new(Box::new(FakeLight(outputs.clone())));
+        (backlight, outputs)
+    }
+
+    fn last_output(outputs: &Mutex<Vec<u8>>) -> Option<u8> {
+        outputs.lock().unwrap().last().cloned()
+    }
+
+    #[test]
+    fn curves_map_the_whole_range() {
+        for &curve in &[
+            BrightnessCurve::Linear,
+            BrightnessCurve::Gamma(2.2),
+            BrightnessCurve::Perceptual,
+        ] {
+            assert_eq!(curve.apply(0.0), 0.0);
+            assert!((curve.apply(1.0) - 1.0).abs() < 1e-6);
+            let mut previous = 0.0;
+            for i in 1..101 {
+                let output = curve.apply(i as f32 / 100.0);
+                assert!(output > previous, "{:?} isn't increasing", curve);
+                previous = output;
+            }
+        }
+        assert_eq!(BrightnessCurve::Linear.apply(0.3), 0.3);
+        assert!((BrightnessCurve::Gamma(2.0).apply(0.5) - 0.25).abs() < 1e-6);
+        // The two parts of the lightness curve meet.
+        let perceptual = BrightnessCurve::Perceptual;
+        assert!((perceptual.apply(0.08) - perceptual.apply(0.080_01)).abs() < 1e-5);
+    }
+
+    #[test]
+    fn levels_are_mapped_to_the_range() {
+        let (backlight, outputs) = backlight();
+        let backlight = backlight
+            .with_curve(BrightnessCurve::Linear)
+            .with_range(20, 220);
+        assert!(backlight.set_brightness(0.0));
+        assert_eq!(last_output(&outputs), Some(20));
+        assert!(backlight.set_brightness(255));
+        assert_eq!(last_output(&outputs), Some(220));
+        assert!(backlight.set_brightness(0.5));
+        assert_eq!(last_output(&outputs), Some(120));
+        assert!(backlight.set_brightness(2.0));
+        assert_eq!(last_output(&outputs), Some(220));
+
+        // Turned off, the level is 0 when handed over.
+        assert!(backlight.off());
+        assert_eq!(last_output(&outputs), Some(0));
+        assert_eq!(backlight.brightness(), Brightness(0.0));
+        assert!(backlight.set_mode(BrightnessMode::Sensor));
+        assert_eq!(last_output(&outputs), Some(20));
+    }
+
+    #[test]
+    fn ramps_reach_their_target_or_stop() {
+        let (backlight, outputs) = backlight();
+        let backlight = backlight.with_curve(BrightnessCurve::Linear);
+        backlight.ramp_to(1.0, Duration::from_millis(100));
+        thread::sleep(Duration::from_millis(200));
+        assert_eq!(backlight.brightness(), Brightness(1.0));
+        assert_eq!(last_output(&outputs), Some(255));
+        let frames = outputs.lock().unwrap().len();
+        assert!(frames > 2, "Only {} frames", frames);
+
+        backlight.ramp_to(0.0, Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(50));
+        assert!(backlight.set_brightness(0.5));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(backlight.brightness(), Brightness(0.5));
+        assert_eq!(last_output(&outputs), Some(128));
+    }
+}
@@ -0,0 +1,345 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+/// The probe used by default, which returns a 204 when reachable.
+pub const DEFAULT_PROBE_URL: &str = "http://connectivitycheck.gstatic.com/generate_204";
+
+/// The interesting parts of an HTTP response.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    /// The `Location` header, if any.
+    pub location: Option<String>,
+    /// The size of the body, if known.
+    pub content_length: Option<usize>,
+}
+
+/// The HTTP layer used to run connectivity probes.
+pub trait HttpClient {
+    /// Performs a GET request without following redirects.
+    fn get(&mut self, url: &str, timeout: Duration) -> io::Result<HttpResponse>;
+}
+
+/// A minimal plain HTTP/1.1 client over a TCP socket. Only `http://` URLs
+/// are supported.
+#[derive(Clone, Default)]
+pub struct TcpHttpClient;
+
+fn invalid_url(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Splits a URL into its host, without the brackets of IPv6 literals, port
+/// and path.
+fn parse_url(url: &str) -> io::Result<(String, u16, String)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid_url(format!("Unsupported probe URL: {}", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => (&rest[..pos], &rest[pos..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.strip_prefix('[') {
+        Some(literal) => {
+            let end = literal
+                .find(']')
+                .ok_or_else(|| invalid_url(format!("Unterminated IPv6 address in {}", url)))?;
+            (&literal[..end], &literal[end + 1..])
+        }
+        None => match authority.rfind(':') {
+            Some(pos) => (&authority[..pos], &authority[pos..]),
+            None => (authority, ""),
+        },
+    };
+    let port = match port {
+        "" => 80,
+        port => port
+            .strip_prefix(':')
+            .and_then(|port| port.parse().ok())
+            .ok_or_else(|| invalid_url(format!("Invalid port in {}", url)))?,
+    };
+    if host.is_empty() {
+        return Err(invalid_url(format!("Missing host in {}", url)));
+    }
+    Ok((host.to_owned(), port, path.to_owned()))
+}
+
+impl HttpClient for TcpHttpClient {
+    fn get(&mut self, url: &str, timeout: Duration) -> io::Result<HttpResponse> {
+        let (host, port, path) = parse_url(url)?;
+        let addr = (host.as_str(), port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotFound, format!("Can't resolve {}", host))
+            })?;
+
+        let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let host = if addr.is_ipv6() && host.contains(':') {
+            format!("[{}]", host)
+        } else {
+            host
+        };
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nUser-Agent: gonkhal\r\n\r\n",
+            path, host
+        )?;
+
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        // "HTTP/1.1 204 No Content"
+        let status = line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Invalid HTTP status line")
+            })?;
+
+        let mut response = HttpResponse {
+            status,
+            location: None,
+            content_length: None,
+        };
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let mut parts = header.splitn(2, ':');
+            let name = parts.next().unwrap_or("").trim().to_lowercase();
+            let value = parts.next().unwrap_or("").trim();
+            match name.as_str() {
+                "location" => response.location = Some(value.to_owned()),
+                "content-length" => response.content_length = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        // Without a length we look at the body ourselves: a portal serving
+        // its login page with a 200 will send some content.
+        if response.content_length.is_none() && response.status == 200 {
+            let mut body = [0u8; 1];
+            response.content_length = Some(reader.read(&mut body).unwrap_or(0));
+        }
+
+        Ok(response)
+    }
+}
+
+/// The result of a connectivity check.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Connectivity {
+    /// The probe got the expected answer.
+    Validated,
+    /// Traffic is intercepted, with the portal URL when the redirect gave one.
+    CaptivePortal(Option<String>),
+    /// The probe failed.
+    NoInternet,
+}
+
+/// Checks whether a network actually provides internet access.
+pub struct ConnectivityChecker<H: HttpClient> {
+    http: H,
+    url: String,
+    timeout: Duration,
+    attempts: u32,
+    retry_delay: Duration,
+}
+
+impl ConnectivityChecker<TcpHttpClient> {
+    /// Creates a checker probing `DEFAULT_PROBE_URL`.
+    pub fn new() -> Self {
+        ConnectivityChecker::with_client(TcpHttpClient)
+    }
+}
+
+impl Default for ConnectivityChecker<TcpHttpClient> {
+    fn default() -> Self {
+        ConnectivityChecker::new()
+    }
+}
+
+impl<H: HttpClient> ConnectivityChecker<H> {
+    /// Creates a checker with a custom HTTP layer.
+    pub fn with_client(http: H) -> Self {
+        ConnectivityChecker {
+            http,
+            url: DEFAULT_PROBE_URL.to_owned(),
+            timeout: Duration::from_secs(10),
+            attempts: 3,
+            retry_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Sets the URL to probe. It must answer with a 204 when reachable.
+    pub fn with_url(mut self, url: &str) -> Self {
+        self.url = url.to_owned();
+        self
+    }
+
+    /// Sets the timeout of each probe.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets how many times a failing probe is attempted, and the delay
+    /// between attempts. Networks often need a moment after association.
+    pub fn with_attempts(mut self, attempts: u32, retry_delay: Duration) -> Self {
+        self.attempts = attempts.max(1);
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Probes the configured URL.
+    pub fn check(&mut self) -> Connectivity {
+        for attempt in 0..self.attempts {
+            if attempt > 0 {
+                thread::sleep(self.retry_delay);
+            }
+            if let Ok(response) = self.http.get(&self.url, self.timeout) {
+                return Self::classify(&response);
+            }
+        }
+        Connectivity::NoInternet
+    }
+
+    /// Runs a check if this supplicant event reports a new connection, as
+    /// delivered by `WifiEvents`. Returns None for other events.
+    pub fn check_event(&mut self, event: &str) -> Option<Connectivity> {
+        if !event.contains("CTRL-EVENT-CONNECTED") {
+            return None;
+        }
+        Some(self.check())
+    }
+
+    fn classify(response: &HttpResponse) -> Connectivity {
+        match response.status {
+            204 => Connectivity::Validated,
+            // Some proxies turn a 204 into an empty 200.
+            200 if response.content_length == Some(0) => Connectivity::Validated,
+            301 | 302 | 303 | 307 | 308 => Connectivity::CaptivePortal(response.location.clone()),
+            200..=399 => Connectivity::CaptivePortal(None),
+            _ => Connectivity::NoInternet,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Serves each response to one connection, and returns the probe URL.
+    fn serve(responses: Vec<&'static str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!(
+            "http://127.0.0.1:{}/generate_204",
+            listener.local_addr().unwrap().port()
+        );
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while reader.read_line(&mut request).unwrap() > 2 {
+                    request.clear();
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        url
+    }
+
+    fn check(response: &'static str) -> Connectivity {
+        let url = serve(vec![response]);
+        ConnectivityChecker::new()
+            .with_url(&url)
+            .with_timeout(Duration::from_secs(5))
+            .with_attempts(1, Duration::from_secs(0))
+            .check()
+    }
+
+    #[test]
+    fn verdicts() {
+        assert_eq!(
+            check("HTTP/1.1 204 No Content\r\n\r\n"),
+            Connectivity::Validated
+        );
+        assert_eq!(
+            check("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"),
+            Connectivity::Validated
+        );
+        assert_eq!(
+            check("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n<html>Log in</html>"),
+            Connectivity::CaptivePortal(None)
+        );
+        assert_eq!(
+            check("HTTP/1.1 302 Found\r\nLocation: http://portal.example/login\r\n\r\n"),
+            Connectivity::CaptivePortal(Some("http://portal.example/login".to_owned()))
+        );
+        assert_eq!(
+            check("HTTP/1.1 503 Service Unavailable\r\n\r\n"),
+            Connectivity::NoInternet
+        );
+    }
+
+    #[test]
+    fn unreachable() {
+        // Nothing listens on the port once the listener is dropped.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut checker = ConnectivityChecker::new()
+            .with_url(&format!("http://127.0.0.1:{}/", port))
+            .with_attempts(2, Duration::from_millis(1));
+        assert_eq!(checker.check(), Connectivity::NoInternet);
+    }
+
+    #[test]
+    fn urls() {
+        let parse = |url| parse_url(url).unwrap();
+        assert_eq!(
+            parse("http://example.com"),
+            ("example.com".to_owned(), 80, "/".to_owned())
+        );
+        assert_eq!(
+            parse("http://10.0.0.1:8080/generate_204"),
+            ("10.0.0.1".to_owned(), 8080, "/generate_204".to_owned())
+        );
+        assert_eq!(
+            parse("http://[::1]:8080/"),
+            ("::1".to_owned(), 8080, "/".to_owned())
+        );
+        assert_eq!(
+            parse("http://[fe80::1]/probe"),
+            ("fe80::1".to_owned(), 80, "/probe".to_owned())
+        );
+        for url in &[
+            "https://example.com/",
+            "http://:80/",
+            "http://example.com:port/",
+            "http://[::1/",
+            "http://[::1]x/",
+        ] {
+            assert!(parse_url(url).is_err(), "{}", url);
+        }
+    }
+}
@@ -0,0 +1,1437 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use libc::{self, c_char, c_int, c_void};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use wifi::event_interface;
+
+const AF_INET: u8 = libc::AF_INET as u8;
+
+const NLMSG_ERROR: u16 = 2;
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_DELADDR: u16 = 21;
+const RTM_NEWROUTE: u16 = 24;
+
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_REPLACE: u16 = 0x100;
+const NLM_F_CREATE: u16 = 0x400;
+
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_BROADCAST: u16 = 4;
+const IFLA_MTU: u16 = 4;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_DHCP: u8 = 16;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+const DHCP_SERVER_PORT: u16 = 67;
+const DHCP_CLIENT_PORT: u16 = 68;
+
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+// Asks servers to broadcast their replies, which we can't receive as
+// unicast before the interface has an address.
+const BROADCAST_FLAG: u16 = 0x8000;
+// op, htype, hlen, hops, xid, secs, flags, 4 addresses, chaddr, sname, file.
+const BOOTP_HEADER_LEN: usize = 236;
+
+/// Well known DHCP option codes, from RFC 2132.
+pub mod options {
+    pub const PAD: u8 = 0;
+    pub const SUBNET_MASK: u8 = 1;
+    pub const ROUTER: u8 = 3;
+    pub const DNS_SERVER: u8 = 6;
+    pub const HOST_NAME: u8 = 12;
+    pub const INTERFACE_MTU: u8 = 26;
+    pub const REQUESTED_ADDRESS: u8 = 50;
+    pub const LEASE_TIME: u8 = 51;
+    pub const MESSAGE_TYPE: u8 = 53;
+    pub const SERVER_ID: u8 = 54;
+    pub const PARAMETER_LIST: u8 = 55;
+    pub const MAX_MESSAGE_SIZE: u8 = 57;
+    pub const RENEWAL_TIME: u8 = 58;
+    pub const REBINDING_TIME: u8 = 59;
+    pub const CLIENT_ID: u8 = 61;
+    pub const END: u8 = 255;
+}
+
+/// The DHCP message types carried in option 53.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DhcpMessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl DhcpMessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(DhcpMessageType::Discover),
+            2 => Some(DhcpMessageType::Offer),
+            3 => Some(DhcpMessageType::Request),
+            4 => Some(DhcpMessageType::Decline),
+            5 => Some(DhcpMessageType::Ack),
+            6 => Some(DhcpMessageType::Nak),
+            7 => Some(DhcpMessageType::Release),
+            8 => Some(DhcpMessageType::Inform),
+            _ => None,
+        }
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+fn read_u16(buf: &[u8], at: usize) -> u16 {
+    ((buf[at] as u16) << 8) | buf[at + 1] as u16
+}
+
+fn read_u32(buf: &[u8], at: usize) -> u32 {
+    ((buf[at] as u32) << 24)
+        | ((buf[at + 1] as u32) << 16)
+        | ((buf[at + 2] as u32) << 8)
+        | buf[at + 3] as u32
+}
+
+fn read_addr(buf: &[u8], at: usize) -> Ipv4Addr {
+    Ipv4Addr::new(buf[at], buf[at + 1], buf[at + 2], buf[at + 3])
+}
+
+/// A BOOTP/DHCPv4 message.
+#[derive(Clone, Debug)]
+pub struct DhcpMessage {
+    pub op: u8,
+    pub xid: u32,
+    pub secs: u16,
+    pub flags: u16,
+    pub ciaddr: Ipv4Addr,
+    pub yiaddr: Ipv4Addr,
+    pub siaddr: Ipv4Addr,
+    pub giaddr: Ipv4Addr,
+    pub chaddr: [u8; 6],
+    /// Options in wire order, without the pad and end markers.
+    pub options: Vec<(u8, Vec<u8>)>,
+}
+
+impl DhcpMessage {
+    /// Creates a client request of the given type.
+    pub fn request(kind: DhcpMessageType, xid: u32, chaddr: [u8; 6]) -> Self {
+        DhcpMessage {
+            op: BOOTREQUEST,
+            xid,
+            secs: 0,
+            flags: 0,
+            ciaddr: Ipv4Addr::new(0, 0, 0, 0),
+            yiaddr: Ipv4Addr::new(0, 0, 0, 0),
+            siaddr: Ipv4Addr::new(0, 0, 0, 0),
+            giaddr: Ipv4Addr::new(0, 0, 0, 0),
+            chaddr,
+            options: vec![(options::MESSAGE_TYPE, vec![kind as u8])],
+        }
+    }
+
+    /// Returns the type of this message, if it carries a valid option 53.
+    pub fn message_type(&self) -> Option<DhcpMessageType> {
+        self.option(options::MESSAGE_TYPE)
+            .and_then(|value| value.first())
+            .and_then(|value| DhcpMessageType::from_u8(*value))
+    }
+
+    /// Returns the payload of the first option with this code.
+    pub fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|&&(c, _)| c == code)
+            .map(|(_, value)| &value[..])
+    }
+
+    /// Appends an option to this message.
+    pub fn add_option(&mut self, code: u8, value: Vec<u8>) {
+        self.options.push((code, value));
+    }
+
+    /// Returns an option holding a single IPv4 address.
+    pub fn option_addr(&self, code: u8) -> Option<Ipv4Addr> {
+        self.option(code).and_then(|value| {
+            if value.len() >= 4 {
+                Some(read_addr(value, 0))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns an option holding a list of IPv4 addresses.
+    pub fn option_addrs(&self, code: u8) -> Vec<Ipv4Addr> {
+        self.option(code)
+            .map(|value| {
+                value
+                    .chunks(4)
+                    .filter(|c| c.len() == 4)
+                    .map(|c| read_addr(c, 0))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns an option holding a 32 bits big endian integer.
+    pub fn option_u32(&self, code: u8) -> Option<u32> {
+        self.option(code).and_then(|value| {
+            if value.len() >= 4 {
+                Some(read_u32(value, 0))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Returns an option holding a 16 bits big endian integer.
+    pub fn option_u16(&self, code: u8) -> Option<u16> {
+        self.option(code).and_then(|value| {
+            if value.len() >= 2 {
+                Some(read_u16(value, 0))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Serializes this message in wire format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(300);
+        buf.push(self.op);
+        buf.push(1); // htype: ethernet
+        buf.push(6); // hlen
+        buf.push(0); // hops
+        buf.extend_from_slice(&self.xid.to_be_bytes());
+        buf.extend_from_slice(&self.secs.to_be_bytes());
+        buf.extend_from_slice(&self.flags.to_be_bytes());
+        for addr in &[self.ciaddr, self.yiaddr, self.siaddr, self.giaddr] {
+            buf.extend_from_slice(&addr.octets());
+        }
+        buf.extend_from_slice(&self.chaddr);
+        // Pad chaddr to 16 bytes, then empty sname and file fields.
+        buf.resize(BOOTP_HEADER_LEN, 0);
+        buf.extend_from_slice(&MAGIC_COOKIE);
+        for &(code, ref value) in &self.options {
+            // Longer values would need RFC 3396 splitting, which we never emit.
+            let len = value.len().min(255);
+            buf.push(code);
+            buf.push(len as u8);
+            buf.extend_from_slice(&value[..len]);
+        }
+        buf.push(options::END);
+        // Some servers drop messages shorter than a BOOTP packet.
+        if buf.len() < 300 {
+            buf.resize(300, 0);
+        }
+        buf
+    }
+
+    /// Parses a message in wire format.
+    pub fn parse(buf: &[u8]) -> io::Result<Self> {
+        if buf.len() < BOOTP_HEADER_LEN + MAGIC_COOKIE.len() {
+            return Err(invalid_data("DHCP message too short"));
+        }
+        if buf[1] != 1 || buf[2] != 6 {
+            return Err(invalid_data("Unsupported DHCP hardware type"));
+        }
+        if buf[BOOTP_HEADER_LEN..BOOTP_HEADER_LEN + 4] != MAGIC_COOKIE {
+            return Err(invalid_data("Missing DHCP magic cookie"));
+        }
+
+        let mut chaddr = [0; 6];
+        chaddr.copy_from_slice(&buf[28..34]);
+
+        let mut options = vec![];
+        let mut pos = BOOTP_HEADER_LEN + MAGIC_COOKIE.len();
+        while pos < buf.len() {
+            let code = buf[pos];
+            pos += 1;
+            if code == options::PAD {
+                continue;
+            }
+            if code == options::END {
+                break;
+            }
+            if pos >= buf.len() {
+                return Err(invalid_data("Truncated DHCP option"));
+            }
+            let len = buf[pos] as usize;
+            pos += 1;
+            if pos + len > buf.len() {
+                return Err(invalid_data("Truncated DHCP option"));
+            }
+            options.push((code, buf[pos..pos + len].to_vec()));
+            pos += len;
+        }
+
+        Ok(DhcpMessage {
+            op: buf[0],
+            xid: read_u32(buf, 4),
+            secs: read_u16(buf, 8),
+            flags: read_u16(buf, 10),
+            ciaddr: read_addr(buf, 12),
+            yiaddr: read_addr(buf, 16),
+            siaddr: read_addr(buf, 20),
+            giaddr: read_addr(buf, 24),
+            chaddr,
+            options,
+        })
+    }
+}
+
+/// The configuration obtained from a DHCP server.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DhcpLease {
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    pub server: Ipv4Addr,
+    pub routers: Vec<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub mtu: Option<u16>,
+    pub lease_time: Duration,
+    /// T1, when we start unicasting renewal requests to the server.
+    pub renewal_time: Duration,
+    /// T2, when we start broadcasting rebinding requests.
+    pub rebinding_time: Duration,
+    pub acquired_at: SystemTime,
+}
+
+fn prefix_len(mask: Ipv4Addr) -> u8 {
+    u32::from(mask).count_ones() as u8
+}
+
+impl DhcpLease {
+    /// Builds a lease from a DHCPACK message.
+    pub fn from_ack(ack: &DhcpMessage) -> io::Result<Self> {
+        let server = ack
+            .option_addr(options::SERVER_ID)
+            .ok_or_else(|| invalid_data("DHCPACK without server identifier"))?;
+        let lease_secs = ack
+            .option_u32(options::LEASE_TIME)
+            .ok_or_else(|| invalid_data("DHCPACK without lease time"))?;
+        let lease_time = Duration::from_secs(lease_secs as u64);
+        let renewal_time = ack
+            .option_u32(options::RENEWAL_TIME)
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(lease_time / 2);
+        let rebinding_time = ack
+            .option_u32(options::REBINDING_TIME)
+            .map(|secs| Duration::from_secs(secs as u64))
+            .unwrap_or(lease_time * 7 / 8);
+        // Default to the classful netmask when the server doesn't send one.
+        let prefix = ack
+            .option_addr(options::SUBNET_MASK)
+            .map(prefix_len)
+            .unwrap_or_else(|| match ack.yiaddr.octets()[0] {
+                0..=127 => 8,
+                128..=191 => 16,
+                _ => 24,
+            });
+
+        Ok(DhcpLease {
+            address: ack.yiaddr,
+            prefix_len: prefix,
+            server,
+            routers: ack.option_addrs(options::ROUTER),
+            dns: ack.option_addrs(options::DNS_SERVER),
+            mtu: ack.option_u16(options::INTERFACE_MTU),
+            lease_time,
+            renewal_time,
+            rebinding_time,
+            acquired_at: SystemTime::now(),
+        })
+    }
+
+    fn elapsed(&self) -> Duration {
+        self.acquired_at.elapsed().unwrap_or_default()
+    }
+
+    /// Checks if this lease has reached its renewal time.
+    pub fn needs_renewal(&self) -> bool {
+        self.elapsed() >= self.renewal_time
+    }
+
+    /// Checks if this lease has reached its rebinding time.
+    pub fn needs_rebinding(&self) -> bool {
+        self.elapsed() >= self.rebinding_time
+    }
+
+    /// Checks if this lease is no longer valid.
+    pub fn is_expired(&self) -> bool {
+        self.elapsed() >= self.lease_time
+    }
+
+    /// Saves this lease to a file, using a simple `key=value` format.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fn join(addrs: &[Ipv4Addr]) -> String {
+            addrs
+                .iter()
+                .map(|a| a.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        }
+
+        let acquired = self
+            .acquired_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let mut file = File::create(path)?;
+        writeln!(file, "address={}", self.address)?;
+        writeln!(file, "prefix_len={}", self.prefix_len)?;
+        writeln!(file, "server={}", self.server)?;
+        writeln!(file, "routers={}", join(&self.routers))?;
+        writeln!(file, "dns={}", join(&self.dns))?;
+        if let Some(mtu) = self.mtu {
+            writeln!(file, "mtu={}", mtu)?;
+        }
+        writeln!(file, "lease_time={}", self.lease_time.as_secs())?;
+        writeln!(file, "renewal_time={}", self.renewal_time.as_secs())?;
+        writeln!(file, "rebinding_time={}", self.rebinding_time.as_secs())?;
+        writeln!(file, "acquired_at={}", acquired.as_secs())?;
+        file.sync_all()
+    }
+
+    /// Loads a lease previously written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        fn addrs(value: &str) -> io::Result<Vec<Ipv4Addr>> {
+            value
+                .split(',')
+                .filter(|a| !a.is_empty())
+                .map(|a| {
+                    a.parse()
+                        .map_err(|_| invalid_data("Invalid address in lease"))
+                })
+                .collect()
+        }
+        fn secs(value: &str) -> io::Result<Duration> {
+            value
+                .parse()
+                .map(Duration::from_secs)
+                .map_err(|_| invalid_data("Invalid duration in lease"))
+        }
+
+        let mut lease = DhcpLease {
+            address: Ipv4Addr::new(0, 0, 0, 0),
+            prefix_len: 0,
+            server: Ipv4Addr::new(0, 0, 0, 0),
+            routers: vec![],
+            dns: vec![],
+            mtu: None,
+            lease_time: Duration::from_secs(0),
+            renewal_time: Duration::from_secs(0),
+            rebinding_time: Duration::from_secs(0),
+            acquired_at: UNIX_EPOCH,
+        };
+
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            let mut parts = line.splitn(2, '=');
+            let (key, value) = match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) => (key.trim(), value.trim()),
+                _ => continue,
+            };
+            match key {
+                "address" => {
+                    lease.address = value
+                        .parse()
+                        .map_err(|_| invalid_data("Invalid lease address"))?
+                }
+                "prefix_len" => {
+                    lease.prefix_len = value.parse().map_err(|_| invalid_data("Invalid prefix"))?
+                }
+                "server" => {
+                    lease.server = value
+                        .parse()
+                        .map_err(|_| invalid_data("Invalid lease server"))?
+                }
+                "routers" => lease.routers = addrs(value)?,
+                "dns" => lease.dns = addrs(value)?,
+                "mtu" => lease.mtu = Some(value.parse().map_err(|_| invalid_data("Invalid MTU"))?),
+                "lease_time" => lease.lease_time = secs(value)?,
+                "renewal_time" => lease.renewal_time = secs(value)?,
+                "rebinding_time" => lease.rebinding_time = secs(value)?,
+                "acquired_at" => lease.acquired_at = UNIX_EPOCH + secs(value)?,
+                _ => {}
+            }
+        }
+
+        if lease.address.is_unspecified() {
+            return Err(invalid_data("DhcpLease file without address"));
+        }
+        Ok(lease)
+    }
+}
+
+/// The transport used to exchange DHCP messages.
+pub trait DhcpTransport {
+    /// Sends a packet to a server, or broadcasts it when `dest` is the
+    /// broadcast address.
+    fn send(&mut self, packet: &[u8], dest: Ipv4Addr) -> io::Result<()>;
+
+    /// Waits up to `timeout` for a packet. Returns an error of kind
+    /// `WouldBlock` or `TimedOut` when nothing was received.
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize>;
+}
+
+/// A UDP socket bound to the DHCP client port of an interface.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    /// Binds the DHCP client port on the given interface.
+    pub fn bind(ifname: &str) -> io::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(
+            Ipv4Addr::new(0, 0, 0, 0),
+            DHCP_CLIENT_PORT,
+        ))?;
+        socket.set_broadcast(true)?;
+
+        let mut name = ifname.as_bytes().to_vec();
+        name.push(0);
+        let res = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_BINDTODEVICE,
+                name.as_ptr() as *const c_void,
+                name.len() as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl DhcpTransport for UdpTransport {
+    fn send(&mut self, packet: &[u8], dest: Ipv4Addr) -> io::Result<()> {
+        self.socket
+            .send_to(packet, SocketAddrV4::new(dest, DHCP_SERVER_PORT))
+            .map(|_| ())
+    }
+
+    fn recv(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.socket.set_read_timeout(Some(timeout))?;
+        self.socket.recv_from(buf).map(|(size, _)| size)
+    }
+}
+
+/// Applies the layer 3 configuration of an interface.
+pub trait InterfaceConfig {
+    /// Adds an address to the interface, replacing it if it already exists.
+    fn add_address(&mut self, ifname: &str, address: Ipv4Addr, prefix_len: u8) -> io::Result<()>;
+
+    /// Removes an address from the interface.
+    fn del_address(&mut self, ifname: &str, address: Ipv4Addr, prefix_len: u8) -> io::Result<()>;
+
+    /// Sets the default route through this gateway.
+    fn set_default_route(&mut self, ifname: &str, gateway: Ipv4Addr) -> io::Result<()>;
+
+    /// Sets the MTU of the interface.
+    fn set_mtu(&mut self, ifname: &str, mtu: u16) -> io::Result<()>;
+}
+
+/// An `InterfaceConfig` talking to the kernel over a NETLINK_ROUTE socket.
+pub struct Rtnetlink {
+    fd: c_int,
+    seq: u32,
+}
+
+fn push_attr(buf: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    let len = 4 + value.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(value);
+    // Attributes are aligned on 4 bytes.
+    while !buf.len().is_multiple_of(4) {
+        buf.push(0);
+    }
+}
+
+fn if_index(ifname: &str) -> io::Result<u32> {
+    let mut name = ifname.as_bytes().to_vec();
+    name.push(0);
+    match unsafe { libc::if_nametoindex(name.as_ptr() as *const c_char) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+impl Rtnetlink {
+    /// Opens a netlink route socket.
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                libc::NETLINK_ROUTE,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Don't wait forever for an acknowledgement.
+        let timeout = libc::timeval {
+            tv_sec: 5,
+            tv_usec: 0,
+        };
+        let res = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const c_void,
+                mem::size_of_val(&timeout) as libc::socklen_t,
+            )
+        };
+        if res < 0 {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(fd) };
+            return Err(err);
+        }
+
+        Ok(Rtnetlink { fd, seq: 0 })
+    }
+
+    /// Sends a request and waits for the kernel acknowledgement.
+    fn request(&mut self, kind: u16, flags: u16, payload: &[u8]) -> io::Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+
+        let mut msg = Vec::with_capacity(16 + payload.len());
+        msg.extend_from_slice(&((16 + payload.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&kind.to_ne_bytes());
+        msg.extend_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        msg.extend_from_slice(&self.seq.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(payload);
+
+        if unsafe { libc::send(self.fd, msg.as_ptr() as *const c_void, msg.len(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut reply = [0u8; 1024];
+        loop {
+            let size =
+                unsafe { libc::recv(self.fd, reply.as_mut_ptr() as *mut c_void, reply.len(), 0) };
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let size = size as usize;
+            let mut pos = 0;
+            while pos + 16 <= size {
+                let len = u32::from_ne_bytes([
+                    reply[pos],
+                    reply[pos + 1],
+                    reply[pos + 2],
+                    reply[pos + 3],
+                ]) as usize;
+                let ty = u16::from_ne_bytes([reply[pos + 4], reply[pos + 5]]);
+                let seq = u32::from_ne_bytes([
+                    reply[pos + 8],
+                    reply[pos + 9],
+                    reply[pos + 10],
+                    reply[pos + 11],
+                ]);
+                if len < 16 || pos + len > size {
+                    return Err(invalid_data("Malformed netlink reply"));
+                }
+                if ty == NLMSG_ERROR && seq == self.seq && len >= 20 {
+                    let err = i32::from_ne_bytes([
+                        reply[pos + 16],
+                        reply[pos + 17],
+                        reply[pos + 18],
+                        reply[pos + 19],
+                    ]);
+                    return if err == 0 {
+                        Ok(())
+                    } else {
+                        Err(io::Error::from_raw_os_error(-err))
+                    };
+                }
+                pos += (len + 3) & !3;
+            }
+        }
+    }
+
+    fn address_request(
+        &mut self,
+        kind: u16,
+        flags: u16,
+        ifname: &str,
+        address: Ipv4Addr,
+        prefix_len: u8,
+    ) -> io::Result<()> {
+        let index = if_index(ifname)?;
+        let mut payload = vec![AF_INET, prefix_len, 0, RT_SCOPE_UNIVERSE];
+        payload.extend_from_slice(&index.to_ne_bytes());
+        push_attr(&mut payload, IFA_LOCAL, &address.octets());
+        push_attr(&mut payload, IFA_ADDRESS, &address.octets());
+        if prefix_len < 31 {
+            let host_mask = u32::MAX.checked_shr(prefix_len as u32).unwrap_or(0);
+            let broadcast = Ipv4Addr::from(u32::from(address) | host_mask);
+            push_attr(&mut payload, IFA_BROADCAST, &broadcast.octets());
+        }
+        self.request(kind, flags, &payload)
+    }
+}
+
+impl Drop for Rtnetlink {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+impl InterfaceConfig for Rtnetlink {
+    fn add_address(&mut self, ifname: &str, address: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        self.address_request(
+            RTM_NEWADDR,
+            NLM_F_CREATE | NLM_F_REPLACE,
+            ifname,
+            address,
+            prefix_len,
+        )
+    }
+
+    fn del_address(&mut self, ifname: &str, address: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+        self.address_request(RTM_DELADDR, 0, ifname, address, prefix_len)
+    }
+
+    fn set_default_route(&mut self, ifname: &str, gateway: Ipv4Addr) -> io::Result<()> {
+        let index = if_index(ifname)?;
+        // struct rtmsg, with a 0.0.0.0/0 destination.
+        let mut payload = vec![
+            AF_INET,
+            0,
+            0,
+            0,
+            RT_TABLE_MAIN,
+            RTPROT_DHCP,
+            RT_SCOPE_UNIVERSE,
+            RTN_UNICAST,
+        ];
+        payload.extend_from_slice(&0u32.to_ne_bytes());
+        push_attr(&mut payload, RTA_GATEWAY, &gateway.octets());
+        push_attr(&mut payload, RTA_OIF, &index.to_ne_bytes());
+        self.request(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_REPLACE, &payload)
+    }
+
+    fn set_mtu(&mut self, ifname: &str, mtu: u16) -> io::Result<()> {
+        let index = if_index(ifname)?;
+        // struct ifinfomsg: family, pad, type, index, flags, change.
+        let mut payload = vec![0, 0, 0, 0];
+        payload.extend_from_slice(&(index as i32).to_ne_bytes());
+        payload.extend_from_slice(&0u32.to_ne_bytes());
+        payload.extend_from_slice(&0u32.to_ne_bytes());
+        push_attr(&mut payload, IFLA_MTU, &(mtu as u32).to_ne_bytes());
+        self.request(RTM_NEWLINK, 0, &payload)
+    }
+}
+
+/// Returns the interface that just got associated if this supplicant event
+/// is a `CTRL-EVENT-CONNECTED`. Events without an `IFNAME=` prefix come from
+/// `primary`, the interface the supplicant was started on.
+pub fn connected_interface<'a>(event: &'a str, primary: &'a str) -> Option<&'a str> {
+    if !event.contains("CTRL-EVENT-CONNECTED") {
+        return None;
+    }
+    event_interface(event).or(Some(primary))
+}
+
+/// Reads the hardware address of an interface from sysfs.
+pub fn hardware_address(ifname: &str) -> io::Result<[u8; 6]> {
+    let mut line = String::new();
+    BufReader::new(File::open(format!("/sys/class/net/{}/address", ifname))?)
+        .read_line(&mut line)?;
+
+    let mut addr = [0u8; 6];
+    let mut count = 0;
+    for (i, part) in line.trim().split(':').enumerate() {
+        if i >= 6 {
+            return Err(invalid_data("Invalid hardware address"));
+        }
+        addr[i] =
+            u8::from_str_radix(part, 16).map_err(|_| invalid_data("Invalid hardware address"))?;
+        count += 1;
+    }
+    if count != 6 {
+        return Err(invalid_data("Invalid hardware address"));
+    }
+    Ok(addr)
+}
+
+/// A DHCPv4 client for one interface.
+pub struct DhcpClient<T: DhcpTransport, C: InterfaceConfig> {
+    ifname: String,
+    hwaddr: [u8; 6],
+    transport: T,
+    config: C,
+    lease: Option<DhcpLease>,
+    lease_file: Option<PathBuf>,
+    hostname: Option<String>,
+    timeout: Duration,
+    retries: u32,
+    xid: u32,
+}
+
+impl DhcpClient<UdpTransport, Rtnetlink> {
+    /// Creates a client for an interface, using a UDP socket bound to it
+    /// and rtnetlink to configure it.
+    pub fn for_interface(ifname: &str) -> io::Result<Self> {
+        let hwaddr = hardware_address(ifname)?;
+        Ok(DhcpClient::new(
+            ifname,
+            hwaddr,
+            UdpTransport::bind(ifname)?,
+            Rtnetlink::new()?,
+        ))
+    }
+}
+
+impl<T: DhcpTransport, C: InterfaceConfig> DhcpClient<T, C> {
+    /// Creates a client with a custom transport and interface configuration.
+    pub fn new(ifname: &str, hwaddr: [u8; 6], transport: T, config: C) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        DhcpClient {
+            ifname: ifname.to_owned(),
+            hwaddr,
+            transport,
+            config,
+            lease: None,
+            lease_file: None,
+            hostname: None,
+            timeout: Duration::from_secs(4),
+            retries: 4,
+            xid: now.subsec_nanos() ^ process::id().rotate_left(16),
+        }
+    }
+
+    /// Persists leases to this file, and reuses the lease it contains if any.
+    pub fn with_lease_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.lease = DhcpLease::load(path.as_ref())
+            .ok()
+            .filter(|lease| !lease.is_expired());
+        self.lease_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sends this host name to the server.
+    pub fn with_hostname(mut self, hostname: &str) -> Self {
+        self.hostname = Some(hostname.to_owned());
+        self
+    }
+
+    /// Sets the initial reply timeout, doubled on each retry, and the
+    /// number of retries.
+    pub fn with_timeout(mut self, timeout: Duration, retries: u32) -> Self {
+        self.timeout = timeout;
+        self.retries = retries;
+        self
+    }
+
+    /// The name of the interface managed by this client.
+    pub fn interface(&self) -> &str {
+        &self.ifname
+    }
+
+    /// The current lease, if any.
+    pub fn lease(&self) -> Option<&DhcpLease> {
+        self.lease.as_ref()
+    }
+
+    /// Gets a lease, first trying to confirm a persisted one and falling
+    /// back to a full discovery.
+    pub fn start(&mut self) -> io::Result<DhcpLease> {
+        if let Some(lease) = self.lease.clone() {
+            let mut msg = self.message(DhcpMessageType::Request);
+            msg.add_option(options::REQUESTED_ADDRESS, lease.address.octets().to_vec());
+            match self.exchange(msg, Ipv4Addr::new(255, 255, 255, 255)) {
+                // Only an ACK for the address we asked for confirms it.
+                Ok(ack) if ack.address == lease.address => return self.bind(ack),
+                _ => self.unbind(),
+            }
+        }
+        self.discover()
+    }
+
+    /// Runs a full DISCOVER / OFFER / REQUEST / ACK exchange.
+    pub fn discover(&mut self) -> io::Result<DhcpLease> {
+        let offer = self.transaction(
+            self.message(DhcpMessageType::Discover),
+            Ipv4Addr::new(255, 255, 255, 255),
+            DhcpMessageType::Offer,
+        )?;
+        let server = offer
+            .option_addr(options::SERVER_ID)
+            .ok_or_else(|| invalid_data("DHCPOFFER without server identifier"))?;
+
+        let mut msg = self.message(DhcpMessageType::Request);
+        msg.add_option(options::REQUESTED_ADDRESS, offer.yiaddr.octets().to_vec());
+        msg.add_option(options::SERVER_ID, server.octets().to_vec());
+        let lease = self.exchange(msg, Ipv4Addr::new(255, 255, 255, 255))?;
+        self.bind(lease)
+    }
+
+    /// Extends the current lease by unicasting a request to its server.
+    pub fn renew(&mut self) -> io::Result<DhcpLease> {
+        let lease = self.current_lease()?;
+        let mut msg = self.message(DhcpMessageType::Request);
+        msg.ciaddr = lease.address;
+        let lease = self.exchange(msg, lease.server)?;
+        self.bind(lease)
+    }
+
+    /// Extends the current lease by broadcasting a request to any server.
+    pub fn rebind(&mut self) -> io::Result<DhcpLease> {
+        let lease = self.current_lease()?;
+        let mut msg = self.message(DhcpMessageType::Request);
+        msg.ciaddr = lease.address;
+        let lease = self.exchange(msg, Ipv4Addr::new(255, 255, 255, 255))?;
+        self.bind(lease)
+    }
+
+    /// Gives the current lease back to the server and removes the address
+    /// from the interface.
+    pub fn release(&mut self) -> io::Result<()> {
+        let lease = self.current_lease()?;
+        let mut msg = self.message(DhcpMessageType::Release);
+        msg.ciaddr = lease.address;
+        msg.add_option(options::SERVER_ID, lease.server.octets().to_vec());
+        self.transport.send(&msg.to_bytes(), lease.server)?;
+
+        self.lease = None;
+        if let Some(ref path) = self.lease_file {
+            let _ = fs::remove_file(path);
+        }
+        self.config
+            .del_address(&self.ifname, lease.address, lease.prefix_len)
+    }
+
+    /// Renews, rebinds or restarts as needed by the lease timers. Meant to
+    /// be called periodically, see `next_deadline`.
+    pub fn maintain(&mut self) -> io::Result<DhcpLease> {
+        let lease = match self.lease.clone() {
+            Some(lease) => lease,
+            None => return self.discover(),
+        };
+        if lease.is_expired() {
+            self.unbind();
+            return self.discover();
+        }
+        let extended = if lease.needs_rebinding() {
+            self.rebind()
+        } else if lease.needs_renewal() {
+            self.renew()
+        } else {
+            return Ok(lease);
+        };
+        match extended {
+            Ok(lease) => Ok(lease),
+            // The server says the address is no longer ours.
+            Err(ref err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                self.unbind();
+                self.discover()
+            }
+            // Without an answer the lease stays usable until it expires.
+            Err(_) => Ok(lease),
+        }
+    }
+
+    /// Time left until `maintain` has something to do.
+    pub fn next_deadline(&self) -> Duration {
+        match self.lease {
+            Some(ref lease) => {
+                let elapsed = lease.elapsed();
+                [lease.renewal_time, lease.rebinding_time, lease.lease_time]
+                    .iter()
+                    .find(|&&deadline| deadline > elapsed)
+                    .map(|&deadline| deadline - elapsed)
+                    .unwrap_or_default()
+            }
+            None => Duration::from_secs(0),
+        }
+    }
+
+    fn current_lease(&self) -> io::Result<DhcpLease> {
+        self.lease
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No current DHCP lease"))
+    }
+
+    fn message(&self, kind: DhcpMessageType) -> DhcpMessage {
+        let mut msg = DhcpMessage::request(kind, self.xid, self.hwaddr);
+        let mut client_id = vec![1];
+        client_id.extend_from_slice(&self.hwaddr);
+        msg.add_option(options::CLIENT_ID, client_id);
+        if kind == DhcpMessageType::Release {
+            return msg;
+        }
+        msg.add_option(options::MAX_MESSAGE_SIZE, 1500u16.to_be_bytes().to_vec());
+        msg.add_option(
+            options::PARAMETER_LIST,
+            vec![
+                options::SUBNET_MASK,
+                options::ROUTER,
+                options::DNS_SERVER,
+                options::INTERFACE_MTU,
+                options::LEASE_TIME,
+                options::RENEWAL_TIME,
+                options::REBINDING_TIME,
+            ],
+        );
+        if let Some(ref hostname) = self.hostname {
+            msg.add_option(options::HOST_NAME, hostname.as_bytes().to_vec());
+        }
+        msg
+    }
+
+    /// Sends a REQUEST and turns the ACK into a lease.
+    fn exchange(&mut self, msg: DhcpMessage, dest: Ipv4Addr) -> io::Result<DhcpLease> {
+        let ack = self.transaction(msg, dest, DhcpMessageType::Ack)?;
+        DhcpLease::from_ack(&ack)
+    }
+
+    /// Sends a message until we get a reply of the expected type or a NAK,
+    /// with an exponential backoff.
+    fn transaction(
+        &mut self,
+        msg: DhcpMessage,
+        dest: Ipv4Addr,
+        expected: DhcpMessageType,
+    ) -> io::Result<DhcpMessage> {
+        self.xid = self.xid.wrapping_add(1);
+        let mut msg = msg;
+        msg.xid = self.xid;
+        if msg.ciaddr.is_unspecified() {
+            msg.flags |= BROADCAST_FLAG;
+        }
+
+        let mut timeout = self.timeout;
+        let mut buf = [0u8; 1500];
+        for _ in 0..self.retries + 1 {
+            self.transport.send(&msg.to_bytes(), dest)?;
+
+            let deadline = Instant::now() + timeout;
+            loop {
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                let size = match self.transport.recv(&mut buf, deadline - now) {
+                    Ok(size) => size,
+                    Err(ref err)
+                        if err.kind() == io::ErrorKind::WouldBlock
+                            || err.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        break
+                    }
+                    Err(err) => return Err(err),
+                };
+                let reply = match DhcpMessage::parse(&buf[..size]) {
+                    Ok(reply) => reply,
+                    Err(_) => continue,
+                };
+                if reply.op != BOOTREPLY || reply.xid != self.xid || reply.chaddr != self.hwaddr {
+                    continue;
+                }
+                match reply.message_type() {
+                    Some(kind) if kind == expected => return Ok(reply),
+                    Some(DhcpMessageType::Nak) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::ConnectionRefused,
+                            "DHCPNAK received",
+                        ))
+                    }
+                    _ => continue,
+                }
+            }
+            timeout = (timeout * 2).min(Duration::from_secs(64));
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "No reply from DHCP server",
+        ))
+    }
+
+    /// Forgets the current lease and removes its address.
+    fn unbind(&mut self) {
+        if let Some(lease) = self.lease.take() {
+            let _ = self
+                .config
+                .del_address(&self.ifname, lease.address, lease.prefix_len);
+        }
+    }
+
+    /// Configures the interface with a new lease and persists it.
+    fn bind(&mut self, lease: DhcpLease) -> io::Result<DhcpLease> {
+        if let Some(ref old) = self.lease {
+            if old.address != lease.address || old.prefix_len != lease.prefix_len {
+                let _ = self
+                    .config
+                    .del_address(&self.ifname, old.address, old.prefix_len);
+            }
+        }
+        self.config
+            .add_address(&self.ifname, lease.address, lease.prefix_len)?;
+        if let Some(mtu) = lease.mtu {
+            self.config.set_mtu(&self.ifname, mtu)?;
+        }
+        if let Some(router) = lease.routers.first() {
+            self.config.set_default_route(&self.ifname, *router)?;
+        }
+        if let Some(ref path) = self.lease_file {
+            lease.save(path)?;
+        }
+        self.lease = Some(lease.clone());
+        Ok(lease)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::env;
+
+    const CHADDR: [u8; 6] = [2, 0, 0, 0, 0, 1];
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 42);
+    const BROADCAST: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 255);
+
+    /// A server answering each request with the next queued reply, or
+    /// staying silent once there are none left.
+    struct FakeServer {
+        sent: Vec<(DhcpMessage, Ipv4Addr)>,
+        replies: VecDeque<(DhcpMessageType, Ipv4Addr)>,
+    }
+
+    impl FakeServer {
+        fn new(replies: &[(DhcpMessageType, Ipv4Addr)]) -> Self {
+            FakeServer {
+                sent: vec![],
+                replies: replies.iter().cloned().collect(),
+            }
+        }
+    }
+
+    fn reply(request: &DhcpMessage, kind: DhcpMessageType, yiaddr: Ipv4Addr) -> DhcpMessage {
+        let mut msg = DhcpMessage::request(kind, request.xid, request.chaddr);
+        msg.op = BOOTREPLY;
+        msg.yiaddr = yiaddr;
+        msg.add_option(options::SERVER_ID, SERVER.octets().to_vec());
+        msg.add_option(options::LEASE_TIME, 3600u32.to_be_bytes().to_vec());
+        msg.add_option(options::SUBNET_MASK, vec![255, 255, 255, 0]);
+        msg.add_option(options::ROUTER, SERVER.octets().to_vec());
+        msg
+    }
+
+    impl DhcpTransport for FakeServer {
+        fn send(&mut self, packet: &[u8], dest: Ipv4Addr) -> io::Result<()> {
+            self.sent.push((DhcpMessage::parse(packet)?, dest));
+            Ok(())
+        }
+
+        fn recv(&mut self, buf: &mut [u8], _timeout: Duration) -> io::Result<usize> {
+            let (kind, yiaddr) = self
+                .replies
+                .pop_front()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::WouldBlock, "No reply"))?;
+            let bytes = reply(&self.sent.last().unwrap().0, kind, yiaddr).to_bytes();
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeConfig {
+        log: Vec<String>,
+    }
+
+    impl InterfaceConfig for FakeConfig {
+        fn add_address(&mut self, _: &str, address: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+            self.log.push(format!("add {}/{}", address, prefix_len));
+            Ok(())
+        }
+
+        fn del_address(&mut self, _: &str, address: Ipv4Addr, prefix_len: u8) -> io::Result<()> {
+            self.log.push(format!("del {}/{}", address, prefix_len));
+            Ok(())
+        }
+
+        fn set_default_route(&mut self, _: &str, gateway: Ipv4Addr) -> io::Result<()> {
+            self.log.push(format!("route {}", gateway));
+            Ok(())
+        }
+
+        fn set_mtu(&mut self, _: &str, mtu: u16) -> io::Result<()> {
+            self.log.push(format!("mtu {}", mtu));
+            Ok(())
+        }
+    }
+
+    fn fake_client(replies: &[(DhcpMessageType, Ipv4Addr)]) -> DhcpClient<FakeServer, FakeConfig> {
+        DhcpClient::new(
+            "wlan0",
+            CHADDR,
+            FakeServer::new(replies),
+            FakeConfig::default(),
+        )
+        .with_timeout(Duration::from_millis(1), 0)
+    }
+
+    /// A one hour lease acquired `age` ago.
+    fn lease(age: Duration) -> DhcpLease {
+        let mut ack = reply(
+            &DhcpMessage::request(DhcpMessageType::Request, 0, CHADDR),
+            DhcpMessageType::Ack,
+            ADDRESS,
+        );
+        ack.add_option(options::REBINDING_TIME, 3000u32.to_be_bytes().to_vec());
+        let mut lease = DhcpLease::from_ack(&ack).unwrap();
+        lease.acquired_at = SystemTime::now() - age;
+        lease
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let mut msg = DhcpMessage::request(DhcpMessageType::Request, 0x1234_5678, CHADDR);
+        msg.secs = 3;
+        msg.flags = BROADCAST_FLAG;
+        msg.ciaddr = ADDRESS;
+        msg.add_option(options::HOST_NAME, b"phone".to_vec());
+        msg.add_option(options::REQUESTED_ADDRESS, ADDRESS.octets().to_vec());
+
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes.len(), 300);
+        assert_eq!(&bytes[4..12], &[0x12, 0x34, 0x56, 0x78, 0, 3, 0x80, 0]);
+        let parsed = DhcpMessage::parse(&bytes).unwrap();
+        assert_eq!(parsed.op, BOOTREQUEST);
+        assert_eq!(parsed.xid, msg.xid);
+        assert_eq!(parsed.secs, 3);
+        assert_eq!(parsed.flags, BROADCAST_FLAG);
+        assert_eq!(parsed.ciaddr, ADDRESS);
+        assert_eq!(parsed.chaddr, CHADDR);
+        assert_eq!(parsed.options, msg.options);
+        assert_eq!(parsed.message_type(), Some(DhcpMessageType::Request));
+    }
+
+    #[test]
+    fn parse_options() {
+        let mut bytes = DhcpMessage::request(DhcpMessageType::Ack, 1, CHADDR).to_bytes();
+        bytes.truncate(BOOTP_HEADER_LEN + MAGIC_COOKIE.len());
+        bytes.extend_from_slice(&[options::PAD, options::MESSAGE_TYPE, 1, 5]);
+        bytes.extend_from_slice(&[options::DNS_SERVER, 8, 8, 8, 8, 8, 1, 1, 1, 1]);
+        bytes.extend_from_slice(&[options::INTERFACE_MTU, 2, 5, 0xdc]);
+        bytes.extend_from_slice(&[options::LEASE_TIME, 2, 0, 1]);
+        bytes.extend_from_slice(&[options::END, options::ROUTER, 4, 1, 2, 3, 4]);
+
+        let msg = DhcpMessage::parse(&bytes).unwrap();
+        assert_eq!(msg.message_type(), Some(DhcpMessageType::Ack));
+        assert_eq!(
+            msg.option_addrs(options::DNS_SERVER),
+            vec![Ipv4Addr::new(8, 8, 8, 8), Ipv4Addr::new(1, 1, 1, 1)]
+        );
+        assert_eq!(msg.option_u16(options::INTERFACE_MTU), Some(1500));
+        // Too short for its type, and after the end marker.
+        assert_eq!(msg.option_u32(options::LEASE_TIME), None);
+        assert_eq!(msg.option(options::ROUTER), None);
+
+        let truncated = &bytes[..bytes.len() - 20];
+        assert!(DhcpMessage::parse(truncated).is_err());
+        bytes[BOOTP_HEADER_LEN] = 0;
+        assert!(DhcpMessage::parse(&bytes).is_err());
+    }
+
+    #[test]
+    fn lease_timers() {
+        let fresh = lease(Duration::from_secs(0));
+        assert_eq!(fresh.prefix_len, 24);
+        assert_eq!(fresh.renewal_time, Duration::from_secs(1800));
+        assert_eq!(fresh.rebinding_time, Duration::from_secs(3000));
+        assert!(!fresh.needs_renewal());
+
+        let renewing = lease(Duration::from_secs(2000));
+        assert!(renewing.needs_renewal() && !renewing.needs_rebinding());
+        let rebinding = lease(Duration::from_secs(3100));
+        assert!(rebinding.needs_rebinding() && !rebinding.is_expired());
+        assert!(lease(Duration::from_secs(3600)).is_expired());
+
+        let mut client = fake_client(&[]);
+        client.lease = Some(renewing);
+        let left = client.next_deadline();
+        assert!(left <= Duration::from_secs(1000) && left > Duration::from_secs(990));
+    }
+
+    #[test]
+    fn discover_broadcasts() {
+        let mut client = fake_client(&[
+            (DhcpMessageType::Offer, ADDRESS),
+            (DhcpMessageType::Ack, ADDRESS),
+        ]);
+        assert_eq!(client.start().unwrap().address, ADDRESS);
+        let sent = &client.transport.sent;
+        assert_eq!(sent[0].0.message_type(), Some(DhcpMessageType::Discover));
+        assert_eq!(sent[1].0.message_type(), Some(DhcpMessageType::Request));
+        assert!(sent
+            .iter()
+            .all(|&(ref msg, dest)| msg.flags == BROADCAST_FLAG && dest == BROADCAST));
+        assert_eq!(
+            client.config.log,
+            ["add 192.168.1.42/24", "route 192.168.1.1"]
+        );
+    }
+
+    #[test]
+    fn maintain_renews_then_rebinds() {
+        let mut client = fake_client(&[(DhcpMessageType::Ack, ADDRESS)]);
+        client.lease = Some(lease(Duration::from_secs(2000)));
+        assert!(!client.maintain().unwrap().needs_renewal());
+        let (ref renew, dest) = client.transport.sent[0];
+        assert_eq!((renew.ciaddr, renew.flags, dest), (ADDRESS, 0, SERVER));
+
+        // Nobody answers past T2, but the lease is still valid.
+        client.lease = Some(lease(Duration::from_secs(3100)));
+        assert!(client.maintain().unwrap().needs_rebinding());
+        let (ref rebind, dest) = client.transport.sent[1];
+        assert_eq!((rebind.ciaddr, dest), (ADDRESS, BROADCAST));
+        assert!(client.lease().is_some());
+    }
+
+    #[test]
+    fn maintain_restarts() {
+        // An expired lease is dropped before discovering again.
+        let mut client = fake_client(&[
+            (DhcpMessageType::Offer, ADDRESS),
+            (DhcpMessageType::Ack, ADDRESS),
+        ]);
+        client.lease = Some(lease(Duration::from_secs(3600)));
+        assert!(!client.maintain().unwrap().is_expired());
+        assert_eq!(client.config.log[0], "del 192.168.1.42/24");
+        assert_eq!(
+            client.transport.sent[0].0.message_type(),
+            Some(DhcpMessageType::Discover)
+        );
+
+        // So is a lease the server refuses to renew.
+        let other = Ipv4Addr::new(192, 168, 1, 43);
+        let mut refused = fake_client(&[
+            (DhcpMessageType::Nak, ADDRESS),
+            (DhcpMessageType::Offer, other),
+            (DhcpMessageType::Ack, other),
+        ]);
+        refused.lease = Some(lease(Duration::from_secs(2000)));
+        assert_eq!(refused.maintain().unwrap().address, other);
+        assert_eq!(refused.config.log[0], "del 192.168.1.42/24");
+    }
+
+    #[test]
+    fn start_checks_persisted_address() {
+        let path = env::temp_dir().join(format!("gonkhal-dhcp-{}", process::id()));
+        lease(Duration::from_secs(0)).save(&path).unwrap();
+
+        let other = Ipv4Addr::new(192, 168, 1, 43);
+        let mut client = fake_client(&[
+            (DhcpMessageType::Ack, other),
+            (DhcpMessageType::Offer, other),
+            (DhcpMessageType::Ack, other),
+        ])
+        .with_lease_file(&path);
+        assert_eq!(client.lease().map(|lease| lease.address), Some(ADDRESS));
+        assert_eq!(client.start().unwrap().address, other);
+        let types: Vec<_> = client
+            .transport
+            .sent
+            .iter()
+            .map(|(msg, _)| msg.message_type().unwrap())
+            .collect();
+        assert_eq!(
+            types,
+            [
+                DhcpMessageType::Request,
+                DhcpMessageType::Discover,
+                DhcpMessageType::Request
+            ]
+        );
+        assert_eq!(DhcpLease::load(&path).unwrap().address, other);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn connected_events() {
+        let event = "<3>CTRL-EVENT-CONNECTED - Connection to 00:11:22:33:44:55 completed";
+        assert_eq!(connected_interface(event, "wlan1"), Some("wlan1"));
+        let prefixed = format!("IFNAME=p2p0 {}", event);
+        assert_eq!(connected_interface(&prefixed, "wlan1"), Some("p2p0"));
+        assert_eq!(
+            connected_interface("<3>CTRL-EVENT-SCAN-RESULTS", "wlan1"),
+            None
+        );
+    }
+
+    /// Runs `ip` with these arguments, panicking if it fails.
+    fn ip(args: &[&str]) -> String {
+        let output = process::Command::new("ip").args(args).output().unwrap();
+        assert!(output.status.success(), "ip {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    // Needs root and iproute2 to create the veth pair.
+    #[test]
+    #[ignore]
+    fn rtnetlink_on_veth() {
+        let ifname = format!("gkdhcp{}", process::id() % 10000);
+        let peer = format!("{}p", ifname);
+        ip(&[
+            "link", "add", &ifname, "type", "veth", "peer", "name", &peer,
+        ]);
+        ip(&["link", "set", &ifname, "up"]);
+        ip(&["link", "set", &peer, "up"]);
+
+        let client = DhcpClient::for_interface(&ifname).unwrap();
+        assert_eq!(client.interface(), ifname);
+        drop(client);
+
+        let mut config = Rtnetlink::new().unwrap();
+        config.add_address(&ifname, ADDRESS, 24).unwrap();
+        // Adding it again replaces it.
+        config.add_address(&ifname, ADDRESS, 24).unwrap();
+        assert!(ip(&["-4", "addr", "show", "dev", &ifname]).contains("192.168.1.42/24"));
+        config.set_mtu(&ifname, 1400).unwrap();
+        let mtu = fs::read_to_string(format!("/sys/class/net/{}/mtu", ifname)).unwrap();
+        assert_eq!(mtu.trim(), "1400");
+        config.del_address(&ifname, ADDRESS, 24).unwrap();
+        assert!(!ip(&["-4", "addr", "show", "dev", &ifname]).contains("192.168.1.42"));
+        assert!(config.set_mtu("gkdhcp-missing", 1400).is_err());
+
+        ip(&["link", "del", &ifname]);
+    }
+}
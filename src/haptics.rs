@@ -0,0 +1,302 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+/// Where devices can tune the effects, see `HapticEffects::parse`.
+pub const SYSTEM_EFFECTS_PATH: &str = "/system/etc/haptics.conf";
+
+/// The predefined haptic effects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum HapticEffect {
+    /// A very light tap, for instance when scrolling through a list.
+    Tick,
+    /// A regular tap, for instance on a button.
+    Click,
+    /// Two clicks in a row.
+    DoubleClick,
+    /// A strong tap, for confirmations.
+    HeavyClick,
+    /// An incoming call, which repeats until canceled.
+    Ringtone,
+}
+
+impl HapticEffect {
+    /// The name used in configuration files.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HapticEffect::Tick => "tick",
+            HapticEffect::Click => "click",
+            HapticEffect::DoubleClick => "double_click",
+            HapticEffect::HeavyClick => "heavy_click",
+            HapticEffect::Ringtone => "ringtone",
+        }
+    }
+
+    /// Parses a configuration name.
+    pub fn from_name(name: &str) -> Option<HapticEffect> {
+        match name {
+            "tick" => Some(HapticEffect::Tick),
+            "click" => Some(HapticEffect::Click),
+            "double_click" => Some(HapticEffect::DoubleClick),
+            "heavy_click" => Some(HapticEffect::HeavyClick),
+            "ringtone" => Some(HapticEffect::Ringtone),
+            _ => None,
+        }
+    }
+}
+
+/// How strongly an effect is played.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EffectStrength {
+    Light,
+    Medium,
+    Strong,
+}
+
+impl EffectStrength {
+    /// Scales an amplitude of the waveform. Pauses stay pauses.
+    pub fn scale(&self, amplitude: u8) -> u8 {
+        let percent = match *self {
+            EffectStrength::Light => 50,
+            EffectStrength::Medium => 75,
+            EffectStrength::Strong => 100,
+        };
+        if amplitude == 0 {
+            0
+        } else {
+            ((amplitude as u32 * percent / 100) as u8).max(1)
+        }
+    }
+}
+
+/// The vibration of an effect, as `(duration, amplitude)` steps like
+/// `Vibrator::amplitude_pattern` takes.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Waveform {
+    pub steps: Vec<(Duration, u8)>,
+    /// Where to loop from once the end is reached, if the effect repeats.
+    pub repeat: Option<usize>,
+}
+
+impl Waveform {
+    fn once(steps: &[(u64, u8)]) -> Waveform {
+        Waveform {
+            steps: steps
+                .iter()
+                .map(|&(ms, amplitude)| (Duration::from_millis(ms), amplitude))
+                .collect(),
+            repeat: None,
+        }
+    }
+}
+
+/// The waveforms of all the effects.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HapticEffects {
+    waveforms: HashMap<HapticEffect, Waveform>,
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed haptic effect: {}", line),
+    )
+}
+
+impl Default for HapticEffects {
+    /// The built-in effects, tuned for common eccentric motors.
+    fn default() -> Self {
+        let mut waveforms = HashMap::new();
+        waveforms.insert(HapticEffect::Tick, Waveform::once(&[(10, 100)]));
+        waveforms.insert(HapticEffect::Click, Waveform::once(&[(20, 180)]));
+        waveforms.insert(
+            HapticEffect::DoubleClick,
+            Waveform::once(&[(20, 180), (100, 0), (20, 180)]),
+        );
+        waveforms.insert(HapticEffect::HeavyClick, Waveform::once(&[(30, 255)]));
+        let mut ringtone = Waveform::once(&[(1000, 255), (1000, 0)]);
+        ringtone.repeat = Some(0);
+        waveforms.insert(HapticEffect::Ringtone, ringtone);
+        HapticEffects { waveforms }
+    }
+}
+
+impl HapticEffects {
+    /// The built-in effects, with the overrides of `SYSTEM_EFFECTS_PATH` if
+    /// that file exists and is valid.
+    pub fn system() -> Self {
+        HapticEffects::load(SYSTEM_EFFECTS_PATH).unwrap_or_default()
+    }
+
+    /// The built-in effects, with the overrides of this file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut content = String::new();
+        File::open(path)?.read_to_string(&mut content)?;
+        HapticEffects::parse(&content)
+    }
+
+    /// The built-in effects, with overrides given one per line as
+    /// `effect=duration:amplitude,...` with durations in milliseconds, and
+    /// `effect.repeat=index` or `effect.repeat=none`. For instance:
+    ///
+    /// ```text
+    /// # A stronger click for this motor.
+    /// click=25:220
+    /// ringtone=800:255,400:0,800:255,1500:0
+    /// ringtone.repeat=0
+    /// ```
+    pub fn parse(content: &str) -> io::Result<Self> {
+        let mut effects = HapticEffects::default();
+        for line in content.lines().map(|line| line.trim()) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = parts.next().ok_or_else(|| invalid(line))?.trim();
+            let (name, repeat) = match key.strip_suffix(".repeat") {
+                Some(name) => (name, true),
+                None => (key, false),
+            };
+            let effect = HapticEffect::from_name(name).ok_or_else(|| invalid(line))?;
+            let waveform = effects.waveforms.get_mut(&effect).unwrap();
+
+            if repeat {
+                waveform.repeat = match value {
+                    "none" => None,
+                    index => Some(index.parse().map_err(|_| invalid(line))?),
+                };
+            } else {
+                waveform.steps = value
+                    .split(',')
+                    .map(|step| {
+                        let mut parts = step.trim().splitn(2, ':');
+                        let ms = parts.next().and_then(|ms| ms.parse().ok());
+                        let amplitude = parts.next().and_then(|a| a.parse().ok());
+                        match (ms, amplitude) {
+                            (Some(ms), Some(amplitude)) => {
+                                Ok((Duration::from_millis(ms), amplitude))
+                            }
+                            _ => Err(invalid(line)),
+                        }
+                    })
+                    .collect::<io::Result<_>>()?;
+            }
+        }
+
+        // Don't let a tuned waveform loop from a step it no longer has.
+        for (effect, waveform) in &effects.waveforms {
+            if waveform
+                .repeat
+                .is_some_and(|index| index >= waveform.steps.len())
+            {
+                return Err(invalid(effect.as_str()));
+            }
+        }
+        Ok(effects)
+    }
+
+    /// The waveform of an effect.
+    pub fn waveform(&self, effect: HapticEffect) -> &Waveform {
+        &self.waveforms[&effect]
+    }
+
+    /// The waveform of an effect played at some strength.
+    pub fn scaled(&self, effect: HapticEffect, strength: EffectStrength) -> Waveform {
+        let waveform = self.waveform(effect);
+        Waveform {
+            steps: waveform
+                .steps
+                .iter()
+                .map(|&(duration, amplitude)| (duration, strength.scale(amplitude)))
+                .collect(),
+            repeat: waveform.repeat,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn error_kind(content: &str) -> io::ErrorKind {
+        HapticEffects::parse(content).unwrap_err().kind()
+    }
+
+    #[test]
+    fn overrides() {
+        let effects = HapticEffects::parse(
+            "# A stronger click.\n\
+             \n\
+             click = 25:220\n\
+             ringtone=800:255, 400:0,800:255,1500:0\n\
+             ringtone.repeat=2\n\
+             tick.repeat=0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            effects.waveform(HapticEffect::Click),
+            &Waveform {
+                steps: vec![(ms(25), 220)],
+                repeat: None,
+            }
+        );
+        let ringtone = effects.waveform(HapticEffect::Ringtone);
+        assert_eq!(ringtone.steps.len(), 4);
+        assert_eq!(ringtone.steps[1], (ms(400), 0));
+        assert_eq!(ringtone.repeat, Some(2));
+        assert_eq!(effects.waveform(HapticEffect::Tick).repeat, Some(0));
+        // The others keep their built-in waveforms.
+        assert_eq!(
+            effects.waveform(HapticEffect::HeavyClick),
+            HapticEffects::default().waveform(HapticEffect::HeavyClick)
+        );
+
+        let once = HapticEffects::parse("ringtone.repeat=none").unwrap();
+        assert_eq!(once.waveform(HapticEffect::Ringtone).repeat, None);
+    }
+
+    #[test]
+    fn invalid_overrides() {
+        // The tuned ringtone has a single step left to loop from.
+        assert_eq!(
+            error_kind("ringtone=500:255\nringtone.repeat=1"),
+            io::ErrorKind::InvalidData
+        );
+        assert_eq!(error_kind("tick.repeat=1"), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind("buzz=10:255"), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind("click"), io::ErrorKind::InvalidData);
+        assert_eq!(error_kind("click.repeat=first"), io::ErrorKind::InvalidData);
+        for steps in &["10", "10:", ":255", "10:256", "-10:255", "10:255,"] {
+            assert_eq!(
+                error_kind(&format!("click={}", steps)),
+                io::ErrorKind::InvalidData
+            );
+        }
+    }
+
+    #[test]
+    fn strength_scaling() {
+        assert_eq!(EffectStrength::Strong.scale(200), 200);
+        assert_eq!(EffectStrength::Medium.scale(200), 150);
+        assert_eq!(EffectStrength::Light.scale(200), 100);
+        // Pauses stay pauses, vibrations stay vibrations.
+        assert_eq!(EffectStrength::Light.scale(0), 0);
+        assert_eq!(EffectStrength::Light.scale(1), 1);
+        assert_eq!(EffectStrength::Medium.scale(1), 1);
+
+        let scaled =
+            HapticEffects::default().scaled(HapticEffect::DoubleClick, EffectStrength::Light);
+        assert_eq!(scaled.steps, vec![(ms(20), 90), (ms(100), 0), (ms(20), 90)]);
+    }
+}
@@ -4,13 +4,69 @@
 
 //! This crate provides access to features of the gonk HAL
 
+extern crate libc;
+
 mod vibrator;
+mod vibration_pattern;
+mod vibration_policy;
+mod vibrator_scheduler;
+mod vibrator_service;
+mod haptics;
+#[cfg(feature = "hardware")]
 mod hw_module;
 mod lights;
+mod backlight;
+mod auto_brightness;
+mod light_animation;
+mod lights_service;
+mod sysfs_lights;
 mod wifi;
+mod wifi_trace;
 mod wake_lock;
+mod wakelock_stats;
+mod dhcp;
+mod connectivity;
+mod sysfs;
+
+pub mod power;
 
-pub use vibrator::{PatternGuard, Vibrator};
-pub use lights::{LightsModule, LightsDevice, LightKind, LightState, BrightnessMode, FlashMode};
-pub use wifi::Wifi;
-pub use wake_lock::{Wakelock, WakelockLevel};
+pub use vibrator::{LedVibrator, PatternGuard, TimedOutputVibrator, Vibrator, VibratorBackend,
+                   DEFAULT_MAX_PATTERN_DURATION};
+#[cfg(feature = "hardware")]
+pub use vibrator::HalVibrator;
+#[cfg(feature = "hardware_legacy")]
+pub use vibrator::LegacyVibrator;
+pub use vibration_pattern::VibrationPattern;
+pub use vibration_policy::{VibrationCounters, VibrationPolicy, VibrationRejected};
+pub use vibrator_scheduler::{pattern_timing_stats, reset_pattern_timing_stats, PatternOutcome,
+                             PatternTimingStats};
+pub use vibrator_service::{InterruptPolicy, VibrationGuard, VibrationPriority, VibrationRequest,
+                           VibratorService};
+pub use haptics::{EffectStrength, HapticEffect, HapticEffects, Waveform, SYSTEM_EFFECTS_PATH};
+pub use auto_brightness::{AutoBrightness, AutoBrightnessConfig, IioLightSensor, LuxSource,
+                           LuxSpline};
+pub use backlight::{Backlight, Brightness, BrightnessCurve};
+pub use lights::{open_light, BrightnessMode, FlashMode, Light, LightKind, LightState};
+#[cfg(feature = "hardware")]
+pub use lights::{LightsDevice, LightsModule};
+pub use light_animation::{Easing, Keyframe, LightAnimation, LightAnimator, DEFAULT_FRAME_RATE};
+pub use lights_service::{LightGuard, LightPriority, LightsService};
+pub use sysfs_lights::{LedMapping, SysfsLight, SysfsLights};
+pub use wifi::{event_interface, WifiBackend, WifiEvents, WifiInterface, WIFI_INVALID_COMMAND,
+               WIFI_NO_EVENT, WIFI_NO_REPLY, WIFI_TRUNCATED};
+#[cfg(feature = "hardware_legacy")]
+pub use wifi::NativeWifi;
+pub use wifi_trace::{read_trace, Recorder, Replay, TraceEntry, REPLAY_DIVERGED};
+pub use wake_lock::{dump_held_locks, set_default_wakelock_backend, set_wakelock_tracing,
+                    wakelock_tracing, HeldLock, SysfsWakelocks, Wakelock, WakelockBackend,
+                    WakelockLevel, WakelockWatchdog};
+#[cfg(feature = "hardware_legacy")]
+pub use wake_lock::LegacyWakelocks;
+pub use wakelock_stats::{diff_wakelock_stats, parse_proc_wakelocks, parse_wakeup_sources,
+                         wakelock_stats, wakelock_stats_from, StatsSource, WakelockDelta,
+                         WakelockStats};
+pub use dhcp::{connected_interface, hardware_address, options as dhcp_options, DhcpClient,
+               DhcpLease, DhcpMessage, DhcpMessageType, DhcpTransport, InterfaceConfig,
+               Rtnetlink, UdpTransport};
+pub use connectivity::{Connectivity, ConnectivityChecker, HttpClient, HttpResponse, TcpHttpClient,
+                       DEFAULT_PROBE_URL};
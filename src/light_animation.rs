@@ -0,0 +1,581 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use lights::{FlashMode, Light, LightState};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many frames per second animations are rendered at by default.
+pub const DEFAULT_FRAME_RATE: u32 = 30;
+
+// How many frames in a row may take longer than their interval before
+// falling back to hardware flashing.
+const MAX_SLOW_FRAMES: u32 = 3;
+
+type Color = (u8, u8, u8);
+
+/// How a fade progresses over time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Easing {
+    Linear,
+    /// Starts slowly.
+    EaseIn,
+    /// Ends slowly.
+    EaseOut,
+    /// Starts and ends slowly.
+    EaseInOut,
+}
+
+impl Easing {
+    /// Maps the elapsed fraction of a fade to its progress, both in `0..1`.
+    pub fn apply(&self, t: f32) -> f32 {
+        match *self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut => t * t * (3.0 - 2.0 * t),
+        }
+    }
+}
+
+/// A fade to a color.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyframe {
+    pub color: Color,
+    pub duration: Duration,
+    pub easing: Easing,
+}
+
+/// A sequence of fades from a starting color, which may loop.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LightAnimation {
+    start: Color,
+    keyframes: Vec<Keyframe>,
+    repeat: bool,
+}
+
+impl LightAnimation {
+    /// An empty animation showing `start`.
+    pub fn new(start: Color) -> Self {
+        LightAnimation {
+            start,
+            keyframes: vec![],
+            repeat: false,
+        }
+    }
+
+    /// Fades from a color to another.
+    pub fn fade(from: Color, to: Color, duration: Duration, easing: Easing) -> Self {
+        LightAnimation::new(from).then(to, duration, easing)
+    }
+
+    /// Fades a color in and out, every `period`.
+    pub fn breathing(color: Color, period: Duration) -> Self {
+        LightAnimation::new((0, 0, 0))
+            .then(color, period / 2, Easing::EaseInOut)
+            .then((0, 0, 0), period / 2, Easing::EaseInOut)
+            .repeating()
+    }
+
+    /// Cycles through the hues, every `period`.
+    pub fn rainbow(period: Duration) -> Self {
+        let hues = [
+            (255, 255, 0),
+            (0, 255, 0),
+            (0, 255, 255),
+            (0, 0, 255),
+            (255, 0, 255),
+            (255, 0, 0),
+        ];
+        hues.iter()
+            .fold(LightAnimation::new((255, 0, 0)), |animation, &hue| {
+                animation.then(hue, period / hues.len() as u32, Easing::Linear)
+            })
+            .repeating()
+    }
+
+    /// Shows each color for its duration, for instance
+    /// `[(green, 200ms), (black, 100ms), (green, 200ms), (black, 2s)]`.
+    pub fn sequence(steps: &[(Color, Duration)]) -> Self {
+        let start = steps.first().map_or((0, 0, 0), |step| step.0);
+        steps.iter().fold(
+            LightAnimation::new(start),
+            |animation, &(color, duration)| {
+                animation
+                    .then(color, Duration::from_secs(0), Easing::Linear)
+                    .hold(duration)
+            },
+        )
+    }
+
+    /// Adds a fade to `color`.
+    pub fn then(mut self, color: Color, duration: Duration, easing: Easing) -> Self {
+        self.keyframes.push(Keyframe {
+            color,
+            duration,
+            easing,
+        });
+        self
+    }
+
+    /// Keeps the current color for a while.
+    pub fn hold(self, duration: Duration) -> Self {
+        let color = self.end_color();
+        self.then(color, duration, Easing::Linear)
+    }
+
+    /// Loops the animation until it's stopped or replaced.
+    pub fn repeating(mut self) -> Self {
+        self.repeat = true;
+        self
+    }
+
+    /// How long one run of the animation lasts.
+    pub fn duration(&self) -> Duration {
+        self.keyframes
+            .iter()
+            .fold(Duration::from_secs(0), |total, keyframe| {
+                total + keyframe.duration
+            })
+    }
+
+    fn end_color(&self) -> Color {
+        self.keyframes
+            .last()
+            .map_or(self.start, |keyframe| keyframe.color)
+    }
+
+    /// The color shown at some point of the animation, or None once it's
+    /// over.
+    pub fn color_at(&self, elapsed: Duration) -> Option<Color> {
+        let total = self.duration();
+        let mut left = if self.repeat && total > Duration::from_secs(0) {
+            Duration::from_nanos((elapsed.as_nanos() % total.as_nanos()) as u64)
+        } else if elapsed < total {
+            elapsed
+        } else {
+            return None;
+        };
+
+        let mut from = self.start;
+        for keyframe in &self.keyframes {
+            if left < keyframe.duration {
+                let t = left.as_secs_f32() / keyframe.duration.as_secs_f32();
+                return Some(mix(from, keyframe.color, keyframe.easing.apply(t)));
+            }
+            left -= keyframe.duration;
+            from = keyframe.color;
+        }
+        Some(from)
+    }
+
+    /// The closest hardware flashing: the brightest color, on while the
+    /// animation is at least half as bright.
+    pub fn flash_state(&self) -> LightState {
+        let step = Duration::from_millis(10);
+        let samples = (self.duration().as_millis() / 10).max(1) as u32;
+        let colors: Vec<Color> = (0..samples)
+            .filter_map(|i| self.color_at(step * i))
+            .collect();
+        let brightest = colors
+            .iter()
+            .cloned()
+            .max_by_key(|&color| luma(color))
+            .unwrap_or_else(|| self.end_color());
+        let lit = colors
+            .iter()
+            .filter(|&&color| luma(color) * 2 >= luma(brightest))
+            .count() as isize;
+        let unlit = colors.len() as isize - lit;
+
+        if !self.repeat || lit == 0 || unlit == 0 {
+            return LightState {
+                color: brightest,
+                ..LightState::default()
+            };
+        }
+        LightState {
+            color: brightest,
+            flash_mode: FlashMode::Timed,
+            flash_on_ms: lit * 10,
+            flash_off_ms: unlit * 10,
+            ..LightState::default()
+        }
+    }
+}
+
+fn mix(from: Color, to: Color, progress: f32) -> Color {
+    let channel =
+        |from: u8, to: u8| (from as f32 + (to as f32 - from as f32) * progress).round() as u8;
+    (
+        channel(from.0, to.0),
+        channel(from.1, to.1),
+        channel(from.2, to.2),
+    )
+}
+
+fn luma(color: Color) -> u32 {
+    (77 * color.0 as u32 + 150 * color.1 as u32 + 29 * color.2 as u32) >> 8
+}
+
+struct Playing {
+    animation: LightAnimation,
+    started: Instant,
+    // The frames that took too long in a row.
+    slow_frames: u32,
+    flashing: bool,
+}
+
+struct State {
+    playing: Option<Playing>,
+    // Bumped whenever the animation is replaced or stopped.
+    generation: u64,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Plays animations on a light from its own thread. Playing an animation
+/// replaces the current one.
+///
+/// When setting the light takes longer than a frame, the animation falls
+/// back to the closest hardware flashing, see `LightAnimation::flash_state`.
+pub struct LightAnimator {
+    shared: Arc<Shared>,
+}
+
+impl LightAnimator {
+    /// Animates a light at `DEFAULT_FRAME_RATE`.
+    pub fn new(light: Box<dyn Light>) -> Self {
+        LightAnimator::with_frame_rate(light, DEFAULT_FRAME_RATE)
+    }
+
+    /// Animates a light at `fps` frames per second.
+    pub fn with_frame_rate(light: Box<dyn Light>, fps: u32) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                playing: None,
+                generation: 0,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let interval = Duration::from_secs(1) / fps.max(1);
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("light animator".to_owned())
+            .spawn(move || LightAnimator::run(&*light, &thread_shared, interval))
+            .expect("Failed to start light animator thread!");
+        LightAnimator { shared }
+    }
+
+    /// Starts an animation, replacing the current one.
+    pub fn play(&self, animation: LightAnimation) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.generation += 1;
+        state.playing = Some(Playing {
+            animation,
+            started: Instant::now(),
+            slow_frames: 0,
+            flashing: false,
+        });
+        self.shared.changed.notify_all();
+    }
+
+    /// Stops the current animation and turns the light off.
+    pub fn stop(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.generation += 1;
+        state.playing = None;
+        self.shared.changed.notify_all();
+    }
+
+    /// Checks if an animation is playing. Animations that don't repeat stop
+    /// on their last color.
+    pub fn is_playing(&self) -> bool {
+        self.shared.state.lock().unwrap().playing.is_some()
+    }
+
+    /// Checks if the current animation fell back to hardware flashing.
+    pub fn is_flashing(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .playing
+            .as_ref()
+            .is_some_and(|playing| playing.flashing)
+    }
+
+    fn run(light: &dyn Light, shared: &Shared, interval: Duration) {
+        let mut state = shared.state.lock().unwrap();
+        let mut shown = None;
+        // The generation stopped last, to only turn the light off once.
+        let mut stopped = None;
+        loop {
+            if state.shutdown {
+                light.off();
+                return;
+            }
+            let generation = state.generation;
+            let now = Instant::now();
+
+            let (target, next) = match state.playing {
+                None => {
+                    if stopped != Some(generation) {
+                        light.off();
+                        shown = None;
+                        stopped = Some(generation);
+                    }
+                    state = shared.changed.wait(state).unwrap();
+                    continue;
+                }
+                Some(ref playing) if playing.flashing => {
+                    // Only the end of a single run is left to show.
+                    let end = playing.started + playing.animation.duration();
+                    if playing.animation.repeat || now < end {
+                        state = if playing.animation.repeat {
+                            shared.changed.wait(state).unwrap()
+                        } else {
+                            shared.changed.wait_timeout(state, end - now).unwrap().0
+                        };
+                        continue;
+                    }
+                    (None, None)
+                }
+                Some(ref playing) => {
+                    let elapsed = now - playing.started;
+                    let frames = (elapsed.as_nanos() / interval.as_nanos()) as u32 + 1;
+                    let next = playing.started + interval * frames;
+                    (
+                        playing.animation.color_at(elapsed).map(|color| LightState {
+                            color,
+                            ..LightState::default()
+                        }),
+                        Some(next),
+                    )
+                }
+            };
+
+            let target = match target {
+                Some(target) => target,
+                None => {
+                    // The animation is over, and stays on its last color.
+                    let color = state.playing.take().unwrap().animation.end_color();
+                    drop(state);
+                    light.set(LightState {
+                        color,
+                        ..LightState::default()
+                    });
+                    shown = Some(color);
+                    stopped = Some(generation);
+                    state = shared.state.lock().unwrap();
+                    continue;
+                }
+            };
+
+            // Don't hold the lock while the light is slowly updated.
+            let frame_start = Instant::now();
+            if shown != Some(target.color) {
+                drop(state);
+                light.set(target.clone());
+                shown = Some(target.color);
+                state = shared.state.lock().unwrap();
+            }
+            let slow = frame_start.elapsed() > interval;
+
+            if state.generation != generation {
+                continue;
+            }
+            let flash = {
+                let playing = state.playing.as_mut().unwrap();
+                playing.slow_frames = if slow { playing.slow_frames + 1 } else { 0 };
+                if playing.slow_frames >= MAX_SLOW_FRAMES {
+                    playing.flashing = true;
+                    Some(playing.animation.flash_state())
+                } else {
+                    None
+                }
+            };
+            if let Some(flash) = flash {
+                drop(state);
+                light.set(flash);
+                shown = None;
+                state = shared.state.lock().unwrap();
+                continue;
+            }
+
+            let next = next.unwrap();
+            let now = Instant::now();
+            if next > now {
+                state = shared.changed.wait_timeout(state, next - now).unwrap().0;
+            }
+        }
+    }
+}
+
+impl Drop for LightAnimator {
+    /// Stops the animator and turns the light off.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GREEN: Color = (0, 255, 0);
+    const BLACK: Color = (0, 0, 0);
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    // The colors a light was set to, and whether it was flashing.
+    type Log = Arc<Mutex<Vec<(Color, bool)>>>;
+
+    /// A light logging how it's set, taking `delay` for each update.
+    struct FakeLight {
+        log: Log,
+        delay: Duration,
+    }
+
+    impl Light for FakeLight {
+        fn set(&self, state: LightState) -> bool {
+            thread::sleep(self.delay);
+            let flashing = matches!(state.flash_mode, FlashMode::Timed);
+            self.log.lock().unwrap().push((state.color, flashing));
+            true
+        }
+    }
+
+    fn animator(delay: Duration, fps: u32) -> (LightAnimator, Log) {
+        let log = Arc::new(Mutex::new(vec![]));
+        let light = FakeLight {
+            log: log.clone(),
+            delay,
+        };
+        (LightAnimator::with_frame_rate(Box::new(light), fps), log)
+    }
+
+    /// Waits until the light got set to something matching `check`.
+    fn wait_for<F: Fn(&[(Color, bool)]) -> bool>(log: &Mutex<Vec<(Color, bool)>>, check: F) {
+        for _ in 0..500 {
+            if check(&log.lock().unwrap()) {
+                return;
+            }
+            thread::sleep(ms(10));
+        }
+        panic!("The light was never set as expected");
+    }
+
+    #[test]
+    fn easings() {
+        for easing in &[
+            Easing::Linear,
+            Easing::EaseIn,
+            Easing::EaseOut,
+            Easing::EaseInOut,
+        ] {
+            assert_eq!(easing.apply(0.0), 0.0);
+            assert_eq!(easing.apply(1.0), 1.0);
+        }
+        assert_eq!(Easing::Linear.apply(0.25), 0.25);
+        assert_eq!(Easing::EaseIn.apply(0.5), 0.25);
+        assert_eq!(Easing::EaseOut.apply(0.5), 0.75);
+        assert_eq!(Easing::EaseInOut.apply(0.5), 0.5);
+        assert!(Easing::EaseInOut.apply(0.25) < 0.25);
+        assert!(Easing::EaseInOut.apply(0.75) > 0.75);
+    }
+
+    #[test]
+    fn colors_over_time() {
+        let fade = LightAnimation::fade(BLACK, (200, 100, 0), ms(100), Easing::Linear);
+        assert_eq!(fade.duration(), ms(100));
+        assert_eq!(fade.color_at(ms(0)), Some(BLACK));
+        assert_eq!(fade.color_at(ms(50)), Some((100, 50, 0)));
+        assert_eq!(fade.color_at(ms(100)), None);
+        assert_eq!(fade.color_at(ms(1000)), None);
+
+        let looping = fade.clone().repeating();
+        assert_eq!(looping.color_at(ms(100)), Some(BLACK));
+        assert_eq!(looping.color_at(ms(1050)), Some((100, 50, 0)));
+
+        let held = fade.hold(ms(50));
+        assert_eq!(held.duration(), ms(150));
+        assert_eq!(held.color_at(ms(120)), Some((200, 100, 0)));
+        assert_eq!(held.color_at(ms(150)), None);
+    }
+
+    #[test]
+    fn sequences_and_flashing() {
+        let blink = LightAnimation::sequence(&[(GREEN, ms(200)), (BLACK, ms(100))]);
+        assert_eq!(blink.duration(), ms(300));
+        assert_eq!(blink.color_at(ms(0)), Some(GREEN));
+        assert_eq!(blink.color_at(ms(199)), Some(GREEN));
+        assert_eq!(blink.color_at(ms(200)), Some(BLACK));
+        assert_eq!(blink.color_at(ms(300)), None);
+
+        // Flashing only makes sense for animations that loop.
+        let once = blink.flash_state();
+        assert_eq!(once.color, GREEN);
+        assert!(matches!(once.flash_mode, FlashMode::NoFlash));
+
+        let flash = blink.repeating().flash_state();
+        assert_eq!(flash.color, GREEN);
+        assert!(matches!(flash.flash_mode, FlashMode::Timed));
+        assert_eq!((flash.flash_on_ms, flash.flash_off_ms), (200, 100));
+
+        let steady = LightAnimation::new(GREEN).hold(ms(100)).repeating();
+        assert!(matches!(
+            steady.flash_state().flash_mode,
+            FlashMode::NoFlash
+        ));
+    }
+
+    #[test]
+    fn animations_are_replaced_and_stopped() {
+        let (animator, log) = animator(ms(0), 100);
+        animator.play(LightAnimation::new(GREEN).hold(ms(10_000)));
+        wait_for(&log, |log| log.last() == Some(&(GREEN, false)));
+        assert!(animator.is_playing());
+
+        let red = (255, 0, 0);
+        animator.play(LightAnimation::new(red).hold(ms(10_000)));
+        wait_for(&log, |log| log.last() == Some(&(red, false)));
+
+        animator.stop();
+        wait_for(&log, |log| log.last() == Some(&(BLACK, false)));
+        assert!(!animator.is_playing());
+
+        // Animations that don't loop stay on their last color.
+        animator.play(LightAnimation::sequence(&[(GREEN, ms(20)), (red, ms(20))]));
+        wait_for(&log, |log| log.last() == Some(&(red, false)));
+        for _ in 0..100 {
+            if !animator.is_playing() {
+                break;
+            }
+            thread::sleep(ms(10));
+        }
+        assert!(!animator.is_playing());
+        assert_eq!(log.lock().unwrap().last(), Some(&(red, false)));
+    }
+
+    #[test]
+    fn slow_lights_fall_back_to_flashing() {
+        let (animator, log) = animator(ms(30), 100);
+        animator.play(LightAnimation::breathing(GREEN, ms(200)));
+        wait_for(&log, |log| {
+            log.last().is_some_and(|&(_, flashing)| flashing)
+        });
+        assert!(animator.is_flashing());
+        assert_eq!(log.lock().unwrap().last(), Some(&(GREEN, true)));
+        drop(animator);
+        wait_for(&log, |log| log.last() == Some(&(BLACK, false)));
+    }
+}
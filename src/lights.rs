@@ -2,11 +2,16 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+#[cfg(feature = "hardware")]
 use hw_module::{hw_device_t, hw_get_module, hw_module_t};
-use std::mem;
+#[cfg(feature = "hardware")]
 use std::os::raw;
+#[cfg(feature = "hardware")]
+use std::ptr;
+use sysfs_lights::SysfsLights;
 
-pub const LIGHTS_HARDWARE_MODULE_ID: &'static [u8; 7usize] = b"lights\x00";
+#[cfg(feature = "hardware")]
+pub const LIGHTS_HARDWARE_MODULE_ID: &[u8; 7usize] = b"lights\x00";
 
 /**
  * The parameters that can be set for a given light.
@@ -14,8 +19,11 @@ pub const LIGHTS_HARDWARE_MODULE_ID: &'static [u8; 7usize] = b"lights\x00";
  * Not all lights must support all parameters.  If you
  * can do something backward-compatible, you should.
  */
+#[cfg(feature = "hardware")]
 #[repr(C)]
 #[derive(Debug, Clone, Copy)]
+// The documentation is the one of the C header.
+#[allow(clippy::doc_overindented_list_items)]
 pub struct light_state_t {
     /**
      * The color of the LED in ARGB.
@@ -45,8 +53,9 @@ pub struct light_state_t {
     pub brightness_mode: raw::c_int,
 }
 
+#[cfg(feature = "hardware")]
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct light_device_t {
     pub common: hw_device_t,
     /**
@@ -59,16 +68,8 @@ pub struct light_device_t {
     >,
 }
 
-impl Default for light_device_t {
-    fn default() -> Self {
-        light_device_t {
-            common: hw_device_t::default(),
-            set_light: None,
-        }
-    }
-}
-
 /// This enum represents the different possible lights.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum LightKind {
     Backlight,
     Keyboard,
@@ -80,11 +81,12 @@ pub enum LightKind {
     Wifi,
 }
 
+#[cfg(feature = "hardware")]
 impl LightKind {
     /// Returns an ascii representation of this light kind suitable
     /// to use when opening a light device.
-    fn to_cstr(&self) -> &[u8] {
-        match *self {
+    fn to_cstr(self) -> &'static [u8] {
+        match self {
             LightKind::Backlight => b"backlight\x00",
             LightKind::Keyboard => b"keyboard\x00",
             LightKind::Buttons => b"buttons\x00",
@@ -105,7 +107,7 @@ pub enum FlashMode {
     Hardware = 2,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(isize)]
 pub enum BrightnessMode {
     User = 0,
@@ -135,33 +137,68 @@ impl Default for LightState {
     }
 }
 
+#[cfg(feature = "hardware")]
 impl LightState {
     fn as_native(&self) -> light_state_t {
         light_state_t {
-            color: ((0xff as u32) << 24) | ((self.color.0 as u32) << 16)
+            color: (0xff_u32 << 24) | ((self.color.0 as u32) << 16)
                 | ((self.color.1 as u32) << 8) | self.color.2 as u32,
             flash_mode: self.flash_mode.clone() as i32,
             flash_on_ms: self.flash_on_ms as i32,
             flash_off_ms: self.flash_off_ms as i32,
-            brightness_mode: self.brightness_mode.clone() as i32,
+            brightness_mode: self.brightness_mode as i32,
         }
     }
 }
 
-/// A device attached to one light.
+/// A light, whatever drives it.
+pub trait Light: Send {
+    /// Setup a display color and blinking pattern for this light.
+    /// Returns true if successful.
+    fn set(&self, state: LightState) -> bool;
+
+    /// Turn this light off.
+    /// Returns true if successful.
+    fn off(&self) -> bool {
+        self.set(LightState::default())
+    }
+}
+
+/// Opens a light with the lights HAL module, or with the kernel LEDs if
+/// there is no module. The HAL module is only tried with the `hardware`
+/// feature.
+pub fn open_light(light: LightKind) -> Option<Box<dyn Light>> {
+    #[cfg(feature = "hardware")]
+    {
+        if let Some(module) = LightsModule::new() {
+            return module
+                .get_device(light)
+                .map(|device| Box::new(device) as Box<dyn Light>);
+        }
+    }
+    SysfsLights::new()
+        .get_device(light)
+        .map(|device| Box::new(device) as Box<dyn Light>)
+}
+
+/// A device attached to one light. It's closed when dropped.
+#[cfg(feature = "hardware")]
 pub struct LightsDevice {
-    device: Box<light_device_t>,
+    // Owned by the HAL module, which allocated it in `open`.
+    device: *mut light_device_t,
 }
 
+#[cfg(feature = "hardware")]
 impl LightsDevice {
     /// Setup a display color and blinking pattern for this light.
     /// Returns true if successful.
     pub fn set(&self, state: LightState) -> bool {
-        if let Some(set_light) = self.device.set_light {
-            return unsafe { set_light(Box::into_raw(self.device.clone()), &state.as_native()) }
-                == 0;
+        unsafe {
+            match (*self.device).set_light {
+                Some(set_light) => set_light(self.device, &state.as_native()) == 0,
+                None => false,
+            }
         }
-        false
     }
 
     /// Turn this light off.
@@ -171,24 +208,55 @@ impl LightsDevice {
     }
 }
 
+// The lights HAL contract lets `set_light` be called from any thread: the
+// framework calls it from its binder threads, and implementations serialize
+// the calls with a lock of their own, since several devices of a module
+// usually share the same LEDs. The device is not `Sync` though, so it's only
+// used by one thread at a time from here.
+#[cfg(feature = "hardware")]
+unsafe impl Send for LightsDevice {}
+
+#[cfg(feature = "hardware")]
+impl Drop for LightsDevice {
+    fn drop(&mut self) {
+        unsafe {
+            if let Some(close) = (*self.device).common.close {
+                close(self.device as *mut hw_device_t);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "hardware")]
+impl Light for LightsDevice {
+    fn set(&self, state: LightState) -> bool {
+        LightsDevice::set(self, state)
+    }
+}
+
 /// The lights module provides access to the Lights devices.
+#[cfg(feature = "hardware")]
 #[derive(Clone)]
 pub struct LightsModule {
-    module: Box<hw_module_t>,
+    // Static data of the module library, which is never unloaded.
+    module: *const hw_module_t,
 }
 
+#[cfg(feature = "hardware")]
 impl LightsModule {
     /// Instanciates a lights module, or return None if the device
     /// doesn't support lights at all.
     pub fn new() -> Option<Self> {
         unsafe {
-            let mut module: Box<hw_module_t> = mem::uninitialized();
+            let mut module: *mut hw_module_t = ptr::null_mut();
             if hw_get_module(
-                LIGHTS_HARDWARE_MODULE_ID.as_ptr(),
-                &mut module as *mut _ as *mut _,
+                LIGHTS_HARDWARE_MODULE_ID.as_ptr() as *const raw::c_char,
+                &mut module,
             ) == 0
+                && !module.is_null()
+                && !(*module).methods.is_null()
             {
-                Some(LightsModule { module: module })
+                Some(LightsModule { module })
             } else {
                 None
             }
@@ -199,19 +267,18 @@ impl LightsModule {
     /// is not supported.
     pub fn get_device(&self, light: LightKind) -> Option<LightsDevice> {
         unsafe {
-            let mut device: Box<light_device_t> = mem::uninitialized();
-
-            if let Some(open) = (*self.module.methods).open {
-                if open(
-                    Box::into_raw(self.clone().module),
-                    light.to_cstr().as_ptr(),
-                    &mut device as *mut _ as *mut _,
-                ) == 0
-                {
-                    Some(LightsDevice { device: device })
-                } else {
-                    None
-                }
+            let open = (*(*self.module).methods).open?;
+            let mut device: *mut hw_device_t = ptr::null_mut();
+            if open(
+                self.module,
+                light.to_cstr().as_ptr() as *const raw::c_char,
+                &mut device,
+            ) == 0
+                && !device.is_null()
+            {
+                Some(LightsDevice {
+                    device: device as *mut light_device_t,
+                })
             } else {
                 None
             }
@@ -0,0 +1,485 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use lights::{open_light, Light, LightKind, LightState};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Who a light is for, from the least to the most important.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LightPriority {
+    Charging,
+    Message,
+    MissedCall,
+    LowBattery,
+}
+
+struct Request {
+    id: usize,
+    light: LightKind,
+    state: LightState,
+    priority: LightPriority,
+}
+
+struct State {
+    requests: Vec<Request>,
+    // The request shown on each light.
+    shown: HashMap<LightKind, usize>,
+    // The lights to set again from the service thread, as their shown
+    // request changed.
+    stale: HashSet<LightKind>,
+    // The devices given with `with_light`, for the service thread to take.
+    devices: Vec<(LightKind, Box<dyn Light>)>,
+    cycle_interval: Option<Duration>,
+    next_id: usize,
+    shutdown: bool,
+}
+
+impl State {
+    /// The request to show on a light: the most important one, the newest
+    /// winning among equals unless they take turns. With turns, the shown
+    /// request stays until the next turn unless `advance`.
+    fn top(&self, light: LightKind, advance: bool) -> Option<usize> {
+        let top = self
+            .requests
+            .iter()
+            .filter(|request| request.light == light)
+            .map(|request| request.priority)
+            .max()?;
+        let candidates: Vec<usize> = self
+            .requests
+            .iter()
+            .filter(|request| request.light == light && request.priority == top)
+            .map(|request| request.id)
+            .collect();
+        let shown = self.shown.get(&light).cloned();
+        match shown {
+            Some(shown) if self.cycle_interval.is_some() => {
+                if !advance && candidates.contains(&shown) {
+                    return Some(shown);
+                }
+                // The turn goes to the next request in line, since ids only
+                // grow.
+                candidates
+                    .iter()
+                    .find(|&&id| id > shown)
+                    .or_else(|| candidates.first())
+                    .cloned()
+            }
+            _ => candidates.last().cloned(),
+        }
+    }
+
+    /// Updates the request shown on a light, which the service thread sets
+    /// if it changed.
+    fn refresh(&mut self, light: LightKind, advance: bool) {
+        let top = self.top(light, advance);
+        if top == self.shown.get(&light).cloned() {
+            return;
+        }
+        match top {
+            Some(id) => self.shown.insert(light, id),
+            None => self.shown.remove(&light),
+        };
+        self.stale.insert(light);
+    }
+
+    /// The state a light should show, None to turn it off.
+    fn target(&self, light: LightKind) -> Option<LightState> {
+        let id = self.shown.get(&light)?;
+        self.requests
+            .iter()
+            .find(|request| request.id == *id)
+            .map(|request| request.state.clone())
+    }
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+/// Arbitrates the lights between concurrent users. Each light shows its
+/// most important request, the newest one winning among equals unless they
+/// take turns, see `with_cycle_interval`. When a request is withdrawn, the
+/// next one in line is shown again.
+///
+/// The lights are set from the service thread, so requests show up shortly
+/// after being made. The lights should only be driven through the service
+/// once it's created.
+pub struct LightsService {
+    shared: Arc<Shared>,
+}
+
+impl LightsService {
+    /// Creates a service opening the lights with `open_light` as they get
+    /// requested.
+    pub fn new() -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                requests: vec![],
+                shown: HashMap::new(),
+                stale: HashSet::new(),
+                devices: vec![],
+                cycle_interval: None,
+                next_id: 0,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("lights service".to_owned())
+            .spawn(move || LightsService::run(&thread_shared))
+            .expect("Failed to start lights service thread!");
+        LightsService { shared }
+    }
+
+    /// Drives a light with this device instead of opening it.
+    pub fn with_light(self, light: LightKind, device: Box<dyn Light>) -> Self {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.devices.push((light, device));
+            state.shown.remove(&light);
+            state.refresh(light, false);
+        }
+        self.shared.changed.notify_all();
+        self
+    }
+
+    /// Makes requests of the same priority take turns on their light every
+    /// `interval`.
+    pub fn with_cycle_interval(self, interval: Duration) -> Self {
+        self.shared.state.lock().unwrap().cycle_interval = Some(interval);
+        self.shared.changed.notify_all();
+        self
+    }
+
+    /// Requests a light, which shows this state if nothing more important
+    /// does.
+    pub fn request(
+        &self,
+        light: LightKind,
+        state: LightState,
+        priority: LightPriority,
+    ) -> LightGuard {
+        let mut shared_state = self.shared.state.lock().unwrap();
+        let id = shared_state.next_id;
+        shared_state.next_id += 1;
+        shared_state.requests.push(Request {
+            id,
+            light,
+            state,
+            priority,
+        });
+        shared_state.refresh(light, false);
+        self.shared.changed.notify_all();
+        LightGuard {
+            handle: Arc::new(Handle {
+                id,
+                light,
+                shared: self.shared.clone(),
+            }),
+        }
+    }
+
+    // The devices belong to this thread, so they're set without the state
+    // locked, from the latest state.
+    fn run(shared: &Shared) {
+        let mut lights: HashMap<LightKind, Option<Box<dyn Light>>> = HashMap::new();
+        let mut next_turn = None;
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            for (light, device) in state.devices.drain(..) {
+                lights.insert(light, Some(device));
+            }
+
+            let now = Instant::now();
+            if let Some(interval) = state.cycle_interval {
+                match next_turn {
+                    Some(turn) if now >= turn => {
+                        // Only the lights with several top requests change.
+                        let shown: Vec<LightKind> = state.shown.keys().cloned().collect();
+                        for light in shown {
+                            state.refresh(light, true);
+                        }
+                        next_turn = Some(now + interval);
+                    }
+                    None => next_turn = Some(now + interval),
+                    _ => {}
+                }
+            }
+
+            let shutdown = state.shutdown;
+            let targets: Vec<(LightKind, Option<LightState>)> = if shutdown {
+                state
+                    .shown
+                    .drain()
+                    .map(|(light, _)| (light, None))
+                    .collect()
+            } else {
+                let stale: Vec<LightKind> = state.stale.drain().collect();
+                stale
+                    .into_iter()
+                    .map(|light| (light, state.target(light)))
+                    .collect()
+            };
+            if !targets.is_empty() {
+                drop(state);
+                for (light, target) in targets {
+                    let device = lights.entry(light).or_insert_with(|| open_light(light));
+                    if let Some(ref device) = *device {
+                        match target {
+                            Some(target) => device.set(target),
+                            None => device.off(),
+                        };
+                    }
+                }
+                state = shared.state.lock().unwrap();
+            }
+            if shutdown {
+                return;
+            }
+            if !state.stale.is_empty() || !state.devices.is_empty() {
+                continue;
+            }
+
+            state = match next_turn {
+                Some(turn) => {
+                    let now = Instant::now();
+                    if turn <= now {
+                        continue;
+                    }
+                    shared.changed.wait_timeout(state, turn - now).unwrap().0
+                }
+                None => shared.changed.wait(state).unwrap(),
+            };
+        }
+    }
+}
+
+impl Default for LightsService {
+    fn default() -> Self {
+        LightsService::new()
+    }
+}
+
+impl Drop for LightsService {
+    /// Stops the service and turns the lights it drove off.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// The request shared by a `LightGuard` and its clones.
+struct Handle {
+    id: usize,
+    light: LightKind,
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    fn cancel(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.requests.retain(|request| request.id != self.id);
+        state.refresh(self.light, false);
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Controls one request of a `LightsService`, without affecting the others.
+///
+/// The request is withdrawn once the guard and its clones are all dropped,
+/// so that a forgotten request doesn't keep its light forever, or earlier
+/// with `cancel`.
+#[derive(Clone)]
+pub struct LightGuard {
+    handle: Arc<Handle>,
+}
+
+impl LightGuard {
+    /// Changes the requested state, keeping its place in line.
+    pub fn update(&self, new_state: LightState) {
+        let handle = &self.handle;
+        let mut state = handle.shared.state.lock().unwrap();
+        if let Some(request) = state
+            .requests
+            .iter_mut()
+            .find(|request| request.id == handle.id)
+        {
+            request.state = new_state;
+        }
+        if state.shown.get(&handle.light) == Some(&handle.id) {
+            state.stale.insert(handle.light);
+            handle.shared.changed.notify_all();
+        }
+    }
+
+    /// Withdraws the request, for the clones of the guard too. If it was
+    /// shown, the next most important request takes over, or the light is
+    /// turned off.
+    pub fn cancel(&mut self) {
+        self.handle.cancel();
+    }
+
+    /// Checks if the request is still shown or waiting to.
+    pub fn is_active(&self) -> bool {
+        let state = self.handle.shared.state.lock().unwrap();
+        state
+            .requests
+            .iter()
+            .any(|request| request.id == self.handle.id)
+    }
+
+    /// Checks if the request is the one shown on its light.
+    pub fn is_shown(&self) -> bool {
+        let state = self.handle.shared.state.lock().unwrap();
+        state.shown.get(&self.handle.light) == Some(&self.handle.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct FakeLight(Arc<Mutex<Vec<(u8, u8, u8)>>>);
+
+    impl Light for FakeLight {
+        fn set(&self, state: LightState) -> bool {
+            self.0.lock().unwrap().push(state.color);
+            true
+        }
+    }
+
+    fn color(red: u8, green: u8, blue: u8) -> LightState {
+        LightState {
+            color: (red, green, blue),
+            ..LightState::default()
+        }
+    }
+
+    // Waits for the service thread to set `count` colors.
+    fn colors(shown: &Mutex<Vec<(u8, u8, u8)>>, count: usize) -> Vec<(u8, u8, u8)> {
+        for _ in 0..200 {
+            if shown.lock().unwrap().len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        shown.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn the_most_important_request_is_shown() {
+        let shown = Arc::new(Mutex::new(vec![]));
+        let service = LightsService::new()
+            .with_light(LightKind::Notifications, Box::new(FakeLight(shown.clone())));
+        let mut message = service.request(
+            LightKind::Notifications,
+            color(0, 255, 0),
+            LightPriority::Message,
+        );
+        assert_eq!(colors(&shown, 1), vec![(0, 255, 0)]);
+
+        let mut battery = service.request(
+            LightKind::Notifications,
+            color(255, 0, 0),
+            LightPriority::LowBattery,
+        );
+        message.update(color(0, 0, 255));
+        assert!(battery.is_shown() && !message.is_shown());
+        assert_eq!(colors(&shown, 2), vec![(0, 255, 0), (255, 0, 0)]);
+
+        battery.cancel();
+        assert_eq!(colors(&shown, 3)[2], (0, 0, 255));
+        message.cancel();
+        assert_eq!(colors(&shown, 4)[3], (0, 0, 0));
+    }
+
+    /// Gives the turn to the next requests, like the service thread does
+    /// every cycle interval.
+    fn next_turn(service: &LightsService) {
+        let mut state = service.shared.state.lock().unwrap();
+        let shown: Vec<LightKind> = state.shown.keys().cloned().collect();
+        for light in shown {
+            state.refresh(light, true);
+        }
+        service.shared.changed.notify_all();
+    }
+
+    #[test]
+    fn equal_requests_take_turns() {
+        let shown = Arc::new(Mutex::new(vec![]));
+        // Too long to ever elapse, the turns are given by the test.
+        let service = LightsService::new()
+            .with_light(LightKind::Attention, Box::new(FakeLight(shown.clone())))
+            .with_cycle_interval(Duration::from_secs(3600));
+        let guards: Vec<LightGuard> = (1..4)
+            .map(|i| service.request(LightKind::Attention, color(i, 0, 0), LightPriority::Message))
+            .collect();
+        assert!(guards[0].is_shown());
+        assert_eq!(colors(&shown, 1), vec![(1, 0, 0)]);
+        next_turn(&service);
+        assert!(guards[1].is_shown());
+        assert_eq!(colors(&shown, 2)[1], (2, 0, 0));
+
+        // Withdrawing a request doesn't skip the turn of the next one.
+        let mut second = guards[1].clone();
+        second.cancel();
+        assert!(!guards[1].is_active() && guards[2].is_shown());
+        assert_eq!(colors(&shown, 3)[2], (3, 0, 0));
+        next_turn(&service);
+        assert!(guards[0].is_shown());
+        assert_eq!(colors(&shown, 4)[3], (1, 0, 0));
+    }
+
+    #[test]
+    fn turns_follow_the_cycle_interval() {
+        let shown = Arc::new(Mutex::new(vec![]));
+        let service = LightsService::new()
+            .with_light(LightKind::Attention, Box::new(FakeLight(shown.clone())))
+            .with_cycle_interval(Duration::from_millis(20));
+        let _first = service.request(LightKind::Attention, color(1, 0, 0), LightPriority::Message);
+        let _second = service.request(LightKind::Attention, color(2, 0, 0), LightPriority::Message);
+        let colors = colors(&shown, 3);
+        assert_eq!(&colors[..3], [(1, 0, 0), (2, 0, 0), (1, 0, 0)]);
+    }
+
+    #[test]
+    fn dropped_guards_withdraw_their_request() {
+        let shown = Arc::new(Mutex::new(vec![]));
+        let service =
+            LightsService::new().with_light(LightKind::Battery, Box::new(FakeLight(shown.clone())));
+        let charging = service.request(
+            LightKind::Battery,
+            color(0, 255, 0),
+            LightPriority::Charging,
+        );
+        assert_eq!(colors(&shown, 1), vec![(0, 255, 0)]);
+        let low = service.request(
+            LightKind::Battery,
+            color(255, 0, 0),
+            LightPriority::LowBattery,
+        );
+        assert_eq!(colors(&shown, 2)[1], (255, 0, 0));
+        let clone = low.clone();
+        drop(low);
+        // A clone still holds it.
+        assert!(clone.is_shown());
+        drop(clone);
+        assert!(charging.is_shown());
+        assert_eq!(colors(&shown, 3)[2], (0, 255, 0));
+        drop(charging);
+        assert_eq!(colors(&shown, 4)[3], (0, 0, 0));
+    }
+}
@@ -0,0 +1,487 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Suspend and autosleep control through `/sys/power`, and notifications
+//! of suspends and resumes.
+
+use libc::{self, c_int};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use sysfs::write_file;
+
+/// A system sleep state, as listed in `/sys/power/state`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SleepState {
+    /// Suspend-to-idle.
+    Freeze,
+    /// Power-on suspend.
+    Standby,
+    /// Suspend-to-RAM.
+    Mem,
+    /// Hibernation.
+    Disk,
+}
+
+impl SleepState {
+    /// The name used by the kernel.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SleepState::Freeze => "freeze",
+            SleepState::Standby => "standby",
+            SleepState::Mem => "mem",
+            SleepState::Disk => "disk",
+        }
+    }
+
+    /// Parses a kernel state name.
+    pub fn from_name(name: &str) -> Option<SleepState> {
+        match name {
+            "freeze" => Some(SleepState::Freeze),
+            "standby" => Some(SleepState::Standby),
+            "mem" => Some(SleepState::Mem),
+            "disk" => Some(SleepState::Disk),
+            _ => None,
+        }
+    }
+}
+
+/// One line of `/sys/kernel/wakeup_reasons/last_resume_reason`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WakeupReason {
+    /// The interrupt that woke the system up, if the line names one.
+    pub irq: Option<u32>,
+    /// The interrupt name, or the whole line for other reasons such as
+    /// "Abort: ...".
+    pub description: String,
+}
+
+/// Parses the content of `last_resume_reason`.
+pub fn parse_resume_reasons(content: &str) -> Vec<WakeupReason> {
+    content
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut parts = line.splitn(2, char::is_whitespace);
+            match parts.next().and_then(|irq| irq.parse().ok()) {
+                Some(irq) => WakeupReason {
+                    irq: Some(irq),
+                    description: parts.next().unwrap_or("").trim().to_owned(),
+                },
+                None => WakeupReason {
+                    irq: None,
+                    description: line.to_owned(),
+                },
+            }
+        })
+        .collect()
+}
+
+fn clock(id: c_int) -> Duration {
+    let mut tp = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe {
+        libc::clock_gettime(id, &mut tp);
+    }
+    Duration::new(tp.tv_sec as u64, tp.tv_nsec as u32)
+}
+
+/// How long the system has been suspended since boot, which is the
+/// difference between the boot time and the monotonic clocks.
+pub fn time_suspended() -> Duration {
+    let monotonic = clock(libc::CLOCK_MONOTONIC);
+    let boottime = clock(libc::CLOCK_BOOTTIME);
+    boottime.checked_sub(monotonic).unwrap_or_default()
+}
+
+/// The monitors of a `SuspendControl` and its clones.
+#[derive(Debug, Default)]
+struct Monitors {
+    senders: Vec<Sender<SuspendEvent>>,
+    // While `suspend` runs, it reports the transitions itself.
+    suspending: bool,
+    // The suspended time up to the last transition `suspend` reported.
+    reported: Duration,
+}
+
+/// Access to the suspend controls of `/sys/power`.
+#[derive(Clone, Debug)]
+pub struct SuspendControl {
+    root: PathBuf,
+    monitors: Arc<Mutex<Monitors>>,
+}
+
+impl SuspendControl {
+    /// Creates a controller using `/sys`.
+    pub fn new() -> Self {
+        SuspendControl::with_root("/sys")
+    }
+
+    /// Creates a controller using another sysfs root.
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        SuspendControl {
+            root: root.as_ref().to_path_buf(),
+            monitors: Arc::new(Mutex::new(Monitors::default())),
+        }
+    }
+
+    fn read(&self, path: &str) -> io::Result<String> {
+        let mut content = String::new();
+        File::open(self.root.join(path))?.read_to_string(&mut content)?;
+        Ok(content)
+    }
+
+    fn write(&self, path: &str, value: &str) -> io::Result<()> {
+        write_file(&self.root.join(path), value)
+    }
+
+    /// The sleep states supported by the kernel.
+    pub fn supported_states(&self) -> io::Result<Vec<SleepState>> {
+        Ok(self
+            .read("power/state")?
+            .split_whitespace()
+            .filter_map(SleepState::from_name)
+            .collect())
+    }
+
+    /// The state autosleep puts the system in, None when it's off.
+    pub fn autosleep(&self) -> io::Result<Option<SleepState>> {
+        let content = self.read("power/autosleep")?;
+        match content.trim() {
+            "off" => Ok(None),
+            name => SleepState::from_name(name).map(Some).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Unknown autosleep state: {}", name),
+                )
+            }),
+        }
+    }
+
+    /// Enables autosleep to the given state, or disables it with None.
+    pub fn set_autosleep(&self, state: Option<SleepState>) -> io::Result<()> {
+        self.write(
+            "power/autosleep",
+            state.map_or("off", |state| state.as_str()),
+        )
+    }
+
+    /// Reads the number of wakeup events so far. This blocks while wakeup
+    /// events are being processed.
+    pub fn wakeup_count(&self) -> io::Result<u64> {
+        let content = self.read("power/wakeup_count")?;
+        content.trim().parse().map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid wakeup count: {}", content.trim()),
+            )
+        })
+    }
+
+    /// Writes back a count read with `wakeup_count`. Returns false if wakeup
+    /// events happened since, in which case the system must not be
+    /// suspended.
+    pub fn set_wakeup_count(&self, count: u64) -> io::Result<bool> {
+        match self.write("power/wakeup_count", &count.to_string()) {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.raw_os_error() == Some(libc::EINVAL) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Enters `state` right away. The call returns after the system resumed,
+    /// or failed to suspend.
+    pub fn suspend(&self, state: SleepState) -> io::Result<()> {
+        let before = {
+            let mut monitors = self.monitors.lock().unwrap();
+            monitors.suspending = true;
+            monitors.send(SuspendEvent::Suspend(Some(state)));
+            time_suspended()
+        };
+        let res = self.write("power/state", state.as_str());
+
+        let mut monitors = self.monitors.lock().unwrap();
+        monitors.suspending = false;
+        monitors.reported = time_suspended();
+        // Aborted suspends resume too, after no sleep at all.
+        let event = ResumeEvent {
+            slept: monitors.reported.checked_sub(before).unwrap_or_default(),
+            reasons: self.last_resume_reasons().unwrap_or_default(),
+        };
+        monitors.send(SuspendEvent::Resume(event));
+        res
+    }
+
+    /// Suspends without racing with wakeup events, using the `wakeup_count`
+    /// protocol. Returns false if the suspend was aborted by a wakeup event,
+    /// and true after resuming from a successful suspend.
+    pub fn try_suspend(&self, state: SleepState) -> io::Result<bool> {
+        let count = self.wakeup_count()?;
+        if !self.set_wakeup_count(count)? {
+            return Ok(false);
+        }
+        match self.suspend(state) {
+            Ok(()) => Ok(true),
+            Err(ref err) if err.raw_os_error() == Some(libc::EBUSY) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// The reasons of the last resume. Needs a kernel with wakeup reasons
+    /// support.
+    pub fn last_resume_reasons(&self) -> io::Result<Vec<WakeupReason>> {
+        Ok(parse_resume_reasons(
+            &self.read("kernel/wakeup_reasons/last_resume_reason")?,
+        ))
+    }
+
+    /// Starts watching for suspends and resumes. The suspends going through
+    /// `suspend` on this control or its clones are reported right before
+    /// they happen, and followed by their resume. The others, for instance
+    /// by autosleep, are detected every `interval` by the system clocks
+    /// once the system runs again, so their suspend is reported right
+    /// before their resume. The watcher stops within an `interval` after
+    /// the returned `SuspendEvents` is dropped.
+    pub fn monitor(&self, interval: Duration) -> SuspendEvents {
+        let (sender, receiver) = mpsc::channel();
+        self.monitors.lock().unwrap().senders.push(sender.clone());
+        let stopped = Arc::new(AtomicBool::new(false));
+        let control = self.clone();
+        let monitor_stopped = stopped.clone();
+        thread::Builder::new()
+            .name("suspend monitor".to_owned())
+            .spawn(move || {
+                let mut suspended = time_suspended();
+                loop {
+                    thread::sleep(interval);
+                    if monitor_stopped.load(Ordering::SeqCst) {
+                        return;
+                    }
+                    let monitors = control.monitors.lock().unwrap();
+                    if monitors.suspending {
+                        continue;
+                    }
+                    let now = time_suspended();
+                    // The clocks can drift apart by a few ticks while awake.
+                    let last = suspended.max(monitors.reported);
+                    if now > last + Duration::from_millis(100) {
+                        let event = ResumeEvent {
+                            slept: now - last,
+                            reasons: control.last_resume_reasons().unwrap_or_default(),
+                        };
+                        if sender.send(SuspendEvent::Suspend(None)).is_err()
+                            || sender.send(SuspendEvent::Resume(event)).is_err()
+                        {
+                            return;
+                        }
+                    }
+                    suspended = now;
+                }
+            })
+            .expect("Failed to start the suspend monitor");
+        SuspendEvents { receiver, stopped }
+    }
+}
+
+impl Default for SuspendControl {
+    fn default() -> Self {
+        SuspendControl::new()
+    }
+}
+
+impl Monitors {
+    /// Sends an event to the live monitors, forgetting the others.
+    fn send(&mut self, event: SuspendEvent) {
+        self.senders
+            .retain(|sender| sender.send(event.clone()).is_ok());
+    }
+}
+
+/// A transition reported by `SuspendControl::monitor`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SuspendEvent {
+    /// The system is suspending, to this state if it's known.
+    Suspend(Option<SleepState>),
+    /// The system resumed.
+    Resume(ResumeEvent),
+}
+
+/// A resume of the system.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResumeEvent {
+    /// How long the system was suspended, at the precision of the polling
+    /// interval.
+    pub slept: Duration,
+    /// Why it woke up, empty if the kernel doesn't tell.
+    pub reasons: Vec<WakeupReason>,
+}
+
+/// The transitions seen by `SuspendControl::monitor`.
+pub struct SuspendEvents {
+    receiver: Receiver<SuspendEvent>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl SuspendEvents {
+    /// Blocks until the next transition.
+    pub fn recv(&self) -> Result<SuspendEvent, RecvError> {
+        self.receiver.recv()
+    }
+
+    /// Waits up to `timeout` for the next transition. Returns `Ok(None)` on
+    /// timeout.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<SuspendEvent>, RecvError> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(event)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+        }
+    }
+
+    /// Returns a pending transition without blocking.
+    pub fn try_recv(&self) -> Result<Option<SuspendEvent>, RecvError> {
+        match self.receiver.try_recv() {
+            Ok(event) => Ok(Some(event)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(RecvError),
+        }
+    }
+}
+
+impl Drop for SuspendEvents {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Iterator for SuspendEvents {
+    type Item = SuspendEvent;
+
+    fn next(&mut self) -> Option<SuspendEvent> {
+        self.recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn fake_root(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("gonkhal-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("power")).unwrap();
+        fs::create_dir_all(root.join("kernel/wakeup_reasons")).unwrap();
+        root
+    }
+
+    #[test]
+    fn resume_reasons_are_parsed() {
+        let reasons =
+            parse_resume_reasons("170 qpnp_rtc_alarm\n\nAbort: Pending Wakeup Sources: ipc\n");
+        assert_eq!(
+            reasons,
+            vec![
+                WakeupReason {
+                    irq: Some(170),
+                    description: "qpnp_rtc_alarm".to_owned(),
+                },
+                WakeupReason {
+                    irq: None,
+                    description: "Abort: Pending Wakeup Sources: ipc".to_owned(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn autosleep_and_wakeup_count_use_sysfs() {
+        let root = fake_root("suspend");
+        fs::write(root.join("power/state"), "freeze mem disk\n").unwrap();
+        fs::write(root.join("power/autosleep"), "off\n").unwrap();
+        fs::write(root.join("power/wakeup_count"), "42\n").unwrap();
+        let control = SuspendControl::with_root(&root);
+
+        assert_eq!(
+            control.supported_states().unwrap(),
+            vec![SleepState::Freeze, SleepState::Mem, SleepState::Disk]
+        );
+        assert_eq!(control.autosleep().unwrap(), None);
+        control.set_autosleep(Some(SleepState::Mem)).unwrap();
+        assert_eq!(control.autosleep().unwrap(), Some(SleepState::Mem));
+
+        assert_eq!(control.wakeup_count().unwrap(), 42);
+        assert!(control.set_wakeup_count(43).unwrap());
+        assert_eq!(
+            fs::read_to_string(root.join("power/wakeup_count")).unwrap(),
+            "43"
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn monitor_stops_when_events_are_dropped() {
+        let root = fake_root("suspend-monitor");
+        let control = SuspendControl::with_root(&root);
+        let events = control.monitor(Duration::from_millis(10));
+        let stopped = events.stopped.clone();
+        assert_eq!(events.try_recv(), Ok(None));
+        drop(events);
+        let mut running = true;
+        for _ in 0..100 {
+            if Arc::strong_count(&stopped) == 1 {
+                running = false;
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_dir_all(&root).unwrap();
+        assert!(!running, "The suspend monitor is still running");
+    }
+
+    #[test]
+    fn suspends_are_reported() {
+        let root = fake_root("suspend-events");
+        fs::write(root.join("power/state"), "").unwrap();
+        fs::write(
+            root.join("kernel/wakeup_reasons/last_resume_reason"),
+            "170 qpnp_rtc_alarm\n",
+        )
+        .unwrap();
+        let control = SuspendControl::with_root(&root);
+        let events = control.clone().monitor(Duration::from_millis(10));
+
+        control.suspend(SleepState::Mem).unwrap();
+        assert_eq!(
+            events.recv(),
+            Ok(SuspendEvent::Suspend(Some(SleepState::Mem)))
+        );
+        match events.recv() {
+            Ok(SuspendEvent::Resume(event)) => assert_eq!(event.reasons[0].irq, Some(170)),
+            other => panic!("Unexpected event: {:?}", other),
+        }
+        assert_eq!(fs::read_to_string(root.join("power/state")).unwrap(), "mem");
+        // A failed suspend still resumes.
+        fs::remove_file(root.join("power/state")).unwrap();
+        assert!(control.suspend(SleepState::Freeze).is_err());
+        assert_eq!(
+            events.recv(),
+            Ok(SuspendEvent::Suspend(Some(SleepState::Freeze)))
+        );
+        assert!(matches!(events.recv(), Ok(SuspendEvent::Resume(_))));
+
+        drop(events);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,304 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use lights::{FlashMode, Light, LightKind, LightState};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use sysfs::write_file;
+
+/// The LEDs showing a light, named after their `/sys/class/leds`
+/// directory.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LedMapping {
+    /// A single LED, lit with the brightness of the color.
+    Single(String),
+    /// Red, green and blue LEDs.
+    Rgb(String, String, String),
+}
+
+impl LedMapping {
+    fn leds(&self) -> Vec<&str> {
+        match *self {
+            LedMapping::Single(ref led) => vec![led],
+            LedMapping::Rgb(ref red, ref green, ref blue) => vec![red, green, blue],
+        }
+    }
+}
+
+/// The lights exposed by the kernel LED class, for devices without a
+/// `lights` HAL module.
+#[derive(Clone, Debug)]
+pub struct SysfsLights {
+    root: PathBuf,
+    mapping: HashMap<LightKind, LedMapping>,
+}
+
+impl SysfsLights {
+    /// Uses the LEDs of `/sys` with the common names: `lcd-backlight`,
+    /// `button-backlight`, `keyboard-backlight`, and `red`, `green` and
+    /// `blue` for the battery, notifications and attention lights.
+    pub fn new() -> Self {
+        SysfsLights::with_root("/sys")
+    }
+
+    /// Uses the LEDs of another sysfs root.
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        let rgb = LedMapping::Rgb("red".to_owned(), "green".to_owned(), "blue".to_owned());
+        let mut mapping = HashMap::new();
+        mapping.insert(
+            LightKind::Backlight,
+            LedMapping::Single("lcd-backlight".to_owned()),
+        );
+        mapping.insert(
+            LightKind::Buttons,
+            LedMapping::Single("button-backlight".to_owned()),
+        );
+        mapping.insert(
+            LightKind::Keyboard,
+            LedMapping::Single("keyboard-backlight".to_owned()),
+        );
+        mapping.insert(LightKind::Battery, rgb.clone());
+        mapping.insert(LightKind::Notifications, rgb.clone());
+        mapping.insert(LightKind::Attention, rgb);
+        SysfsLights {
+            root: root.as_ref().to_path_buf(),
+            mapping,
+        }
+    }
+
+    /// Sets the LEDs showing a light, or hides it with `None`.
+    pub fn with_mapping(mut self, light: LightKind, leds: Option<LedMapping>) -> Self {
+        match leds {
+            Some(leds) => self.mapping.insert(light, leds),
+            None => self.mapping.remove(&light),
+        };
+        self
+    }
+
+    /// Returns the specified light device, or None if its LEDs are not
+    /// present.
+    pub fn get_device(&self, light: LightKind) -> Option<SysfsLight> {
+        let mapping = self.mapping.get(&light)?;
+        let leds = mapping
+            .leds()
+            .iter()
+            .map(|name| Led::open(self.root.join("class/leds").join(name)))
+            .collect::<Option<Vec<_>>>()?;
+        Some(SysfsLight {
+            leds,
+            rgb: match *mapping {
+                LedMapping::Single(_) => false,
+                LedMapping::Rgb(..) => true,
+            },
+        })
+    }
+}
+
+impl Default for SysfsLights {
+    fn default() -> Self {
+        SysfsLights::new()
+    }
+}
+
+struct Led {
+    dir: PathBuf,
+    max_brightness: u32,
+}
+
+impl Led {
+    fn open(dir: PathBuf) -> Option<Led> {
+        if !dir.join("brightness").exists() {
+            return None;
+        }
+        let mut content = String::new();
+        let max_brightness = File::open(dir.join("max_brightness"))
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .ok()
+            .and_then(|_| content.trim().parse().ok())
+            .unwrap_or(255);
+        Some(Led {
+            dir,
+            max_brightness,
+        })
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        write_file(&self.dir.join(file), value)
+    }
+}
+
+/// Sets each LED to its level, blinking the lit ones if `blink` is given.
+fn set_leds(leds: &[(&Led, u8)], blink: Option<(isize, isize)>) -> io::Result<()> {
+    let mut blinking = vec![];
+    for &(led, level) in leds {
+        let brightness = (level as u32 * led.max_brightness / 255).to_string();
+        if blink.is_some() && brightness != "0" {
+            led.write("brightness", &brightness)?;
+            led.write("trigger", "timer")?;
+            blinking.push(led);
+        } else {
+            // Not every LED has triggers, the backlight often doesn't.
+            let _ = led.write("trigger", "none");
+            led.write("brightness", &brightness)?;
+        }
+    }
+    // The delay files only exist once the timer trigger is set, and each
+    // write restarts the blinking. Writing them for all the LEDs last keeps
+    // the colors of a blinking light in phase.
+    if let Some((on, off)) = blink {
+        for led in &blinking {
+            led.write("delay_on", &on.to_string())?;
+        }
+        for led in &blinking {
+            led.write("delay_off", &off.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+/// A light backed by kernel LEDs.
+pub struct SysfsLight {
+    leds: Vec<Led>,
+    rgb: bool,
+}
+
+impl SysfsLight {
+    fn apply(&self, state: &LightState) -> io::Result<()> {
+        let (red, green, blue) = state.color;
+        let blink = match state.flash_mode {
+            FlashMode::NoFlash => None,
+            // Blinking with the timer trigger is the closest we can do.
+            FlashMode::Timed | FlashMode::Hardware => {
+                if state.flash_on_ms > 0 && state.flash_off_ms > 0 {
+                    Some((state.flash_on_ms, state.flash_off_ms))
+                } else {
+                    None
+                }
+            }
+        };
+        if self.rgb {
+            set_leds(
+                &[
+                    (&self.leds[0], red),
+                    (&self.leds[1], green),
+                    (&self.leds[2], blue),
+                ],
+                blink,
+            )
+        } else {
+            // The formula suggested by the lights HAL.
+            let level = (77 * red as u32 + 150 * green as u32 + 29 * blue as u32) >> 8;
+            set_leds(&[(&self.leds[0], level as u8)], blink)
+        }
+    }
+}
+
+impl Light for SysfsLight {
+    fn set(&self, state: LightState) -> bool {
+        self.apply(&state).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn fake_leds(name: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("gonkhal-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for led in &["red", "green", "blue", "lcd-backlight"] {
+            let dir = root.join("class/leds").join(led);
+            fs::create_dir_all(&dir).unwrap();
+            for file in &["brightness", "trigger", "delay_on", "delay_off"] {
+                fs::write(dir.join(file), "").unwrap();
+            }
+        }
+        fs::write(
+            root.join("class/leds/lcd-backlight/max_brightness"),
+            "100\n",
+        )
+        .unwrap();
+        root
+    }
+
+    fn read(root: &Path, led: &str, file: &str) -> String {
+        fs::read_to_string(root.join("class/leds").join(led).join(file)).unwrap()
+    }
+
+    #[test]
+    fn steady_colors_set_brightness() {
+        let root = fake_leds("sysfs-lights-steady");
+        let lights = SysfsLights::with_root(&root);
+        let notifications = lights.get_device(LightKind::Notifications).unwrap();
+        assert!(notifications.set(LightState {
+            color: (255, 128, 0),
+            ..LightState::default()
+        }));
+        for &(led, brightness) in &[("red", "255"), ("green", "128"), ("blue", "0")] {
+            assert_eq!(read(&root, led, "brightness"), brightness);
+            assert_eq!(read(&root, led, "trigger"), "none");
+        }
+
+        // A single LED gets the luminance, scaled to its maximum.
+        let backlight = lights.get_device(LightKind::Backlight).unwrap();
+        assert!(backlight.set(LightState {
+            color: (255, 255, 255),
+            ..LightState::default()
+        }));
+        assert_eq!(read(&root, "lcd-backlight", "brightness"), "100");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn flashing_colors_use_the_timer_trigger() {
+        let root = fake_leds("sysfs-lights-flash");
+        let light = SysfsLights::with_root(&root)
+            .get_device(LightKind::Attention)
+            .unwrap();
+        assert!(light.set(LightState {
+            color: (0, 255, 255),
+            flash_mode: FlashMode::Timed,
+            flash_on_ms: 300,
+            flash_off_ms: 700,
+            ..LightState::default()
+        }));
+        for led in &["green", "blue"] {
+            assert_eq!(read(&root, led, "brightness"), "255");
+            assert_eq!(read(&root, led, "trigger"), "timer");
+            assert_eq!(read(&root, led, "delay_on"), "300");
+            assert_eq!(read(&root, led, "delay_off"), "700");
+        }
+        // Unlit LEDs don't blink.
+        assert_eq!(read(&root, "red", "brightness"), "0");
+        assert_eq!(read(&root, "red", "trigger"), "none");
+        assert_eq!(read(&root, "red", "delay_on"), "");
+
+        // Flashing without delays is a steady light.
+        assert!(light.set(LightState {
+            color: (0, 255, 0),
+            flash_mode: FlashMode::Hardware,
+            ..LightState::default()
+        }));
+        assert_eq!(read(&root, "green", "trigger"), "none");
+        assert_eq!(read(&root, "green", "brightness"), "255");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn missing_leds_have_no_device() {
+        let root = fake_leds("sysfs-lights-missing");
+        let lights = SysfsLights::with_root(&root);
+        assert!(lights.get_device(LightKind::Buttons).is_none());
+        assert!(lights
+            .with_mapping(LightKind::Backlight, None)
+            .get_device(LightKind::Backlight)
+            .is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
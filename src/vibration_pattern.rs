@@ -0,0 +1,360 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::Duration;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+fn checked_millis(ms: i64) -> io::Result<Duration> {
+    if ms < 0 {
+        return Err(invalid(format!(
+            "Negative duration in vibration pattern: {}",
+            ms
+        )));
+    }
+    Ok(Duration::from_millis(ms as u64))
+}
+
+fn parse_millis(value: &str) -> io::Result<Duration> {
+    value
+        .parse::<i64>()
+        .map_err(|_| invalid(format!("Invalid duration in vibration pattern: {}", value)))
+        .and_then(checked_millis)
+}
+
+/// A sequence of alternating vibrations and pauses, starting with a
+/// vibration like `Vibrator::pattern` does.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VibrationPattern {
+    timings: Vec<Duration>,
+}
+
+impl VibrationPattern {
+    /// Creates a pattern from `on, off` durations.
+    pub fn new(timings: Vec<Duration>) -> Self {
+        VibrationPattern { timings }
+    }
+
+    /// Creates a pattern from `on, off` durations in milliseconds, as
+    /// `Vibrator::pattern` takes. Fails on negative durations.
+    pub fn from_millis(timings: &[isize]) -> io::Result<Self> {
+        timings
+            .iter()
+            .map(|&ms| checked_millis(ms as i64))
+            .collect::<io::Result<_>>()
+            .map(VibrationPattern::new)
+    }
+
+    /// Parses a comma separated list of `on, off` durations in
+    /// milliseconds, like `100, 30, 100`.
+    pub fn parse_list(list: &str) -> io::Result<Self> {
+        if list.trim().is_empty() {
+            return Ok(VibrationPattern::default());
+        }
+        list.split(',')
+            .map(|value| parse_millis(value.trim()))
+            .collect::<io::Result<_>>()
+            .map(VibrationPattern::new)
+    }
+
+    /// Parses a JSON array of milliseconds with the meaning of Android's
+    /// `long[]` patterns: the first value is a delay before vibrating,
+    /// then `on, off` durations alternate.
+    pub fn parse_android_json(json: &str) -> io::Result<Self> {
+        let json = json.trim();
+        let list = match json
+            .strip_prefix('[')
+            .and_then(|json| json.strip_suffix(']'))
+        {
+            Some(list) => list.trim(),
+            None => return Err(invalid(format!("Not a JSON array: {}", json))),
+        };
+        if list.contains('[') || list.contains(']') {
+            return Err(invalid(format!(
+                "Nested JSON arrays aren't patterns: {}",
+                json
+            )));
+        }
+        let android = VibrationPattern::parse_list(list)?;
+        let mut timings = android.timings;
+        if !timings.is_empty() {
+            // An empty vibration makes the delay the first pause.
+            timings.insert(0, Duration::from_secs(0));
+        }
+        Ok(VibrationPattern::new(timings))
+    }
+
+    /// Parses the compact notation `on100 off30 on100`, with durations in
+    /// milliseconds. Consecutive steps of the same kind add up.
+    pub fn parse_notation(notation: &str) -> io::Result<Self> {
+        let mut pattern = VibrationPattern::default();
+        for token in notation.split_whitespace() {
+            let (on, value) = if let Some(value) = token.strip_prefix("on") {
+                (true, value)
+            } else if let Some(value) = token.strip_prefix("off") {
+                (false, value)
+            } else {
+                return Err(invalid(format!("Invalid vibration step: {}", token)));
+            };
+            let value = value.strip_suffix("ms").unwrap_or(value);
+            pattern.push(on, parse_millis(value)?);
+        }
+        Ok(pattern)
+    }
+
+    /// Encodes text in Morse code, with dots lasting `unit`. Dashes last
+    /// three units, and the pauses between symbols, letters and words one,
+    /// three and seven units.
+    pub fn morse(text: &str, unit: Duration) -> io::Result<Self> {
+        let mut pattern = VibrationPattern::default();
+        for (i, word) in text.split_whitespace().enumerate() {
+            if i > 0 {
+                pattern.push(false, unit * 7);
+            }
+            for (j, letter) in word.chars().enumerate() {
+                let code = morse_code(letter)
+                    .ok_or_else(|| invalid(format!("No Morse code for {:?}", letter)))?;
+                if j > 0 {
+                    pattern.push(false, unit * 3);
+                }
+                for (k, symbol) in code.chars().enumerate() {
+                    if k > 0 {
+                        pattern.push(false, unit);
+                    }
+                    pattern.push(true, if symbol == '-' { unit * 3 } else { unit });
+                }
+            }
+        }
+        Ok(pattern)
+    }
+
+    /// Appends a vibration or a pause, merging it with the previous step if
+    /// it's of the same kind.
+    pub fn push(&mut self, on: bool, duration: Duration) {
+        let last_on = self.timings.len() % 2 == 1;
+        if self.timings.is_empty() && !on {
+            self.timings.push(Duration::from_secs(0));
+            self.timings.push(duration);
+        } else if last_on == on {
+            *self.timings.last_mut().unwrap() += duration;
+        } else {
+            self.timings.push(duration);
+        }
+    }
+
+    /// The `on, off` durations.
+    pub fn timings(&self) -> &[Duration] {
+        &self.timings
+    }
+
+    /// How long the pattern lasts.
+    pub fn total_duration(&self) -> Duration {
+        self.timings
+            .iter()
+            .fold(Duration::from_secs(0), |total, &duration| total + duration)
+    }
+
+    /// The durations in milliseconds, as `Vibrator::pattern` takes.
+    pub fn to_millis(&self) -> Vec<isize> {
+        self.timings
+            .iter()
+            .map(|&duration| millis(duration) as isize)
+            .collect()
+    }
+
+    /// The pattern as `(duration, amplitude)` steps at full strength, as
+    /// `Vibrator::amplitude_pattern` takes.
+    pub fn to_steps(&self) -> Vec<(Duration, u8)> {
+        self.timings
+            .iter()
+            .enumerate()
+            .map(|(i, &duration)| (duration, if i % 2 == 0 { 255 } else { 0 }))
+            .collect()
+    }
+}
+
+impl fmt::Display for VibrationPattern {
+    /// Formats the pattern in the compact notation.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, &duration) in self.timings.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            let kind = if i % 2 == 0 { "on" } else { "off" };
+            write!(f, "{}{}", kind, millis(duration))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for VibrationPattern {
+    type Err = io::Error;
+
+    /// Parses the compact notation, see `parse_notation`.
+    fn from_str(notation: &str) -> io::Result<Self> {
+        VibrationPattern::parse_notation(notation)
+    }
+}
+
+fn morse_code(letter: char) -> Option<&'static str> {
+    let code = match letter.to_ascii_uppercase() {
+        'A' => ".-",
+        'B' => "-...",
+        'C' => "-.-.",
+        'D' => "-..",
+        'E' => ".",
+        'F' => "..-.",
+        'G' => "--.",
+        'H' => "....",
+        'I' => "..",
+        'J' => ".---",
+        'K' => "-.-",
+        'L' => ".-..",
+        'M' => "--",
+        'N' => "-.",
+        'O' => "---",
+        'P' => ".--.",
+        'Q' => "--.-",
+        'R' => ".-.",
+        'S' => "...",
+        'T' => "-",
+        'U' => "..-",
+        'V' => "...-",
+        'W' => ".--",
+        'X' => "-..-",
+        'Y' => "-.--",
+        'Z' => "--..",
+        '0' => "-----",
+        '1' => ".----",
+        '2' => "..---",
+        '3' => "...--",
+        '4' => "....-",
+        '5' => ".....",
+        '6' => "-....",
+        '7' => "--...",
+        '8' => "---..",
+        '9' => "----.",
+        '.' => ".-.-.-",
+        ',' => "--..--",
+        '?' => "..--..",
+        '\'' => ".----.",
+        '!' => "-.-.--",
+        '/' => "-..-.",
+        '(' => "-.--.",
+        ')' => "-.--.-",
+        '&' => ".-...",
+        ':' => "---...",
+        ';' => "-.-.-.",
+        '=' => "-...-",
+        '+' => ".-.-.",
+        '-' => "-....-",
+        '"' => ".-..-.",
+        '@' => ".--.-.",
+        _ => return None,
+    };
+    Some(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(timings: &[u64]) -> VibrationPattern {
+        VibrationPattern::new(
+            timings
+                .iter()
+                .map(|&ms| Duration::from_millis(ms))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn notation_round_trips() {
+        for pattern in &[
+            ms(&[]),
+            ms(&[100]),
+            ms(&[0, 30, 100]),
+            ms(&[100, 30, 100, 500]),
+        ] {
+            let notation = pattern.to_string();
+            assert_eq!(&notation.parse::<VibrationPattern>().unwrap(), pattern);
+        }
+        assert_eq!(
+            VibrationPattern::parse_notation("off20 on100ms on50 off30").unwrap(),
+            ms(&[0, 20, 150, 30])
+        );
+        assert!(VibrationPattern::parse_notation("on100 buzz30").is_err());
+        assert!(VibrationPattern::parse_notation("on-5").is_err());
+    }
+
+    #[test]
+    fn millis_and_lists_round_trip() {
+        let pattern = ms(&[100, 30, 100]);
+        assert_eq!(
+            VibrationPattern::from_millis(&pattern.to_millis()).unwrap(),
+            pattern
+        );
+        assert_eq!(
+            VibrationPattern::parse_list(" 100, 30 ,100 ").unwrap(),
+            pattern
+        );
+        assert!(VibrationPattern::from_millis(&[100, -1]).is_err());
+    }
+
+    #[test]
+    fn android_json_starts_with_a_delay() {
+        assert_eq!(
+            VibrationPattern::parse_android_json(" [ 50, 100, 30 ] ").unwrap(),
+            ms(&[0, 50, 100, 30])
+        );
+        assert_eq!(
+            VibrationPattern::parse_android_json("[ ]").unwrap(),
+            VibrationPattern::default()
+        );
+        for json in &["", "[", "100, 30", "[[100, 30]]", "[100, [30]]", "[100,]"] {
+            assert!(
+                VibrationPattern::parse_android_json(json).is_err(),
+                "{:?} was accepted",
+                json
+            );
+        }
+    }
+
+    #[test]
+    fn morse_timing() {
+        let unit = Duration::from_millis(100);
+        assert_eq!(
+            VibrationPattern::morse("SOS", unit).unwrap(),
+            ms(&[
+                100, 100, 100, 100, 100, 300, 300, 100, 300, 100, 300, 300, 100, 100, 100, 100, 100
+            ])
+        );
+        // Letters are three units apart, words seven.
+        assert_eq!(
+            VibrationPattern::morse("et", unit).unwrap(),
+            ms(&[100, 300, 300])
+        );
+        assert_eq!(
+            VibrationPattern::morse("  e   e ", unit).unwrap(),
+            ms(&[100, 700, 100])
+        );
+        assert_eq!(
+            VibrationPattern::morse("", unit).unwrap(),
+            VibrationPattern::default()
+        );
+        for text in &["SOS#", "caf\u{e9}", "a_b"] {
+            let err = VibrationPattern::morse(text, unit).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+}
@@ -0,0 +1,436 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use vibrator::VibratorBackend;
+
+/// How long battery readings are reused, since vibrations can be frequent.
+const BATTERY_READ_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Limits protecting the motor and the battery from runaway vibrations,
+/// see `Vibrator::with_policy`. Nothing is limited by default.
+#[derive(Clone, Debug)]
+pub struct VibrationPolicy {
+    max_duration: Option<Duration>,
+    duty_cycle: Option<(u8, Duration)>,
+    min_battery: Option<u8>,
+    max_temperature: Option<f32>,
+    root: PathBuf,
+}
+
+impl Default for VibrationPolicy {
+    fn default() -> Self {
+        VibrationPolicy::new()
+    }
+}
+
+impl VibrationPolicy {
+    /// Creates a policy without limits, reading the battery state from
+    /// `/sys`.
+    pub fn new() -> Self {
+        VibrationPolicy {
+            max_duration: None,
+            duty_cycle: None,
+            min_battery: None,
+            max_temperature: None,
+            root: PathBuf::from("/sys"),
+        }
+    }
+
+    /// Rejects single vibrations longer than `duration`.
+    pub fn with_max_duration(mut self, duration: Duration) -> Self {
+        self.max_duration = Some(duration);
+        self
+    }
+
+    /// Rejects vibrations that would keep the motor on more than `percent`
+    /// of any `window`.
+    pub fn with_duty_cycle(mut self, percent: u8, window: Duration) -> Self {
+        self.duty_cycle = Some((percent.min(100), window));
+        self
+    }
+
+    /// Rejects vibrations while the battery is below `percent` and not
+    /// charging.
+    pub fn with_min_battery(mut self, percent: u8) -> Self {
+        self.min_battery = Some(percent);
+        self
+    }
+
+    /// Rejects vibrations while the battery is hotter than `celsius`.
+    pub fn with_max_battery_temperature(mut self, celsius: f32) -> Self {
+        self.max_temperature = Some(celsius);
+        self
+    }
+
+    /// Reads the battery state from another sysfs root.
+    pub fn with_root<P: AsRef<Path>>(mut self, root: P) -> Self {
+        self.root = root.as_ref().to_path_buf();
+        self
+    }
+
+    fn battery_file(&self, file: &str) -> Option<String> {
+        let mut content = String::new();
+        File::open(self.root.join("class/power_supply/battery").join(file))
+            .and_then(|mut f| f.read_to_string(&mut content))
+            .ok()
+            .map(|_| content.trim().to_owned())
+    }
+
+    fn read_battery(&self) -> Battery {
+        Battery {
+            charging: self
+                .battery_file("status")
+                .is_some_and(|status| status == "Charging" || status == "Full"),
+            level: self
+                .battery_file("capacity")
+                .and_then(|level| level.parse().ok()),
+            // In tenths of a degree.
+            temperature: self
+                .battery_file("temp")
+                .and_then(|temp| temp.parse::<f32>().ok())
+                .map(|temp| temp / 10.0),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Battery {
+    charging: bool,
+    level: Option<u8>,
+    temperature: Option<f32>,
+}
+
+/// Why a vibration was refused by a `VibrationPolicy`. It's the inner error
+/// of the `io::Error` returned by `Vibrator::try_on`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum VibrationRejected {
+    /// The vibration is longer than allowed.
+    TooLong { requested: Duration, max: Duration },
+    /// The motor was on for `used` of the last `window` already.
+    DutyCycle { used: Duration, window: Duration },
+    /// The battery level, in percent.
+    LowBattery(u8),
+    /// The battery temperature, in Celsius.
+    Overheated(f32),
+}
+
+impl fmt::Display for VibrationRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VibrationRejected::TooLong { requested, max } => write!(
+                f,
+                "Vibration of {:?} is longer than the {:?} allowed",
+                requested, max
+            ),
+            VibrationRejected::DutyCycle { used, window } => write!(
+                f,
+                "Vibrator already on for {:?} of the last {:?}",
+                used, window
+            ),
+            VibrationRejected::LowBattery(level) => {
+                write!(f, "Battery too low to vibrate: {}%", level)
+            }
+            VibrationRejected::Overheated(celsius) => {
+                write!(f, "Battery too hot to vibrate: {}°C", celsius)
+            }
+        }
+    }
+}
+
+impl Error for VibrationRejected {}
+
+/// What a `VibrationPolicy` let through and refused so far.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VibrationCounters {
+    pub accepted: u64,
+    pub too_long: u64,
+    pub duty_cycle: u64,
+    pub low_battery: u64,
+    pub overheated: u64,
+    /// How long the motor was on.
+    pub on_time: Duration,
+}
+
+struct Usage {
+    // The vibrations that may still count in the duty cycle window.
+    history: VecDeque<(Instant, Instant)>,
+    counters: VibrationCounters,
+    // The last battery readings, and when they were made.
+    battery: Option<(Instant, Battery)>,
+}
+
+impl Usage {
+    /// Ends the running vibration, if any.
+    fn truncate(&mut self, now: Instant) {
+        if let Some(last) = self.history.back_mut() {
+            if last.1 > now {
+                self.counters.on_time -= last.1 - now;
+                last.1 = now;
+            }
+        }
+    }
+}
+
+/// A backend enforcing a policy in front of another one.
+pub struct PolicyBackend {
+    inner: Arc<dyn VibratorBackend>,
+    policy: VibrationPolicy,
+    usage: Mutex<Usage>,
+}
+
+impl PolicyBackend {
+    pub fn new(inner: Arc<dyn VibratorBackend>, policy: VibrationPolicy) -> Self {
+        PolicyBackend {
+            inner,
+            policy,
+            usage: Mutex::new(Usage {
+                history: VecDeque::new(),
+                counters: VibrationCounters::default(),
+                battery: None,
+            }),
+        }
+    }
+
+    pub fn counters(&self) -> VibrationCounters {
+        self.usage.lock().unwrap().counters.clone()
+    }
+
+    fn battery(&self, usage: &mut Usage, now: Instant) -> Battery {
+        match usage.battery {
+            Some((read, battery)) if now.duration_since(read) < BATTERY_READ_INTERVAL => battery,
+            _ => {
+                let battery = self.policy.read_battery();
+                usage.battery = Some((now, battery));
+                battery
+            }
+        }
+    }
+
+    fn check(
+        &self,
+        usage: &mut Usage,
+        duration: Duration,
+        now: Instant,
+    ) -> Result<(), VibrationRejected> {
+        let policy = &self.policy;
+        if let Some(max) = policy.max_duration {
+            if duration > max {
+                return Err(VibrationRejected::TooLong {
+                    requested: duration,
+                    max,
+                });
+            }
+        }
+
+        if policy.min_battery.is_some() || policy.max_temperature.is_some() {
+            let battery = self.battery(usage, now);
+            if let (Some(min), Some(level)) = (policy.min_battery, battery.level) {
+                if level < min && !battery.charging {
+                    return Err(VibrationRejected::LowBattery(level));
+                }
+            }
+            if let (Some(max), Some(temperature)) = (policy.max_temperature, battery.temperature) {
+                if temperature > max {
+                    return Err(VibrationRejected::Overheated(temperature));
+                }
+            }
+        }
+
+        if let Some((percent, window)) = policy.duty_cycle {
+            let start = now.checked_sub(window).unwrap_or(now);
+            // A running vibration is replaced by this one.
+            let used = usage
+                .history
+                .iter()
+                .map(|&(from, to)| to.min(now).saturating_duration_since(from.max(start)))
+                .fold(Duration::from_secs(0), |used, on| used + on);
+            // Huge windows would overflow before the division.
+            let allowed = window
+                .checked_mul(percent as u32)
+                .map_or_else(|| window / 100 * percent as u32, |total| total / 100);
+            if used.saturating_add(duration) > allowed {
+                return Err(VibrationRejected::DutyCycle { used, window });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl VibratorBackend for PolicyBackend {
+    fn on(&self, duration: Duration) -> io::Result<()> {
+        let mut usage = self.usage.lock().unwrap();
+        let now = Instant::now();
+        if let Err(rejected) = self.check(&mut usage, duration, now) {
+            {
+                let counters = &mut usage.counters;
+                match rejected {
+                    VibrationRejected::TooLong { .. } => counters.too_long += 1,
+                    VibrationRejected::DutyCycle { .. } => counters.duty_cycle += 1,
+                    VibrationRejected::LowBattery(_) => counters.low_battery += 1,
+                    VibrationRejected::Overheated(_) => counters.overheated += 1,
+                }
+            }
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, rejected));
+        }
+
+        self.inner.on(duration)?;
+        usage.truncate(now);
+        usage.history.push_back((now, now + duration));
+        usage.counters.accepted += 1;
+        usage.counters.on_time += duration;
+        if let Some((_, window)) = self.policy.duty_cycle {
+            if let Some(start) = now.checked_sub(window) {
+                while usage.history.front().is_some_and(|&(_, to)| to < start) {
+                    usage.history.pop_front();
+                }
+            }
+        } else {
+            // Only the running vibration matters then.
+            let len = usage.history.len();
+            usage.history.drain(..len - 1);
+        }
+        Ok(())
+    }
+
+    fn off(&self) -> io::Result<()> {
+        let mut usage = self.usage.lock().unwrap();
+        usage.truncate(Instant::now());
+        self.inner.off()
+    }
+
+    fn supports_amplitude(&self) -> bool {
+        self.inner.supports_amplitude()
+    }
+
+    fn set_amplitude(&self, amplitude: u8) -> io::Result<()> {
+        self.inner.set_amplitude(amplitude)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    struct NullBackend;
+
+    impl VibratorBackend for NullBackend {
+        fn on(&self, _duration: Duration) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn off(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn battery_limits_use_cached_readings() {
+        let root = env::temp_dir().join(format!("gonkhal-policy-{}", process::id()));
+        let battery = root.join("class/power_supply/battery");
+        fs::create_dir_all(&battery).unwrap();
+        fs::write(battery.join("status"), "Discharging\n").unwrap();
+        fs::write(battery.join("capacity"), "10\n").unwrap();
+        fs::write(battery.join("temp"), "300\n").unwrap();
+        let policy = VibrationPolicy::new()
+            .with_min_battery(15)
+            .with_max_battery_temperature(40.0)
+            .with_root(&root);
+        let backend = PolicyBackend::new(Arc::new(NullBackend), policy);
+
+        let rejected = backend.on(Duration::from_millis(10)).unwrap_err();
+        assert_eq!(rejected.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            rejected
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<VibrationRejected>()),
+            Some(&VibrationRejected::LowBattery(10))
+        );
+
+        // Still the reading of the first vibration.
+        fs::write(battery.join("status"), "Charging\n").unwrap();
+        assert!(backend.on(Duration::from_millis(10)).is_err());
+        assert_eq!(backend.counters().low_battery, 2);
+
+        // Once expired, the readings are made again.
+        backend.usage.lock().unwrap().battery = None;
+        assert!(backend.on(Duration::from_millis(10)).is_ok());
+        fs::write(battery.join("temp"), "455\n").unwrap();
+        backend.usage.lock().unwrap().battery = None;
+        assert_eq!(
+            backend
+                .on(Duration::from_millis(10))
+                .unwrap_err()
+                .into_inner()
+                .unwrap()
+                .downcast::<VibrationRejected>()
+                .ok()
+                .map(|rejected| *rejected),
+            Some(VibrationRejected::Overheated(45.5))
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn long_vibrations_are_rejected() {
+        let policy = VibrationPolicy::new().with_max_duration(Duration::from_millis(100));
+        let backend = PolicyBackend::new(Arc::new(NullBackend), policy);
+        let rejected = backend.on(Duration::from_millis(150)).unwrap_err();
+        assert_eq!(
+            rejected
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<VibrationRejected>()),
+            Some(&VibrationRejected::TooLong {
+                requested: Duration::from_millis(150),
+                max: Duration::from_millis(100),
+            })
+        );
+        assert!(backend.on(Duration::from_millis(100)).is_ok());
+        let counters = backend.counters();
+        assert_eq!((counters.accepted, counters.too_long), (1, 1));
+        assert_eq!(counters.on_time, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn duty_cycle_window() {
+        let ms = Duration::from_millis;
+        let window = ms(200);
+        let policy = VibrationPolicy::new().with_duty_cycle(50, window);
+        let backend = PolicyBackend::new(Arc::new(NullBackend), policy);
+        let now = Instant::now();
+        let mut usage = backend.usage.lock().unwrap();
+        // Out of the window, then half in it.
+        usage.history.push_back((now - ms(500), now - ms(300)));
+        usage.history.push_back((now - ms(250), now - ms(150)));
+
+        assert_eq!(backend.check(&mut usage, ms(50), now), Ok(()));
+        assert_eq!(
+            backend.check(&mut usage, ms(51), now),
+            Err(VibrationRejected::DutyCycle {
+                used: ms(50),
+                window,
+            })
+        );
+        // Only the elapsed part of a running vibration counts.
+        usage.history.push_back((now - ms(20), now + ms(1000)));
+        assert_eq!(backend.check(&mut usage, ms(30), now), Ok(()));
+        assert!(backend.check(&mut usage, ms(31), now).is_err());
+        drop(usage);
+
+        let policy = VibrationPolicy::new().with_duty_cycle(50, Duration::MAX);
+        let backend = PolicyBackend::new(Arc::new(NullBackend), policy);
+        assert!(backend.on(ms(10)).is_ok());
+        assert!(backend.on(Duration::from_secs(3600)).is_ok());
+    }
+}
@@ -2,11 +2,34 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use haptics::{EffectStrength, HapticEffect, HapticEffects, Waveform};
+#[cfg(feature = "hardware")]
+use hw_module::{hw_device_t, hw_get_module, hw_module_t};
+#[cfg(feature = "async")]
+use std::future::Future;
+use std::io;
+#[cfg(any(feature = "hardware", feature = "hardware_legacy"))]
 use std::os::raw::c_int;
-use std::{thread, time};
+#[cfg(feature = "hardware")]
+use std::os::raw::{c_char, c_uint};
+use std::path::{Path, PathBuf};
+#[cfg(feature = "async")]
+use std::pin::Pin;
+#[cfg(feature = "hardware")]
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "hardware")]
+use std::sync::Mutex;
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+use std::time;
+use sysfs::write_file;
+use vibration_pattern::VibrationPattern;
+use vibration_policy::{PolicyBackend, VibrationCounters, VibrationPolicy};
+use vibrator_scheduler::{self, Completion, PatternOutcome, Playback};
 
+#[cfg(feature = "hardware_legacy")]
 #[link(name = "hardware_legacy")]
 extern "C" {
     // Return whether the device has a vibrator.
@@ -23,89 +46,765 @@ extern "C" {
     fn vibrator_off() -> c_int;
 }
 
+#[cfg(feature = "hardware")]
+pub const VIBRATOR_HARDWARE_MODULE_ID: &[u8; 9usize] = b"vibrator\x00";
+#[cfg(feature = "hardware")]
+pub const VIBRATOR_DEVICE_ID_MAIN: &[u8; 14usize] = b"main_vibrator\x00";
+
+/**
+ * The vibrator device of the `vibrator` HAL module.
+ */
+#[cfg(feature = "hardware")]
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct vibrator_device_t {
+    pub common: hw_device_t,
+    /**
+     * Turn on vibrator
+     *
+     * This function must only be called after the previous timeout has
+     * expired or was canceled (through vibrator_off()).
+     *
+     * Returns: 0 on success, negative error code on failure.
+     */
+    pub vibrator_on: ::std::option::Option<
+        unsafe extern "C" fn(dev: *mut vibrator_device_t, timeout_ms: c_uint) -> c_int,
+    >,
+    /**
+     * Turn off vibrator
+     *
+     * Cancel a previously-started vibration, if any.
+     *
+     * Returns: 0 on success, negative error code on failure.
+     */
+    pub vibrator_off:
+        ::std::option::Option<unsafe extern "C" fn(dev: *mut vibrator_device_t) -> c_int>,
+}
+
+/// The way the vibrator is driven.
+pub trait VibratorBackend: Send + Sync {
+    /// Turns the vibrator on for `duration`. It turns itself off afterwards.
+    fn on(&self, duration: time::Duration) -> io::Result<()>;
+
+    /// Turns the vibrator off.
+    fn off(&self) -> io::Result<()>;
+
+    /// Checks if `set_amplitude` is supported.
+    fn supports_amplitude(&self) -> bool {
+        false
+    }
+
+    /// Sets the strength of the vibrations, from 1 to 255.
+    fn set_amplitude(&self, _amplitude: u8) -> io::Result<()> {
+        Err(unsupported_amplitude())
+    }
+}
+
+/// Rounds up, since the scheduler passes what's left of a step.
+fn millis(duration: time::Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos().div_ceil(1_000_000) as u64
+}
+
+/// The backend using libhardware_legacy, without amplitude control.
+#[cfg(feature = "hardware_legacy")]
+pub struct LegacyVibrator;
+
+#[cfg(feature = "hardware_legacy")]
+impl LegacyVibrator {
+    /// Returns the backend if the device has a vibrator.
+    pub fn open() -> Option<LegacyVibrator> {
+        if unsafe { vibrator_exists() } == 1 {
+            Some(LegacyVibrator)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(feature = "hardware_legacy")]
+impl VibratorBackend for LegacyVibrator {
+    fn on(&self, duration: time::Duration) -> io::Result<()> {
+        let timeout_ms = millis(duration).min(c_int::MAX as u64);
+        match unsafe { vibrator_on(timeout_ms as c_int) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    fn off(&self) -> io::Result<()> {
+        match unsafe { vibrator_off() } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// The sysfs files some kernels use for the vibration strength.
+const AMPLITUDE_FILES: [&str; 3] = [
+    "class/timed_output/vibrator/amp",
+    "class/timed_output/vibrator/vtg_level",
+    "class/leds/vibrator/amp",
+];
+
+fn amplitude_file(root: &Path) -> Option<PathBuf> {
+    AMPLITUDE_FILES
+        .iter()
+        .map(|file| root.join(file))
+        .find(|path| path.exists())
+}
+
+fn unsupported_amplitude() -> io::Error {
+    io::Error::other("Amplitude control is not supported")
+}
+
+fn write_amplitude(file: &Option<PathBuf>, amplitude: u8) -> io::Result<()> {
+    match *file {
+        Some(ref path) => write_file(path, &amplitude.max(1).to_string()),
+        None => Err(unsupported_amplitude()),
+    }
+}
+
+#[cfg(feature = "hardware")]
+struct DevicePtr(*mut vibrator_device_t);
+
+// The device is only used behind a mutex.
+#[cfg(feature = "hardware")]
+unsafe impl Send for DevicePtr {}
+
+/// The backend using the `vibrator` HAL module. Amplitude control is
+/// available when the kernel has a sysfs file for it.
+#[cfg(feature = "hardware")]
+pub struct HalVibrator {
+    device: Mutex<DevicePtr>,
+    amplitude_file: Option<PathBuf>,
+}
+
+#[cfg(feature = "hardware")]
+impl HalVibrator {
+    /// Opens the main vibrator of the HAL module, or returns None if the
+    /// device doesn't have one.
+    pub fn open() -> Option<HalVibrator> {
+        HalVibrator::open_with_root("/sys")
+    }
+
+    /// Like `open`, looking for the amplitude control in another sysfs root.
+    pub fn open_with_root<P: AsRef<Path>>(root: P) -> Option<HalVibrator> {
+        let device = unsafe {
+            let mut module: *mut hw_module_t = ptr::null_mut();
+            if hw_get_module(
+                VIBRATOR_HARDWARE_MODULE_ID.as_ptr() as *const c_char,
+                &mut module,
+            ) != 0
+                || module.is_null()
+                || (*module).methods.is_null()
+            {
+                return None;
+            }
+            let open = (*(*module).methods).open?;
+            let mut device: *mut hw_device_t = ptr::null_mut();
+            if open(
+                module,
+                VIBRATOR_DEVICE_ID_MAIN.as_ptr() as *const c_char,
+                &mut device,
+            ) != 0
+                || device.is_null()
+            {
+                return None;
+            }
+            device as *mut vibrator_device_t
+        };
+
+        let root = root.as_ref();
+        Some(HalVibrator {
+            device: Mutex::new(DevicePtr(device)),
+            amplitude_file: amplitude_file(root),
+        })
+    }
+}
+
+#[cfg(feature = "hardware")]
+fn hal_result(res: c_int) -> io::Result<()> {
+    if res < 0 {
+        Err(io::Error::from_raw_os_error(-res))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "hardware")]
+impl VibratorBackend for HalVibrator {
+    fn on(&self, duration: time::Duration) -> io::Result<()> {
+        let device = self.device.lock().unwrap();
+        let timeout_ms = millis(duration).min(c_uint::MAX as u64);
+        match unsafe { (*device.0).vibrator_on } {
+            Some(vibrator_on) => hal_result(unsafe { vibrator_on(device.0, timeout_ms as c_uint) }),
+            None => Err(io::Error::other("Missing vibrator_on")),
+        }
+    }
+
+    fn off(&self) -> io::Result<()> {
+        let device = self.device.lock().unwrap();
+        match unsafe { (*device.0).vibrator_off } {
+            Some(vibrator_off) => hal_result(unsafe { vibrator_off(device.0) }),
+            None => Err(io::Error::other("Missing vibrator_off")),
+        }
+    }
+
+    fn supports_amplitude(&self) -> bool {
+        self.amplitude_file.is_some()
+    }
+
+    fn set_amplitude(&self, amplitude: u8) -> io::Result<()> {
+        write_amplitude(&self.amplitude_file, amplitude)
+    }
+}
+
+#[cfg(feature = "hardware")]
+impl Drop for HalVibrator {
+    fn drop(&mut self) {
+        let device = self.device.lock().unwrap();
+        unsafe {
+            if let Some(close) = (*device.0).common.close {
+                close(device.0 as *mut hw_device_t);
+            }
+        }
+    }
+}
+
+/// The backend writing to the kernel's timed_output class, at
+/// `/sys/class/timed_output/vibrator/enable`.
+pub struct TimedOutputVibrator {
+    enable: PathBuf,
+    amplitude_file: Option<PathBuf>,
+}
+
+impl TimedOutputVibrator {
+    /// Returns the backend if this sysfs root has a timed_output vibrator.
+    pub fn open<P: AsRef<Path>>(root: P) -> Option<TimedOutputVibrator> {
+        let root = root.as_ref();
+        let enable = root.join("class/timed_output/vibrator/enable");
+        if !enable.exists() {
+            return None;
+        }
+        Some(TimedOutputVibrator {
+            enable,
+            amplitude_file: amplitude_file(root),
+        })
+    }
+}
+
+impl VibratorBackend for TimedOutputVibrator {
+    fn on(&self, duration: time::Duration) -> io::Result<()> {
+        // The value is the number of milliseconds to vibrate for.
+        write_file(&self.enable, &millis(duration).to_string())
+    }
+
+    fn off(&self) -> io::Result<()> {
+        write_file(&self.enable, "0")
+    }
+
+    fn supports_amplitude(&self) -> bool {
+        self.amplitude_file.is_some()
+    }
+
+    fn set_amplitude(&self, amplitude: u8) -> io::Result<()> {
+        write_amplitude(&self.amplitude_file, amplitude)
+    }
+}
+
+/// The backend using the LED class vibrator of newer kernels, at
+/// `/sys/class/leds/vibrator`, driven by its `activate`, `duration` and
+/// `state` files.
+pub struct LedVibrator {
+    dir: PathBuf,
+    amplitude_file: Option<PathBuf>,
+}
+
+impl LedVibrator {
+    /// Returns the backend if this sysfs root has a LED class vibrator.
+    pub fn open<P: AsRef<Path>>(root: P) -> Option<LedVibrator> {
+        let root = root.as_ref();
+        let dir = root.join("class/leds/vibrator");
+        if ["activate", "duration", "state"]
+            .iter()
+            .any(|file| !dir.join(file).exists())
+        {
+            return None;
+        }
+        Some(LedVibrator {
+            dir,
+            amplitude_file: amplitude_file(root),
+        })
+    }
+}
+
+impl VibratorBackend for LedVibrator {
+    fn on(&self, duration: time::Duration) -> io::Result<()> {
+        // The transient trigger turns the state off once the duration
+        // elapsed.
+        write_file(&self.dir.join("state"), "1")?;
+        write_file(&self.dir.join("duration"), &millis(duration).to_string())?;
+        write_file(&self.dir.join("activate"), "1")
+    }
+
+    fn off(&self) -> io::Result<()> {
+        write_file(&self.dir.join("activate"), "0")
+    }
+
+    fn supports_amplitude(&self) -> bool {
+        self.amplitude_file.is_some()
+    }
+
+    fn set_amplitude(&self, amplitude: u8) -> io::Result<()> {
+        write_amplitude(&self.amplitude_file, amplitude)
+    }
+}
+
+/// Looks for a vibrator exposed by the kernel in this sysfs root.
+fn sysfs_backend(root: &Path) -> Option<Arc<dyn VibratorBackend>> {
+    if let Some(led) = LedVibrator::open(root) {
+        return Some(Arc::new(led));
+    }
+    TimedOutputVibrator::open(root).map(|timed| Arc::new(timed) as Arc<dyn VibratorBackend>)
+}
+
 /// A structure to control cancellation of ongoing vibrations.
 #[derive(Clone)]
 pub struct PatternGuard {
     canceled: Arc<AtomicBool>,
+    backend: Option<Arc<dyn VibratorBackend>>,
+    finished: Arc<Completion>,
 }
 
 impl Default for PatternGuard {
+    /// A guard of a pattern with nothing to play, which is completed.
     fn default() -> Self {
-        PatternGuard { canceled: Arc::new(AtomicBool::new(false)) }
+        PatternGuard {
+            canceled: Arc::new(AtomicBool::new(false)),
+            backend: None,
+            finished: Arc::new(Completion::finished(PatternOutcome::Completed)),
+        }
     }
 }
 
 impl PatternGuard {
     /// Cancels an ongoing vibration pattern. This will immediately turn
-    /// the vibrator off and exit the pattern thread as soon as possible.
+    /// the vibrator off and stop the pattern.
     pub fn cancel(&mut self) {
-        unsafe {
-            if vibrator_exists() == 1 {
-                vibrator_off();
-            }
-        }
-        self.canceled.store(true, Ordering::Relaxed);
+        vibrator_scheduler::cancel(&self.canceled, self.backend.as_ref());
+        self.finished.finish(PatternOutcome::Canceled);
     }
 
     /// Checks if this pattern guard has been canceled.
     pub fn is_canceled(&self) -> bool {
         self.canceled.load(Ordering::Relaxed)
     }
+
+    /// Checks if the pattern ended, and how.
+    pub fn outcome(&self) -> Option<PatternOutcome> {
+        self.finished.outcome()
+    }
+
+    /// Blocks until the pattern ends.
+    pub fn wait(&self) -> PatternOutcome {
+        self.finished.wait()
+    }
+
+    /// Blocks until the pattern ends, or `timeout` elapsed. Returns `None`
+    /// if it's still playing.
+    pub fn wait_timeout(&self, timeout: time::Duration) -> Option<PatternOutcome> {
+        self.finished.wait_timeout(timeout)
+    }
+
+    /// Blocks until the pattern ends, like `wait`, consuming the guard.
+    pub fn join(self) -> PatternOutcome {
+        self.wait()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Future for PatternGuard {
+    type Output = PatternOutcome;
+
+    /// Resolves once the pattern ends.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<PatternOutcome> {
+        match self.finished.poll(cx.waker()) {
+            Some(outcome) => Poll::Ready(outcome),
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// The longest a pattern plays by default, to stop forgotten repeating
+/// patterns.
+pub const DEFAULT_MAX_PATTERN_DURATION: time::Duration = time::Duration::from_secs(300);
+
+/// Converts an `on, off` pattern to `(duration, amplitude)` steps.
+pub(crate) fn pattern_steps(pattern: &[isize]) -> Vec<(time::Duration, u8)> {
+    pattern
+        .iter()
+        .enumerate()
+        .map(|(i, val)| {
+            let duration = time::Duration::from_millis((*val).max(0) as u64);
+            (duration, if i % 2 == 0 { 255 } else { 0 })
+        })
+        .collect()
+}
+
+/// The backend a vibrator plays through, its policy included.
+pub(crate) fn vibrator_backend(vibrator: &Vibrator) -> Arc<dyn VibratorBackend> {
+    vibrator.backend.clone()
+}
+
+/// Validates the index a pattern repeats from.
+pub(crate) fn repeat_index(steps: &[(time::Duration, u8)], repeat: Option<usize>) -> Option<usize> {
+    // Repeating a silent tail would spin without ever vibrating.
+    repeat.filter(|&start| {
+        steps.get(start..).is_some_and(|tail| {
+            tail.iter()
+                .any(|step| step.0 > time::Duration::from_secs(0))
+        })
+    })
 }
 
 /// The vibrator device.
 #[derive(Clone)]
-pub struct Vibrator;
+pub struct Vibrator {
+    backend: Arc<dyn VibratorBackend>,
+    max_pattern_duration: time::Duration,
+    effects: Arc<HapticEffects>,
+    policy: Option<Arc<PolicyBackend>>,
+}
 
 impl Vibrator {
-    /// Creates a `Vibrator` if the hardware supports it, or None.
+    /// Creates a `Vibrator` if the hardware supports it, or None. The
+    /// `vibrator` HAL module is preferred, then the kernel's sysfs
+    /// interfaces, with libhardware_legacy as a last resort. Haptic effects
+    /// use the device's tuning, see `HapticEffects::system`. The HAL
+    /// libraries are only tried with the `hardware` and `hardware_legacy`
+    /// features.
     pub fn new() -> Option<Self> {
-        if unsafe { vibrator_exists() } == 1 {
-            Some(Vibrator)
-        } else {
-            None
+        #[cfg(feature = "hardware")]
+        let hal = HalVibrator::open().map(|hal| Vibrator::with_backend(Arc::new(hal)));
+        #[cfg(not(feature = "hardware"))]
+        let hal = None;
+        let vibrator = hal.or_else(|| Vibrator::with_root("/sys"));
+        #[cfg(feature = "hardware_legacy")]
+        let vibrator = vibrator.or_else(|| {
+            LegacyVibrator::open().map(|legacy| Vibrator::with_backend(Arc::new(legacy)))
+        });
+        vibrator.map(|vibrator| vibrator.with_effects(HapticEffects::system()))
+    }
+
+    /// Creates a `Vibrator` using the LED class or timed_output vibrator of
+    /// this sysfs root, without going through the HAL libraries.
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Option<Self> {
+        sysfs_backend(root.as_ref()).map(Vibrator::with_backend)
+    }
+
+    /// Creates a `Vibrator` using a specific backend.
+    pub fn with_backend(backend: Arc<dyn VibratorBackend>) -> Self {
+        Vibrator {
+            backend,
+            max_pattern_duration: DEFAULT_MAX_PATTERN_DURATION,
+            effects: Arc::new(HapticEffects::default()),
+            policy: None,
         }
     }
 
+    /// Enforces usage limits on all the vibrations, patterns included.
+    /// Refused steps of a pattern are skipped.
+    pub fn with_policy(mut self, policy: VibrationPolicy) -> Self {
+        let backend = Arc::new(PolicyBackend::new(self.backend.clone(), policy));
+        self.backend = backend.clone();
+        self.policy = Some(backend);
+        self
+    }
+
+    /// What the policy let through and refused so far, if there is one.
+    pub fn counters(&self) -> Option<VibrationCounters> {
+        self.policy.as_ref().map(|policy| policy.counters())
+    }
+
+    /// Sets the waveforms used by `effect`.
+    pub fn with_effects(mut self, effects: HapticEffects) -> Self {
+        self.effects = Arc::new(effects);
+        self
+    }
+
+    /// Sets the longest a pattern can play, repetitions included, before
+    /// the vibrator is turned off. Defaults to
+    /// `DEFAULT_MAX_PATTERN_DURATION`.
+    pub fn with_max_pattern_duration(mut self, duration: time::Duration) -> Self {
+        self.max_pattern_duration = duration;
+        self
+    }
+
+    /// The longest a pattern can play.
+    pub fn max_pattern_duration(&self) -> time::Duration {
+        self.max_pattern_duration
+    }
+
     /// Turns the vibrator on for some period of time.
     /// Returns true if successful.
     pub fn on(&self, timeout_ms: isize) -> bool {
-        unsafe { vibrator_on(timeout_ms as c_int) == 0 }
+        self.backend
+            .on(time::Duration::from_millis(timeout_ms.max(0) as u64))
+            .is_ok()
+    }
+
+    /// Like `on`, but reports why the vibrator couldn't be turned on. When a
+    /// policy refuses it, the inner error is a `VibrationRejected`.
+    pub fn try_on(&self, duration: time::Duration) -> io::Result<()> {
+        self.backend.on(duration)
     }
 
     /// Turns the vibrator off.
     /// Returns true if successful.
     pub fn off(&self) -> bool {
-        unsafe { vibrator_off() == 0 }
+        self.backend.off().is_ok()
+    }
+
+    /// Checks if the strength of the vibrations can be changed.
+    pub fn supports_amplitude(&self) -> bool {
+        self.backend.supports_amplitude()
+    }
+
+    /// Sets the strength of the next vibrations, from 1 to 255. A zero is
+    /// treated as 1, use `off` to stop vibrating.
+    /// Returns true if successful.
+    pub fn set_amplitude(&self, amplitude: u8) -> bool {
+        self.backend.set_amplitude(amplitude.max(1)).is_ok()
     }
 
-    /// Vibrates according to a pattern of `on, off` sequence.
+    fn guard(&self) -> PatternGuard {
+        PatternGuard {
+            canceled: Arc::new(AtomicBool::new(false)),
+            backend: Some(self.backend.clone()),
+            finished: Arc::new(Completion::default()),
+        }
+    }
+
+    /// Vibrates according to a pattern of `on, off` sequence. Negative
+    /// durations count as zero, see `VibrationPattern::from_millis`.
     /// This happens on a different thread
     pub fn pattern(vibrator: &Vibrator, pattern: Vec<isize>) -> PatternGuard {
-        let v = vibrator.clone();
-
-        let guard = PatternGuard::default();
-        let g = guard.clone();
-        thread::Builder::new()
-            .name("vibrator".to_owned())
-            .spawn(move || {
-                for (i, val) in pattern.iter().enumerate() {
-
-                    // Early return if this pattern has been canceled.
-                    if g.is_canceled() {
-                        return;
-                    }
-
-                    if i % 2 == 0 {
-                        v.on(*val);
-                    }
-                    // In all cases, wait for the expected duration since on()
-                    // is not a blocking call.
-                    thread::sleep(time::Duration::from_millis(*val as u64));
-                }
-            })
-            .expect("Failed to start vibrator thread!");
+        Vibrator::pattern_with_repeat(vibrator, pattern, None)
+    }
+
+    /// Plays a validated pattern, repeating from the `repeat` index as
+    /// `pattern_with_repeat` does.
+    /// This happens on a different thread
+    pub fn play_pattern(
+        vibrator: &Vibrator,
+        pattern: &VibrationPattern,
+        repeat: Option<usize>,
+    ) -> PatternGuard {
+        vibrator.play(pattern.to_steps(), repeat, false)
+    }
+
+    /// Plays a pattern once. The returned guard is a future resolving when
+    /// the pattern ends:
+    ///
+    /// ```ignore
+    /// let outcome = vibrator.play_async(&pattern).await;
+    /// ```
+    #[cfg(feature = "async")]
+    pub fn play_async(&self, pattern: &VibrationPattern) -> PatternGuard {
+        self.play(pattern.to_steps(), None, false)
+    }
+
+    /// Like `pattern`, but once the end is reached the pattern plays again
+    /// from the `repeat` index, until canceled or until the maximum pattern
+    /// duration is reached. As with Android, the index must be within the
+    /// pattern, otherwise it plays once.
+    pub fn pattern_with_repeat(
+        vibrator: &Vibrator,
+        pattern: Vec<isize>,
+        repeat: Option<usize>,
+    ) -> PatternGuard {
+        let steps = pattern_steps(&pattern);
+        vibrator.play(steps, repeat, false)
+    }
+
+    /// Vibrates according to a sequence of `(duration, amplitude)` steps,
+    /// where a zero amplitude is a pause. Without amplitude support, any
+    /// other amplitude vibrates at full strength.
+    /// This happens on a different thread
+    pub fn amplitude_pattern(
+        vibrator: &Vibrator,
+        pattern: Vec<(time::Duration, u8)>,
+    ) -> PatternGuard {
+        Vibrator::amplitude_pattern_with_repeat(vibrator, pattern, None)
+    }
+
+    /// Like `amplitude_pattern`, repeating from the `repeat` index as
+    /// `pattern_with_repeat` does.
+    pub fn amplitude_pattern_with_repeat(
+        vibrator: &Vibrator,
+        pattern: Vec<(time::Duration, u8)>,
+        repeat: Option<usize>,
+    ) -> PatternGuard {
+        let amplitude = vibrator.supports_amplitude();
+        vibrator.play(pattern, repeat, amplitude)
+    }
+
+    /// The waveform `effect` plays for an effect.
+    pub fn effect_waveform(&self, effect: HapticEffect, strength: EffectStrength) -> Waveform {
+        self.effects.scaled(effect, strength)
+    }
 
+    /// Plays a predefined haptic effect. Without amplitude support, the
+    /// strength makes no difference.
+    /// This happens on a different thread
+    pub fn effect(
+        vibrator: &Vibrator,
+        effect: HapticEffect,
+        strength: EffectStrength,
+    ) -> PatternGuard {
+        let waveform = vibrator.effect_waveform(effect, strength);
+        Vibrator::amplitude_pattern_with_repeat(vibrator, waveform.steps, waveform.repeat)
+    }
+
+    fn play(
+        &self,
+        steps: Vec<(time::Duration, u8)>,
+        repeat: Option<usize>,
+        amplitude: bool,
+    ) -> PatternGuard {
+        let guard = self.guard();
+        vibrator_scheduler::play(Playback {
+            backend: self.backend.clone(),
+            repeat: repeat_index(&steps, repeat),
+            steps,
+            amplitude,
+            // Durations too long to be represented never cut patterns.
+            deadline: time::Instant::now().checked_add(self.max_pattern_duration),
+            position: Arc::new(AtomicUsize::new(0)),
+            canceled: guard.canceled.clone(),
+            finished: guard.finished.clone(),
+        });
         guard
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// A sysfs root with these files, empty.
+    fn fake_root(name: &str, files: &[&str]) -> PathBuf {
+        let root = env::temp_dir().join(format!("gonkhal-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for file in files {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        root
+    }
+
+    fn read(root: &Path, file: &str) -> String {
+        fs::read_to_string(root.join(file)).unwrap()
+    }
+
+    #[test]
+    fn timed_output_writes() {
+        let root = fake_root(
+            "timed-output",
+            &[
+                "class/timed_output/vibrator/enable",
+                "class/timed_output/vibrator/vtg_level",
+            ],
+        );
+        let vibrator = TimedOutputVibrator::open(&root).unwrap();
+        vibrator.on(time::Duration::from_micros(1500)).unwrap();
+        assert_eq!(read(&root, "class/timed_output/vibrator/enable"), "2");
+        vibrator.off().unwrap();
+        assert_eq!(read(&root, "class/timed_output/vibrator/enable"), "0");
+
+        assert!(vibrator.supports_amplitude());
+        vibrator.set_amplitude(0).unwrap();
+        assert_eq!(read(&root, "class/timed_output/vibrator/vtg_level"), "1");
+        vibrator.set_amplitude(200).unwrap();
+        assert_eq!(read(&root, "class/timed_output/vibrator/vtg_level"), "200");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn led_writes() {
+        let root = fake_root(
+            "led-vibrator",
+            &[
+                "class/leds/vibrator/activate",
+                "class/leds/vibrator/duration",
+                "class/leds/vibrator/state",
+            ],
+        );
+        let vibrator = LedVibrator::open(&root).unwrap();
+        vibrator.on(time::Duration::from_millis(30)).unwrap();
+        assert_eq!(read(&root, "class/leds/vibrator/state"), "1");
+        assert_eq!(read(&root, "class/leds/vibrator/duration"), "30");
+        assert_eq!(read(&root, "class/leds/vibrator/activate"), "1");
+        vibrator.off().unwrap();
+        assert_eq!(read(&root, "class/leds/vibrator/activate"), "0");
+
+        assert!(!vibrator.supports_amplitude());
+        assert!(vibrator.set_amplitude(100).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sysfs_detection() {
+        let root = fake_root("vibrator-detection", &[]);
+        assert!(sysfs_backend(&root).is_none());
+        // The LED class needs all its files.
+        let led = [
+            "class/leds/vibrator/activate",
+            "class/leds/vibrator/duration",
+            "class/leds/vibrator/state",
+        ];
+        for file in &led[..2] {
+            let path = root.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, "").unwrap();
+        }
+        assert!(LedVibrator::open(&root).is_none());
+
+        let enable = root.join("class/timed_output/vibrator/enable");
+        fs::create_dir_all(enable.parent().unwrap()).unwrap();
+        fs::write(&enable, "").unwrap();
+        sysfs_backend(&root)
+            .unwrap()
+            .on(time::Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(read(&root, "class/timed_output/vibrator/enable"), "10");
+
+        fs::write(root.join(led[2]), "").unwrap();
+        fs::write(root.join("class/leds/vibrator/amp"), "").unwrap();
+        let backend = sysfs_backend(&root).unwrap();
+        backend.on(time::Duration::from_millis(20)).unwrap();
+        assert_eq!(read(&root, "class/leds/vibrator/duration"), "20");
+        assert_eq!(read(&root, "class/timed_output/vibrator/enable"), "10");
+        assert!(backend.supports_amplitude());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unbounded_patterns() {
+        let root = fake_root("unbounded", &["class/timed_output/vibrator/enable"]);
+        let vibrator = Vibrator::with_root(&root)
+            .unwrap()
+            .with_max_pattern_duration(time::Duration::MAX);
+        let guard = Vibrator::pattern(&vibrator, vec![10, 10, 10]);
+        assert_eq!(
+            guard.wait_timeout(time::Duration::MAX),
+            Some(PatternOutcome::Completed)
+        );
+        assert!(!read(&root, "class/timed_output/vibrator/enable").is_empty());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
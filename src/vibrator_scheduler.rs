@@ -0,0 +1,514 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! The thread playing all the vibration patterns of the process.
+//!
+//! Steps are scheduled at absolute deadlines computed from the start of the
+//! pattern, so a late wakeup doesn't delay the following steps.
+
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::task::Waker;
+use std::thread;
+use std::time::{Duration, Instant};
+use vibrator::VibratorBackend;
+
+/// How accurately the pattern steps start, see `pattern_timing_stats`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PatternTimingStats {
+    /// The number of steps played.
+    pub steps: u64,
+    /// How late steps started on average.
+    pub mean_jitter: Duration,
+    /// The latest a step started.
+    pub max_jitter: Duration,
+}
+
+/// How a pattern ended.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PatternOutcome {
+    /// It played until its end, or until the maximum pattern duration.
+    Completed,
+    /// It was canceled before.
+    Canceled,
+}
+
+/// Signals the end of a pattern to the threads and tasks waiting for it.
+#[derive(Default)]
+pub struct Completion {
+    state: Mutex<(Option<PatternOutcome>, Vec<Waker>)>,
+    done: Condvar,
+}
+
+impl Completion {
+    /// A completion for a pattern that already ended.
+    pub fn finished(outcome: PatternOutcome) -> Self {
+        Completion {
+            state: Mutex::new((Some(outcome), vec![])),
+            done: Condvar::new(),
+        }
+    }
+
+    /// Records how the pattern ended, unless it's known already.
+    pub fn finish(&self, outcome: PatternOutcome) {
+        let mut state = self.state.lock().unwrap();
+        if state.0.is_some() {
+            return;
+        }
+        state.0 = Some(outcome);
+        self.done.notify_all();
+        // Woken tasks may check the outcome right away.
+        let wakers = mem::take(&mut state.1);
+        drop(state);
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// How the pattern ended, if it did.
+    pub fn outcome(&self) -> Option<PatternOutcome> {
+        self.state.lock().unwrap().0
+    }
+
+    /// Blocks until the pattern ends.
+    pub fn wait(&self) -> PatternOutcome {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(outcome) = state.0 {
+                return outcome;
+            }
+            state = self.done.wait(state).unwrap();
+        }
+    }
+
+    /// Blocks until the pattern ends or `timeout` elapsed.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<PatternOutcome> {
+        let deadline = match Instant::now().checked_add(timeout) {
+            Some(deadline) => deadline,
+            // Too far away to ever be reached.
+            None => return Some(self.wait()),
+        };
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            if state.0.is_some() || now >= deadline {
+                return state.0;
+            }
+            state = self.done.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Returns the outcome, or registers the waker to call once it's known.
+    /// Each task waiting on clones of a guard gets woken, polling again
+    /// only updates the waker of the task.
+    pub fn poll(&self, waker: &Waker) -> Option<PatternOutcome> {
+        let mut state = self.state.lock().unwrap();
+        if state.0.is_none() {
+            match state.1.iter_mut().find(|known| known.will_wake(waker)) {
+                Some(known) => known.clone_from(waker),
+                None => state.1.push(waker.clone()),
+            }
+        }
+        state.0
+    }
+}
+
+/// A pattern being played.
+pub struct Playback {
+    pub backend: Arc<dyn VibratorBackend>,
+    pub steps: Vec<(Duration, u8)>,
+    pub repeat: Option<usize>,
+    /// Whether to apply the step amplitudes.
+    pub amplitude: bool,
+    /// When the pattern is cut short, if ever.
+    pub deadline: Option<Instant>,
+    /// The step to start from, then the step being played.
+    pub position: Arc<AtomicUsize>,
+    pub canceled: Arc<AtomicBool>,
+    pub finished: Arc<Completion>,
+}
+
+struct Scheduled {
+    playback: Playback,
+    // The step to start next, and when.
+    position: usize,
+    next: Instant,
+}
+
+#[derive(Default)]
+struct Timing {
+    steps: u64,
+    total_jitter: Duration,
+    max_jitter: Duration,
+}
+
+impl Timing {
+    fn record(&mut self, jitter: Duration) {
+        self.steps += 1;
+        self.total_jitter += jitter;
+        self.max_jitter = self.max_jitter.max(jitter);
+    }
+}
+
+struct State {
+    scheduled: Vec<Scheduled>,
+    timing: Timing,
+}
+
+enum Action {
+    On(Duration),
+    Off,
+    SetAmplitude(u8),
+}
+
+// What a pattern does to its vibrator, done once the state is unlocked.
+struct BackendAction {
+    backend: Arc<dyn VibratorBackend>,
+    canceled: Arc<AtomicBool>,
+    action: Action,
+}
+
+struct Scheduler {
+    state: Mutex<State>,
+    changed: Condvar,
+    // Held while driving the vibrators, so a canceled pattern doesn't start
+    // a step after being turned off.
+    io: Mutex<()>,
+}
+
+static SCHEDULER: OnceLock<Scheduler> = OnceLock::new();
+
+fn scheduler() -> &'static Scheduler {
+    let mut created = false;
+    let scheduler = SCHEDULER.get_or_init(|| {
+        created = true;
+        Scheduler {
+            state: Mutex::new(State {
+                scheduled: vec![],
+                timing: Timing::default(),
+            }),
+            changed: Condvar::new(),
+            io: Mutex::new(()),
+        }
+    });
+    if created {
+        thread::Builder::new()
+            .name("vibrator".to_owned())
+            .spawn(run)
+            .expect("Failed to start vibrator thread!");
+    }
+    scheduler
+}
+
+/// Starts playing a pattern.
+pub fn play(playback: Playback) {
+    let scheduler = scheduler();
+    let mut state = scheduler.state.lock().unwrap();
+    state.scheduled.push(Scheduled {
+        position: playback.position.load(Ordering::Relaxed),
+        playback,
+        next: Instant::now(),
+    });
+    scheduler.changed.notify_all();
+}
+
+/// Cancels a pattern: the flag is set and the vibrator turned off without
+/// racing with the scheduler starting a step.
+pub fn cancel(canceled: &AtomicBool, backend: Option<&Arc<dyn VibratorBackend>>) {
+    let stop = || {
+        canceled.store(true, Ordering::Relaxed);
+        if let Some(backend) = backend {
+            let _ = backend.off();
+        }
+    };
+    match SCHEDULER.get() {
+        Some(scheduler) => {
+            {
+                let _io = scheduler.io.lock().unwrap();
+                stop();
+            }
+            let _state = scheduler.state.lock().unwrap();
+            scheduler.changed.notify_all();
+        }
+        None => stop(),
+    }
+}
+
+/// Returns the timing statistics of the patterns played so far.
+pub fn pattern_timing_stats() -> PatternTimingStats {
+    let state = match SCHEDULER.get() {
+        Some(scheduler) => scheduler.state.lock().unwrap(),
+        None => return PatternTimingStats::default(),
+    };
+    let timing = &state.timing;
+    PatternTimingStats {
+        steps: timing.steps,
+        mean_jitter: if timing.steps > 0 {
+            Duration::from_nanos((timing.total_jitter.as_nanos() / timing.steps as u128) as u64)
+        } else {
+            Duration::from_secs(0)
+        },
+        max_jitter: timing.max_jitter,
+    }
+}
+
+/// Clears the timing statistics.
+pub fn reset_pattern_timing_stats() {
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.state.lock().unwrap().timing = Timing::default();
+    }
+}
+
+/// Queues the actions of the steps that are due. Returns false once the
+/// pattern is over.
+fn advance(
+    scheduled: &mut Scheduled,
+    now: Instant,
+    timing: &mut Timing,
+    actions: &mut Vec<BackendAction>,
+) -> bool {
+    let playback = &scheduled.playback;
+    if playback.canceled.load(Ordering::Relaxed) {
+        return false;
+    }
+    let mut push = |action| {
+        actions.push(BackendAction {
+            backend: playback.backend.clone(),
+            canceled: playback.canceled.clone(),
+            action,
+        })
+    };
+    while scheduled.next <= now {
+        if scheduled.position == playback.steps.len() {
+            match playback.repeat {
+                Some(start) => scheduled.position = start,
+                // The last step is over.
+                None => return false,
+            }
+        }
+        if playback
+            .deadline
+            .is_some_and(|deadline| scheduled.next >= deadline)
+        {
+            push(Action::Off);
+            return false;
+        }
+
+        let (duration, level) = playback.steps[scheduled.position];
+        playback
+            .position
+            .store(scheduled.position, Ordering::Relaxed);
+        let duration = match playback.deadline {
+            Some(deadline) => duration.min(deadline - scheduled.next),
+            None => duration,
+        };
+        let end = scheduled.next + duration;
+        // Steps that ended while catching up are skipped.
+        if end > now {
+            timing.record(now - scheduled.next);
+            if level == 0 {
+                push(Action::Off);
+            } else {
+                if playback.amplitude {
+                    push(Action::SetAmplitude(level));
+                }
+                // Only what's left of the step if we woke up late.
+                push(Action::On(end - now));
+            }
+        }
+        scheduled.next = end;
+        scheduled.position += 1;
+    }
+    true
+}
+
+fn run() {
+    let scheduler = SCHEDULER.get().unwrap();
+    let mut state = scheduler.state.lock().unwrap();
+    loop {
+        let now = Instant::now();
+        let mut actions = vec![];
+        let mut finished = vec![];
+        {
+            let State {
+                ref mut scheduled,
+                ref mut timing,
+            } = *state;
+            scheduled.retain_mut(|scheduled| {
+                let playing = advance(scheduled, now, timing, &mut actions);
+                if !playing {
+                    let playback = &scheduled.playback;
+                    let outcome = if playback.canceled.load(Ordering::Relaxed) {
+                        PatternOutcome::Canceled
+                    } else {
+                        PatternOutcome::Completed
+                    };
+                    finished.push((playback.finished.clone(), outcome));
+                }
+                playing
+            });
+        }
+
+        // The vibrators can be slow, and the completions wake up other
+        // threads, so both happen without blocking `play`.
+        if !actions.is_empty() || !finished.is_empty() {
+            drop(state);
+            {
+                let _io = scheduler.io.lock().unwrap();
+                for action in actions {
+                    if action.canceled.load(Ordering::Relaxed) {
+                        continue;
+                    }
+                    let _ = match action.action {
+                        Action::On(duration) => action.backend.on(duration),
+                        Action::Off => action.backend.off(),
+                        Action::SetAmplitude(level) => action.backend.set_amplitude(level),
+                    };
+                }
+            }
+            for (completion, outcome) in finished {
+                completion.finish(outcome);
+            }
+            state = scheduler.state.lock().unwrap();
+            continue;
+        }
+
+        state = match state.scheduled.iter().map(|scheduled| scheduled.next).min() {
+            Some(next) => {
+                let now = Instant::now();
+                if next <= now {
+                    continue;
+                }
+                scheduler.changed.wait_timeout(state, next - now).unwrap().0
+            }
+            None => scheduler.changed.wait(state).unwrap(),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::task::Wake;
+
+    struct NullBackend;
+
+    impl VibratorBackend for NullBackend {
+        fn on(&self, _duration: Duration) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn off(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn scheduled(steps: Vec<(Duration, u8)>, start: Instant, deadline: Instant) -> Scheduled {
+        Scheduled {
+            playback: Playback {
+                backend: Arc::new(NullBackend),
+                steps,
+                repeat: None,
+                amplitude: true,
+                deadline: Some(deadline),
+                position: Arc::new(AtomicUsize::new(0)),
+                canceled: Arc::new(AtomicBool::new(false)),
+                finished: Arc::new(Completion::default()),
+            },
+            position: 0,
+            next: start,
+        }
+    }
+
+    fn describe(actions: &[BackendAction]) -> Vec<String> {
+        actions
+            .iter()
+            .map(|action| match action.action {
+                Action::On(duration) => format!("on {}ms", duration.as_millis()),
+                Action::Off => "off".to_owned(),
+                Action::SetAmplitude(level) => format!("amplitude {}", level),
+            })
+            .collect()
+    }
+
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn completions_wake_every_waiting_task() {
+        let first = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let second = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let first_waker = Waker::from(first.clone());
+        let completion = Completion::default();
+        assert_eq!(completion.poll(&first_waker), None);
+        assert_eq!(completion.poll(&first_waker), None);
+        assert_eq!(completion.poll(&Waker::from(second.clone())), None);
+        assert_eq!(completion.state.lock().unwrap().1.len(), 2);
+
+        completion.finish(PatternOutcome::Canceled);
+        completion.finish(PatternOutcome::Completed);
+        assert_eq!(first.0.load(Ordering::SeqCst), 1);
+        assert_eq!(second.0.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            completion.poll(&first_waker),
+            Some(PatternOutcome::Canceled)
+        );
+        assert_eq!(completion.wait(), PatternOutcome::Canceled);
+    }
+
+    #[test]
+    fn late_wakeups_skip_the_steps_already_over() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut scheduled = scheduled(
+            vec![(ms(10), 255), (ms(10), 0), (ms(0), 80), (ms(10), 100)],
+            now - ms(25),
+            now + ms(1000),
+        );
+        let mut actions = vec![];
+        let mut timing = Timing::default();
+        assert!(advance(&mut scheduled, now, &mut timing, &mut actions));
+        assert_eq!(describe(&actions), vec!["amplitude 100", "on 5ms"]);
+        assert_eq!(timing.steps, 1);
+        assert_eq!(scheduled.playback.position.load(Ordering::Relaxed), 3);
+
+        // The pattern is over once its last step ended.
+        actions.clear();
+        assert!(!advance(
+            &mut scheduled,
+            now + ms(5),
+            &mut timing,
+            &mut actions
+        ));
+        assert!(actions.is_empty());
+    }
+
+    #[test]
+    fn the_deadline_cuts_patterns_short() {
+        let ms = Duration::from_millis;
+        let now = Instant::now();
+        let mut scheduled = scheduled(vec![(ms(100), 255)], now, now + ms(30));
+        scheduled.playback.repeat = Some(0);
+        let mut actions = vec![];
+        let mut timing = Timing::default();
+        assert!(advance(&mut scheduled, now, &mut timing, &mut actions));
+        assert_eq!(describe(&actions), vec!["amplitude 255", "on 30ms"]);
+
+        actions.clear();
+        assert!(!advance(
+            &mut scheduled,
+            now + ms(30),
+            &mut timing,
+            &mut actions
+        ));
+        assert_eq!(describe(&actions), vec!["off"]);
+    }
+}
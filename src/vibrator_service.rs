@@ -0,0 +1,450 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use haptics::Waveform;
+#[cfg(feature = "async")]
+use std::future::Future;
+#[cfg(feature = "async")]
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(feature = "async")]
+use std::task::{Context, Poll};
+use std::task::{Wake, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
+use vibrator::{pattern_steps, repeat_index, vibrator_backend, Vibrator};
+use vibrator_scheduler::{self, Completion, PatternOutcome, Playback};
+
+/// Who a vibration is for, from the least to the most important.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum VibrationPriority {
+    TouchFeedback,
+    Notification,
+    Call,
+    Alarm,
+}
+
+/// What happens to a vibration when a more important one takes over.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InterruptPolicy {
+    /// It's canceled.
+    Drop,
+    /// It waits, and continues from the interrupted step once it's the most
+    /// important again.
+    Resume,
+}
+
+/// A vibration to play through a `VibratorService`.
+#[derive(Clone, Debug)]
+pub struct VibrationRequest {
+    steps: Vec<(Duration, u8)>,
+    repeat: Option<usize>,
+    amplitude: bool,
+    priority: VibrationPriority,
+    policy: InterruptPolicy,
+}
+
+impl VibrationRequest {
+    /// A request for an `on, off` pattern, as `Vibrator::pattern_with_repeat`
+    /// takes. It has a notification priority and is dropped when
+    /// interrupted.
+    pub fn pattern(pattern: Vec<isize>, repeat: Option<usize>) -> Self {
+        VibrationRequest {
+            steps: pattern_steps(&pattern),
+            repeat,
+            amplitude: false,
+            priority: VibrationPriority::Notification,
+            policy: InterruptPolicy::Drop,
+        }
+    }
+
+    /// A request for a waveform with amplitudes, for instance a haptic
+    /// effect from `Vibrator::effect_waveform`.
+    pub fn waveform(waveform: Waveform) -> Self {
+        VibrationRequest {
+            steps: waveform.steps,
+            repeat: waveform.repeat,
+            amplitude: true,
+            priority: VibrationPriority::Notification,
+            policy: InterruptPolicy::Drop,
+        }
+    }
+
+    /// Sets the priority of the request.
+    pub fn with_priority(mut self, priority: VibrationPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Sets what happens when the request gets interrupted.
+    pub fn with_policy(mut self, policy: InterruptPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+}
+
+struct Pending {
+    id: usize,
+    request: VibrationRequest,
+    // The step to play next, or being played, kept up to date by the
+    // scheduler.
+    position: Arc<AtomicUsize>,
+    // How long it played so far, which counts towards the maximum pattern
+    // duration.
+    played: Duration,
+    finished: Arc<Completion>,
+}
+
+// The scheduler playback of the request driving the vibrator.
+struct Playing {
+    id: usize,
+    started: Instant,
+    canceled: Arc<AtomicBool>,
+    finished: Arc<Completion>,
+}
+
+struct State {
+    pending: Vec<Pending>,
+    current: Option<Playing>,
+    next_id: usize,
+    shutdown: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+impl Shared {
+    fn remove(state: &mut State, id: usize, outcome: PatternOutcome) {
+        if let Some(index) = state.pending.iter().position(|pending| pending.id == id) {
+            state.pending.remove(index).finished.finish(outcome);
+        }
+    }
+}
+
+// Wakes the service thread up when a playback ends.
+struct PlaybackWaker(Arc<Shared>);
+
+impl Wake for PlaybackWaker {
+    fn wake(self: Arc<Self>) {
+        // Locking orders this with the service checking the playback before
+        // waiting.
+        let _state = self.0.state.lock().unwrap();
+        self.0.changed.notify_all();
+    }
+}
+
+/// Arbitrates the vibrator between concurrent users. The most important
+/// request plays, the newest one winning among equals, and the others wait
+/// or are dropped according to their `InterruptPolicy`.
+///
+/// The vibrator should only be driven through the service once it's
+/// created.
+pub struct VibratorService {
+    shared: Arc<Shared>,
+}
+
+impl VibratorService {
+    /// Creates a service driving this vibrator from its own thread.
+    pub fn new(vibrator: Vibrator) -> Self {
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                pending: vec![],
+                current: None,
+                next_id: 0,
+                shutdown: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let thread_shared = shared.clone();
+        thread::Builder::new()
+            .name("vibrator service".to_owned())
+            .spawn(move || VibratorService::run(&vibrator, &thread_shared))
+            .expect("Failed to start vibrator service thread!");
+        VibratorService { shared }
+    }
+
+    /// Queues a request, which starts right away if nothing more important
+    /// is playing.
+    pub fn vibrate(&self, mut request: VibrationRequest) -> VibrationGuard {
+        let mut state = self.shared.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        request.repeat = repeat_index(&request.steps, request.repeat);
+        let finished = Arc::new(Completion::default());
+        state.pending.push(Pending {
+            id,
+            request,
+            position: Arc::new(AtomicUsize::new(0)),
+            played: Duration::from_secs(0),
+            finished: finished.clone(),
+        });
+        self.shared.changed.notify_all();
+        VibrationGuard {
+            id,
+            shared: self.shared.clone(),
+            finished,
+        }
+    }
+
+    // The requests play through the vibrator scheduler, which is only
+    // called without the state locked since it wakes this thread up with
+    // its own lock held.
+    fn run(vibrator: &Vibrator, shared: &Arc<Shared>) {
+        let backend = vibrator_backend(vibrator);
+        let waker = Waker::from(Arc::new(PlaybackWaker(shared.clone())));
+        let mut state = shared.state.lock().unwrap();
+        loop {
+            // Newer requests win among equals, since ids only grow.
+            let top = state
+                .pending
+                .iter()
+                .max_by_key(|pending| (pending.request.priority, pending.id))
+                .map(|pending| pending.id)
+                .filter(|_| !state.shutdown);
+
+            if let Some(playing) = state.current.take() {
+                if playing.finished.outcome().is_some() {
+                    Shared::remove(&mut state, playing.id, PatternOutcome::Completed);
+                    continue;
+                }
+                if top == Some(playing.id) {
+                    state.current = Some(playing);
+                    state = shared.changed.wait(state).unwrap();
+                    continue;
+                }
+                // Interrupted, either by a more important request or by its
+                // own cancellation.
+                let now = Instant::now();
+                let dropped = match state.pending.iter_mut().find(|p| p.id == playing.id) {
+                    Some(pending) => {
+                        pending.played += now - playing.started;
+                        pending.request.policy == InterruptPolicy::Drop
+                    }
+                    None => false,
+                };
+                if dropped {
+                    Shared::remove(&mut state, playing.id, PatternOutcome::Canceled);
+                }
+                drop(state);
+                vibrator_scheduler::cancel(&playing.canceled, Some(&backend));
+                state = shared.state.lock().unwrap();
+                continue;
+            }
+
+            if state.shutdown {
+                for pending in state.pending.drain(..) {
+                    pending.finished.finish(PatternOutcome::Canceled);
+                }
+                return;
+            }
+            let top = match top {
+                Some(top) => top,
+                None => {
+                    state = shared.changed.wait(state).unwrap();
+                    continue;
+                }
+            };
+
+            let now = Instant::now();
+            let pending = state.pending.iter().find(|p| p.id == top).unwrap();
+            let left = match vibrator.max_pattern_duration().checked_sub(pending.played) {
+                Some(left) if left > Duration::from_secs(0) => left,
+                _ => {
+                    Shared::remove(&mut state, top, PatternOutcome::Completed);
+                    continue;
+                }
+            };
+            let playback = Playback {
+                backend: backend.clone(),
+                steps: pending.request.steps.clone(),
+                repeat: pending.request.repeat,
+                amplitude: pending.request.amplitude && vibrator.supports_amplitude(),
+                deadline: now.checked_add(left),
+                position: pending.position.clone(),
+                canceled: Arc::new(AtomicBool::new(false)),
+                finished: Arc::new(Completion::default()),
+            };
+            playback.finished.poll(&waker);
+            state.current = Some(Playing {
+                id: top,
+                started: now,
+                canceled: playback.canceled.clone(),
+                finished: playback.finished.clone(),
+            });
+            drop(state);
+            vibrator_scheduler::play(playback);
+            state = shared.state.lock().unwrap();
+        }
+    }
+}
+
+impl Drop for VibratorService {
+    /// Stops the service and turns the vibrator off.
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().shutdown = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+/// Controls one request of a `VibratorService`, without affecting the
+/// others.
+#[derive(Clone)]
+pub struct VibrationGuard {
+    id: usize,
+    shared: Arc<Shared>,
+    finished: Arc<Completion>,
+}
+
+impl VibrationGuard {
+    /// Cancels the request. If it was playing, the next most important
+    /// request takes over, or the vibrator is turned off.
+    pub fn cancel(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        Shared::remove(&mut state, self.id, PatternOutcome::Canceled);
+        self.shared.changed.notify_all();
+    }
+
+    /// Checks if the request is still playing or waiting to.
+    pub fn is_active(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.pending.iter().any(|pending| pending.id == self.id)
+    }
+
+    /// Checks if the request is the one driving the vibrator.
+    pub fn is_playing(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state
+            .current
+            .as_ref()
+            .is_some_and(|playing| playing.id == self.id)
+    }
+
+    /// Checks if the request ended, and how. Requests dropped when
+    /// interrupted, or still pending when the service stops, are canceled.
+    pub fn outcome(&self) -> Option<PatternOutcome> {
+        self.finished.outcome()
+    }
+
+    /// Blocks until the request ends.
+    pub fn wait(&self) -> PatternOutcome {
+        self.finished.wait()
+    }
+
+    /// Blocks until the request ends, or `timeout` elapsed. Returns `None`
+    /// if it's still playing or waiting to.
+    pub fn wait_timeout(&self, timeout: Duration) -> Option<PatternOutcome> {
+        self.finished.wait_timeout(timeout)
+    }
+
+    /// Blocks until the request ends, like `wait`, consuming the guard.
+    pub fn join(self) -> PatternOutcome {
+        self.wait()
+    }
+}
+
+#[cfg(feature = "async")]
+impl Future for VibrationGuard {
+    type Output = PatternOutcome;
+
+    /// Resolves once the request ends.
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<PatternOutcome> {
+        match self.finished.poll(cx.waker()) {
+            Some(outcome) => Poll::Ready(outcome),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use vibrator::VibratorBackend;
+
+    struct FakeBackend;
+
+    impl VibratorBackend for FakeBackend {
+        fn on(&self, _duration: Duration) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn off(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn service(max_pattern_duration: Duration) -> VibratorService {
+        VibratorService::new(
+            Vibrator::with_backend(Arc::new(FakeBackend))
+                .with_max_pattern_duration(max_pattern_duration),
+        )
+    }
+
+    #[test]
+    fn interrupted_requests_are_dropped_or_resumed() {
+        let service = service(Duration::from_secs(10));
+        let dropped = service.vibrate(VibrationRequest::pattern(vec![1000], None));
+        while !dropped.is_playing() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let resumed = service.vibrate(
+            VibrationRequest::pattern(vec![100], None)
+                .with_priority(VibrationPriority::Call)
+                .with_policy(InterruptPolicy::Resume),
+        );
+        let alarm = service.vibrate(
+            VibrationRequest::pattern(vec![50], None).with_priority(VibrationPriority::Alarm),
+        );
+
+        assert_eq!(alarm.wait(), PatternOutcome::Completed);
+        assert_eq!(dropped.outcome(), Some(PatternOutcome::Canceled));
+        assert!(!dropped.is_active());
+        assert_eq!(
+            resumed.wait_timeout(Duration::from_secs(1)),
+            Some(PatternOutcome::Completed)
+        );
+    }
+
+    #[test]
+    fn resumed_requests_keep_their_play_time() {
+        let start = Instant::now();
+        let service = service(Duration::from_millis(150));
+        let resumed = service.vibrate(
+            VibrationRequest::pattern(vec![120], None).with_policy(InterruptPolicy::Resume),
+        );
+        thread::sleep(Duration::from_millis(60));
+        assert!(resumed.is_playing());
+        let call = service.vibrate(
+            VibrationRequest::pattern(vec![100], None).with_priority(VibrationPriority::Call),
+        );
+        assert_eq!(call.join(), PatternOutcome::Completed);
+
+        // The pause doesn't count towards the maximum pattern duration.
+        assert_eq!(resumed.join(), PatternOutcome::Completed);
+        assert!(start.elapsed() >= Duration::from_millis(220));
+    }
+
+    #[test]
+    fn canceled_and_pending_requests_end_canceled() {
+        let service = service(Duration::from_secs(10));
+        let mut canceled = service.vibrate(VibrationRequest::pattern(vec![1000], None));
+        let waiting = service.vibrate(
+            VibrationRequest::pattern(vec![1000], None)
+                .with_priority(VibrationPriority::TouchFeedback)
+                .with_policy(InterruptPolicy::Resume),
+        );
+        canceled.cancel();
+        assert_eq!(canceled.outcome(), Some(PatternOutcome::Canceled));
+        assert_eq!(
+            waiting.wait_timeout(Duration::from_millis(20)),
+            None,
+            "The waiting request plays until the service stops"
+        );
+        drop(service);
+        assert_eq!(waiting.wait(), PatternOutcome::Canceled);
+    }
+}
@@ -2,9 +2,23 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::backtrace::Backtrace;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+#[cfg(feature = "hardware_legacy")]
 use std::ffi::CString;
+use std::fmt;
+use std::io;
+#[cfg(feature = "hardware_legacy")]
 use std::os::raw;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use sysfs::write_file;
 
+#[cfg(feature = "hardware_legacy")]
 #[link(name = "hardware_legacy")]
 extern "C" {
     pub fn acquire_wake_lock(lock: raw::c_int, id: *const raw::c_char) -> raw::c_int;
@@ -13,6 +27,7 @@ extern "C" {
 }
 
 /// The two kind of wake locks supported.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum WakelockLevel {
     /// The cpu stays on, but the screen is off.
     Partial = 1,
@@ -20,29 +35,736 @@ pub enum WakelockLevel {
     Full = 2,
 }
 
+/// The way wake locks are taken and released.
+pub trait WakelockBackend: Send + Sync {
+    /// Acquires the lock with this name, or updates it if it's already
+    /// held. With a timeout, the lock releases itself once it expires.
+    fn acquire(
+        &self,
+        name: &str,
+        level: WakelockLevel,
+        timeout: Option<Duration>,
+    ) -> io::Result<()>;
+
+    /// Releases the lock with this name.
+    fn release(&self, name: &str) -> io::Result<()>;
+
+    /// Checks if `acquire` supports timeouts.
+    fn supports_timeout(&self) -> bool;
+}
+
+#[cfg(feature = "hardware_legacy")]
+fn lock_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Malformed lock name: {}", name),
+        )
+    })
+}
+
+/// The backend using libhardware_legacy. It doesn't support timeouts.
+#[cfg(feature = "hardware_legacy")]
+pub struct LegacyWakelocks;
+
+#[cfg(feature = "hardware_legacy")]
+impl WakelockBackend for LegacyWakelocks {
+    fn acquire(
+        &self,
+        name: &str,
+        level: WakelockLevel,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        if timeout.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Wake lock timeouts are not supported by libhardware_legacy",
+            ));
+        }
+        let id = lock_name(name)?;
+        // Returns the number of bytes written to sysfs, or -1 and errno.
+        match unsafe { acquire_wake_lock(level as raw::c_int, id.as_ptr()) } {
+            res if res < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    fn release(&self, name: &str) -> io::Result<()> {
+        let id = lock_name(name)?;
+        match unsafe { release_wake_lock(id.as_ptr()) } {
+            res if res < 0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    fn supports_timeout(&self) -> bool {
+        false
+    }
+}
+
+/// The backend writing directly to the kernel's `/sys/power/wake_lock` and
+/// `/sys/power/wake_unlock` files. The kernel doesn't distinguish levels.
+pub struct SysfsWakelocks {
+    root: PathBuf,
+}
+
+impl SysfsWakelocks {
+    /// Creates a backend using `/sys`.
+    pub fn new() -> Self {
+        SysfsWakelocks::with_root("/sys")
+    }
+
+    /// Creates a backend using another sysfs root, for instance a temporary
+    /// directory with a `power` subdirectory.
+    pub fn with_root<P: AsRef<Path>>(root: P) -> Self {
+        SysfsWakelocks {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn write(&self, file: &str, value: &str) -> io::Result<()> {
+        write_file(&self.root.join("power").join(file), value)
+    }
+}
+
+impl Default for SysfsWakelocks {
+    fn default() -> Self {
+        SysfsWakelocks::new()
+    }
+}
+
+impl WakelockBackend for SysfsWakelocks {
+    fn acquire(
+        &self,
+        name: &str,
+        _level: WakelockLevel,
+        timeout: Option<Duration>,
+    ) -> io::Result<()> {
+        // The kernel splits the request on whitespace.
+        if name.is_empty() || name.contains(char::is_whitespace) || name.contains('\0') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Malformed lock name: {}", name),
+            ));
+        }
+        match timeout {
+            Some(timeout) => {
+                let nanos = timeout.as_nanos().min(u64::MAX as u128) as u64;
+                // A zero timeout would mean no timeout at all.
+                self.write("wake_lock", &format!("{} {}", name, nanos.max(1)))
+            }
+            None => self.write("wake_lock", name),
+        }
+    }
+
+    fn release(&self, name: &str) -> io::Result<()> {
+        self.write("wake_unlock", name)
+    }
+
+    fn supports_timeout(&self) -> bool {
+        true
+    }
+}
+
+fn default_backend_slot() -> &'static Mutex<Arc<dyn WakelockBackend>> {
+    static DEFAULT_BACKEND: OnceLock<Mutex<Arc<dyn WakelockBackend>>> = OnceLock::new();
+    DEFAULT_BACKEND.get_or_init(|| {
+        #[cfg(feature = "hardware_legacy")]
+        let backend: Arc<dyn WakelockBackend> = Arc::new(LegacyWakelocks);
+        #[cfg(not(feature = "hardware_legacy"))]
+        let backend: Arc<dyn WakelockBackend> = Arc::new(SysfsWakelocks::new());
+        Mutex::new(backend)
+    })
+}
+
+/// Sets the backend used by `Wakelock::new` and `Wakelock::with_timeout`,
+/// for instance `SysfsWakelocks` on devices without libhardware_legacy.
+/// Defaults to `LegacyWakelocks` with the `hardware_legacy` feature, and to
+/// `SysfsWakelocks` otherwise. Locks already held keep their backend.
+pub fn set_default_wakelock_backend(backend: Arc<dyn WakelockBackend>) {
+    *default_backend_slot().lock().unwrap() = backend;
+}
+
+fn default_backend() -> Arc<dyn WakelockBackend> {
+    default_backend_slot().lock().unwrap().clone()
+}
+
+static TRACING: AtomicBool = AtomicBool::new(false);
+// The running watchdogs, which need the traces to report leaks.
+static WATCHDOGS: AtomicUsize = AtomicUsize::new(0);
+
+/// Enables or disables recording where wake locks are acquired. Capturing
+/// backtraces is slow, so this is disabled by default, but it's enabled
+/// while a `WakelockWatchdog` runs. Only affects locks acquired afterwards.
+pub fn set_wakelock_tracing(enabled: bool) {
+    TRACING.store(enabled, Ordering::Relaxed);
+}
+
+/// Checks if wake lock acquisitions are being traced.
+pub fn wakelock_tracing() -> bool {
+    TRACING.load(Ordering::Relaxed) || WATCHDOGS.load(Ordering::Relaxed) > 0
+}
+
+/// Where a holder was acquired, when tracing.
+struct Trace {
+    thread: String,
+    backtrace: Backtrace,
+}
+
+impl Trace {
+    fn capture() -> Option<Trace> {
+        if !wakelock_tracing() {
+            return None;
+        }
+        let current = thread::current();
+        Some(Trace {
+            thread: match current.name() {
+                Some(name) => name.to_owned(),
+                None => format!("{:?}", current.id()),
+            },
+            backtrace: Backtrace::force_capture(),
+        })
+    }
+}
+
+struct Holder {
+    level: WakelockLevel,
+    // When this holder expires, if it's timed.
+    deadline: Option<Instant>,
+    timer_running: bool,
+    acquired_at: Instant,
+    trace: Option<Trace>,
+}
+
+impl Holder {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.deadline.is_some_and(|deadline| now >= deadline)
+    }
+}
+
+/// All the holders of a kernel wake lock.
+struct Entry {
+    backend: Arc<dyn WakelockBackend>,
+    holders: HashMap<usize, Holder>,
+}
+
+impl Entry {
+    /// Updates the kernel lock to match the live holders. Returns false
+    /// once there are none left and the lock got released.
+    fn sync(&mut self, name: &str) -> io::Result<bool> {
+        let now = Instant::now();
+        self.holders.retain(|_, holder| !holder.is_expired(now));
+
+        if self.holders.is_empty() {
+            self.backend.release(name)?;
+            return Ok(false);
+        }
+
+        let level = if self
+            .holders
+            .values()
+            .any(|holder| holder.level == WakelockLevel::Full)
+        {
+            WakelockLevel::Full
+        } else {
+            WakelockLevel::Partial
+        };
+        // The kernel lock lasts as long as the longest holder, if they are
+        // all timed and the backend can do it. Timer threads take care of
+        // the other cases.
+        let timeout = if self.backend.supports_timeout() {
+            self.holders
+                .values()
+                .map(|holder| holder.deadline)
+                .try_fold(now, |longest, deadline| {
+                    deadline.map(|deadline| longest.max(deadline))
+                })
+                .map(|deadline| deadline - now)
+        } else {
+            None
+        };
+        self.backend.acquire(name, level, timeout)?;
+        Ok(true)
+    }
+}
+
+/// The process wide state of the wake locks, since the kernel namespace is
+/// global: two `Wakelock`s with the same name share the kernel lock, which is
+/// only released once both are.
+struct Registry {
+    entries: Mutex<HashMap<String, Entry>>,
+    changed: Condvar,
+    next_id: AtomicUsize,
+}
+
+fn registry() -> &'static Registry {
+    static REGISTRY: OnceLock<Registry> = OnceLock::new();
+    REGISTRY.get_or_init(|| Registry {
+        entries: Mutex::new(HashMap::new()),
+        changed: Condvar::new(),
+        next_id: AtomicUsize::new(0),
+    })
+}
+
+impl Registry {
+    fn add(
+        &self,
+        name: &str,
+        level: WakelockLevel,
+        backend: Arc<dyn WakelockBackend>,
+        deadline: Option<Instant>,
+    ) -> Option<usize> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        // Backtraces are slow, don't hold every other lock user meanwhile.
+        let trace = Trace::capture();
+        let mut entries = self.entries.lock().unwrap();
+        let needs_timer = {
+            // The backend of the first holder is used for the whole entry.
+            let entry = entries.entry(name.to_owned()).or_insert_with(|| Entry {
+                backend,
+                holders: HashMap::new(),
+            });
+            entry.holders.insert(
+                id,
+                Holder {
+                    level,
+                    deadline,
+                    timer_running: false,
+                    acquired_at: Instant::now(),
+                    trace,
+                },
+            );
+            if entry.sync(name).is_err() {
+                entry.holders.remove(&id);
+                // Restore the state the other holders expect.
+                if !entry.holders.is_empty() {
+                    let _ = entry.sync(name);
+                }
+                None
+            } else {
+                Some(deadline.is_some() && !entry.backend.supports_timeout())
+            }
+        };
+
+        match needs_timer {
+            Some(needs_timer) => {
+                if needs_timer {
+                    self.start_timer(&mut entries, name, id);
+                }
+                Some(id)
+            }
+            None => {
+                if entries
+                    .get(name)
+                    .is_some_and(|entry| entry.holders.is_empty())
+                {
+                    entries.remove(name);
+                }
+                None
+            }
+        }
+    }
+
+    fn remove(&self, name: &str, id: usize) {
+        let mut entries = self.entries.lock().unwrap();
+        self.remove_locked(&mut entries, name, id);
+    }
+
+    fn remove_locked(&self, entries: &mut HashMap<String, Entry>, name: &str, id: usize) {
+        let empty = match entries.get_mut(name) {
+            Some(entry) => {
+                if entry.holders.remove(&id).is_none() {
+                    return;
+                }
+                // Nothing more we can do if the kernel refuses.
+                !entry.sync(name).unwrap_or(false)
+            }
+            None => return,
+        };
+        if empty {
+            entries.remove(name);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Forgets the holders that expired on their own, which only happens
+    /// with backends supporting timeouts since timer threads remove the
+    /// others.
+    fn prune(entries: &mut HashMap<String, Entry>) {
+        let now = Instant::now();
+        entries.retain(|_, entry| {
+            if entry.backend.supports_timeout() {
+                // The kernel lock expires with the longest holder.
+                entry.holders.retain(|_, holder| !holder.is_expired(now));
+            }
+            !entry.holders.is_empty()
+        });
+    }
+
+    fn holder<'a>(
+        entries: &'a mut HashMap<String, Entry>,
+        name: &str,
+        id: usize,
+    ) -> Option<&'a mut Holder> {
+        entries
+            .get_mut(name)
+            .and_then(|entry| entry.holders.get_mut(&id))
+    }
+
+    /// Releases a holder when its deadline passes, for backends without
+    /// timeout support. Exits once the holder is gone.
+    fn start_timer(&self, entries: &mut HashMap<String, Entry>, name: &str, id: usize) {
+        match Registry::holder(entries, name, id) {
+            Some(ref mut holder) if !holder.timer_running => holder.timer_running = true,
+            _ => {
+                self.changed.notify_all();
+                return;
+            }
+        }
+
+        let timer_name = name.to_owned();
+        let spawned = thread::Builder::new()
+            .name("wakelock timer".to_owned())
+            .spawn(move || {
+                let registry = registry();
+                let mut entries = registry.entries.lock().unwrap();
+                loop {
+                    let deadline = match Registry::holder(&mut entries, &timer_name, id) {
+                        Some(holder) => holder.deadline,
+                        None => return,
+                    };
+                    match deadline {
+                        Some(deadline) => {
+                            let now = Instant::now();
+                            if now >= deadline {
+                                registry.remove_locked(&mut entries, &timer_name, id);
+                                return;
+                            }
+                            entries = registry
+                                .changed
+                                .wait_timeout(entries, deadline - now)
+                                .unwrap()
+                                .0;
+                        }
+                        None => entries = registry.changed.wait(entries).unwrap(),
+                    }
+                }
+            });
+        if spawned.is_err() {
+            if let Some(holder) = Registry::holder(entries, name, id) {
+                holder.timer_running = false;
+            }
+        }
+    }
+}
+
+/// A live holder of a wake lock, as reported by `dump_held_locks`.
+#[derive(Clone, Debug)]
+pub struct HeldLock {
+    pub name: String,
+    pub level: WakelockLevel,
+    /// How long ago it was acquired.
+    pub held_for: Duration,
+    /// The thread that acquired it, if tracing was enabled then.
+    pub thread: Option<String>,
+    /// Where it was acquired, if tracing was enabled then.
+    pub backtrace: Option<String>,
+}
+
+impl fmt::Display for HeldLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Wake lock {} ({:?}) held for {:?}",
+            self.name, self.level, self.held_for
+        )?;
+        if let Some(ref thread) = self.thread {
+            write!(f, " by thread {}", thread)?;
+        }
+        if let Some(ref backtrace) = self.backtrace {
+            write!(f, ", acquired at:\n{}", backtrace)?;
+        }
+        Ok(())
+    }
+}
+
+fn held_locks(filter: &mut dyn FnMut(usize, &Holder) -> bool) -> Vec<HeldLock> {
+    let now = Instant::now();
+    let mut entries = registry().entries.lock().unwrap();
+    Registry::prune(&mut entries);
+    let mut locks: Vec<HeldLock> = entries
+        .iter()
+        .flat_map(|(name, entry)| {
+            entry
+                .holders
+                .iter()
+                .map(move |(id, holder)| (name, *id, holder))
+        })
+        .filter(|&(_, id, holder)| !holder.is_expired(now) && filter(id, holder))
+        .map(|(name, _, holder)| HeldLock {
+            name: name.clone(),
+            level: holder.level,
+            held_for: now - holder.acquired_at,
+            thread: holder.trace.as_ref().map(|trace| trace.thread.clone()),
+            backtrace: holder
+                .trace
+                .as_ref()
+                .map(|trace| trace.backtrace.to_string()),
+        })
+        .collect();
+    locks.sort_by_key(|lock| Reverse(lock.held_for));
+    locks
+}
+
+/// Lists the wake locks held in this process, longest held first. The
+/// acquisition sites are only known for locks taken while tracing.
+pub fn dump_held_locks() -> Vec<HeldLock> {
+    held_locks(&mut |_, _| true)
+}
+
+/// How often watchdogs check the locks at most.
+const MIN_WATCHDOG_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A thread reporting the wake locks held longer than a threshold, which are
+/// likely leaked. Each holder is reported once. The thread stops when the
+/// watchdog is dropped.
+///
+/// Acquisitions are traced while a watchdog runs, see
+/// `set_wakelock_tracing`.
+pub struct WakelockWatchdog {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WakelockWatchdog {
+    /// Starts a watchdog writing the suspicious locks to stderr.
+    pub fn start(threshold: Duration) -> io::Result<WakelockWatchdog> {
+        WakelockWatchdog::with_reporter(threshold, threshold / 2, |lock| eprintln!("{}", lock))
+    }
+
+    /// Starts a watchdog checking the locks every `interval`, but not more
+    /// often than every 100ms, and calling `report` for the ones held longer
+    /// than `threshold`.
+    pub fn with_reporter<F>(
+        threshold: Duration,
+        interval: Duration,
+        mut report: F,
+    ) -> io::Result<WakelockWatchdog>
+    where
+        F: FnMut(&HeldLock) + Send + 'static,
+    {
+        let interval = interval.max(MIN_WATCHDOG_INTERVAL);
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let thread_stop = stop.clone();
+        WATCHDOGS.fetch_add(1, Ordering::Relaxed);
+        let thread = thread::Builder::new()
+            .name("wakelock watchdog".to_owned())
+            .spawn(move || {
+                let mut reported = HashSet::new();
+                let (stopped, wake) = &*thread_stop;
+                loop {
+                    {
+                        let stopped = stopped.lock().unwrap();
+                        if *wake.wait_timeout(stopped, interval).unwrap().0 {
+                            return;
+                        }
+                    }
+                    let mut seen = HashSet::new();
+                    let locks = held_locks(&mut |id, holder| {
+                        seen.insert(id);
+                        holder.acquired_at.elapsed() >= threshold && reported.insert(id)
+                    });
+                    // Forget the holders that are gone.
+                    reported.retain(|id| seen.contains(id));
+                    // Reporters may take their time, or drop the watchdog.
+                    for lock in &locks {
+                        report(lock);
+                    }
+                }
+            });
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(err) => {
+                WATCHDOGS.fetch_sub(1, Ordering::Relaxed);
+                return Err(err);
+            }
+        };
+        Ok(WakelockWatchdog {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for WakelockWatchdog {
+    fn drop(&mut self) {
+        *self.stop.0.lock().unwrap() = true;
+        self.stop.1.notify_all();
+        if let Some(thread) = self.thread.take() {
+            // The reporter may be the one dropping us.
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+        WATCHDOGS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
 /// A Wakelock that can be manually released, or that will
-/// release itself when dropped.
+/// release itself when dropped or when its timeout expires.
+///
+/// Locks with the same name are reference counted: the kernel lock is held
+/// as long as one of them is. Cloning a lock adds a holder with the same
+/// level and expiry.
 pub struct Wakelock {
     name: String,
+    id: usize,
 }
 
 impl Wakelock {
-    /// Creates a new Wakelock with the given name and level.
+    /// Creates a new Wakelock with the given name and level, using the
+    /// default backend, see `set_default_wakelock_backend`.
     pub fn new(name: &str, level: WakelockLevel) -> Option<Wakelock> {
-        let id = CString::new(name).expect(&format!("Malformed lock name: {}", name));
-        let res = unsafe { acquire_wake_lock(level as raw::c_int, id.as_ptr()) };
-        if res == 0 {
-            return Some(Wakelock { name: name.to_owned() });
+        Wakelock::acquire(name, level, default_backend(), None)
+    }
+
+    /// Creates a new Wakelock that releases itself after `timeout`, using
+    /// the default backend.
+    pub fn with_timeout(name: &str, level: WakelockLevel, timeout: Duration) -> Option<Wakelock> {
+        Wakelock::acquire(name, level, default_backend(), Some(timeout))
+    }
+
+    /// Creates a new Wakelock with the given name and level, using a
+    /// specific backend. If the lock is already held, the backend of its
+    /// first holder keeps being used.
+    pub fn with_backend(
+        name: &str,
+        level: WakelockLevel,
+        backend: Arc<dyn WakelockBackend>,
+    ) -> Option<Wakelock> {
+        Wakelock::acquire(name, level, backend, None)
+    }
+
+    /// Creates a new Wakelock that releases itself after `timeout`, using a
+    /// specific backend. The kernel takes care of the timeout when the
+    /// backend supports it, otherwise a timer thread does.
+    pub fn with_backend_and_timeout(
+        name: &str,
+        level: WakelockLevel,
+        backend: Arc<dyn WakelockBackend>,
+        timeout: Duration,
+    ) -> Option<Wakelock> {
+        Wakelock::acquire(name, level, backend, Some(timeout))
+    }
+
+    fn acquire(
+        name: &str,
+        level: WakelockLevel,
+        backend: Arc<dyn WakelockBackend>,
+        timeout: Option<Duration>,
+    ) -> Option<Wakelock> {
+        // A timeout too long to be represented never expires.
+        let deadline = timeout.and_then(|timeout| Instant::now().checked_add(timeout));
+        registry()
+            .add(name, level, backend, deadline)
+            .map(|id| Wakelock {
+                name: name.to_owned(),
+                id,
+            })
+    }
+
+    /// Returns the number of live holders of the lock with this name in
+    /// this process. Useful for debugging.
+    pub fn held_count(name: &str) -> usize {
+        let now = Instant::now();
+        let mut entries = registry().entries.lock().unwrap();
+        Registry::prune(&mut entries);
+        entries.get(name).map_or(0, |entry| {
+            entry
+                .holders
+                .values()
+                .filter(|holder| !holder.is_expired(now))
+                .count()
+        })
+    }
+
+    /// The name of this lock.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks if this lock is still held, ie. it was neither released nor
+    /// expired.
+    pub fn is_held(&self) -> bool {
+        let mut entries = registry().entries.lock().unwrap();
+        Registry::prune(&mut entries);
+        Registry::holder(&mut entries, &self.name, self.id)
+            .is_some_and(|holder| !holder.is_expired(Instant::now()))
+    }
+
+    /// Makes this lock expire `timeout` from now, whether it was timed or
+    /// not, or never if `timeout` is too long to be represented. Returns
+    /// false if the lock is no longer held.
+    pub fn extend(&self, timeout: Duration) -> bool {
+        let registry = registry();
+        let mut entries = registry.entries.lock().unwrap();
+        let now = Instant::now();
+        let previous = match Registry::holder(&mut entries, &self.name, self.id) {
+            Some(ref mut holder) if !holder.is_expired(now) => {
+                let previous = holder.deadline;
+                holder.deadline = now.checked_add(timeout);
+                previous
+            }
+            _ => return false,
+        };
+
+        let (synced, needs_timer) = {
+            let entry = entries.get_mut(&self.name).unwrap();
+            (
+                entry.sync(&self.name).is_ok(),
+                !entry.backend.supports_timeout(),
+            )
+        };
+        if !synced {
+            if let Some(holder) = Registry::holder(&mut entries, &self.name, self.id) {
+                holder.deadline = previous;
+            }
+            return false;
         }
-        None
+        if needs_timer {
+            registry.start_timer(&mut entries, &self.name, self.id);
+        }
+        true
     }
 
-    /// Release the Wakelock.
+    /// Release the Wakelock. Releasing it again, or dropping it afterwards,
+    /// does nothing.
     pub fn release(&self) {
-        let id = CString::new(self.name.clone())
-            .expect(&format!("Malformed lock name: {}", self.name));
-        unsafe {
-            release_wake_lock(id.as_ptr());
+        registry().remove(&self.name, self.id);
+    }
+}
+
+impl Clone for Wakelock {
+    /// Adds a holder to this lock, with the same level and expiry. The clone
+    /// doesn't hold anything if this lock was released, and releasing it
+    /// doesn't affect the other holders.
+    fn clone(&self) -> Self {
+        let registry = registry();
+        let (level, deadline, backend) = {
+            let mut entries = registry.entries.lock().unwrap();
+            let backend = entries.get(&self.name).map(|entry| entry.backend.clone());
+            match (Registry::holder(&mut entries, &self.name, self.id), backend) {
+                (Some(holder), Some(backend)) => (holder.level, holder.deadline, Some(backend)),
+                _ => (WakelockLevel::Partial, None, None),
+            }
+        };
+        let id = backend
+            .and_then(|backend| registry.add(&self.name, level, backend, deadline))
+            // An id nobody holds.
+            .unwrap_or_else(|| registry.next_id.fetch_add(1, Ordering::Relaxed));
+        Wakelock {
+            name: self.name.clone(),
+            id,
         }
     }
 }
@@ -53,3 +775,202 @@ impl Drop for Wakelock {
         self.release();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    /// A sysfs backend writing to a temporary directory.
+    fn sysfs(name: &str) -> (PathBuf, Arc<dyn WakelockBackend>) {
+        let root = env::temp_dir().join(format!("gonkhal-{}-{}", name, process::id()));
+        fs::create_dir_all(root.join("power")).unwrap();
+        for file in &["wake_lock", "wake_unlock"] {
+            fs::write(root.join("power").join(file), "").unwrap();
+        }
+        (root.clone(), Arc::new(SysfsWakelocks::with_root(root)))
+    }
+
+    /// A backend logging the kernel requests.
+    #[derive(Default)]
+    struct FakeBackend {
+        log: Mutex<Vec<String>>,
+    }
+
+    impl WakelockBackend for FakeBackend {
+        fn acquire(
+            &self,
+            name: &str,
+            level: WakelockLevel,
+            _timeout: Option<Duration>,
+        ) -> io::Result<()> {
+            self.log
+                .lock()
+                .unwrap()
+                .push(format!("acquire {} {:?}", name, level));
+            Ok(())
+        }
+
+        fn release(&self, name: &str) -> io::Result<()> {
+            self.log.lock().unwrap().push(format!("release {}", name));
+            Ok(())
+        }
+
+        fn supports_timeout(&self) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn shared_names_release_on_last_drop() {
+        let backend = Arc::new(FakeBackend::default());
+        let first =
+            Wakelock::with_backend("shared", WakelockLevel::Partial, backend.clone()).unwrap();
+        let second =
+            Wakelock::with_backend("shared", WakelockLevel::Full, backend.clone()).unwrap();
+        assert_eq!(Wakelock::held_count("shared"), 2);
+
+        drop(first);
+        assert!(second.is_held());
+        assert_eq!(Wakelock::held_count("shared"), 1);
+        assert_eq!(
+            *backend.log.lock().unwrap(),
+            [
+                "acquire shared Partial",
+                "acquire shared Full",
+                "acquire shared Full"
+            ]
+        );
+
+        drop(second);
+        assert_eq!(Wakelock::held_count("shared"), 0);
+        let log = backend.log.lock().unwrap();
+        assert_eq!(log.len(), 4);
+        assert_eq!(log[3], "release shared");
+    }
+
+    #[test]
+    fn sysfs_writes() {
+        let (root, _) = sysfs("sysfs-writes");
+        let backend = SysfsWakelocks::with_root(&root);
+        let read = |file: &str| fs::read_to_string(root.join("power").join(file)).unwrap();
+
+        backend
+            .acquire("untimed", WakelockLevel::Partial, None)
+            .unwrap();
+        assert_eq!(read("wake_lock"), "untimed");
+        backend
+            .acquire(
+                "timed",
+                WakelockLevel::Partial,
+                Some(Duration::from_millis(1500)),
+            )
+            .unwrap();
+        assert_eq!(read("wake_lock"), "timed 1500000000");
+        backend.release("timed").unwrap();
+        assert_eq!(read("wake_unlock"), "timed");
+
+        let err = backend
+            .acquire("two words", WakelockLevel::Partial, None)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(read("wake_lock"), "timed 1500000000");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn huge_timeouts_never_expire() {
+        let (root, backend) = sysfs("huge");
+        let lock = Wakelock::with_backend_and_timeout(
+            "huge",
+            WakelockLevel::Partial,
+            backend,
+            Duration::MAX,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("power/wake_lock")).unwrap(),
+            "huge"
+        );
+        assert!(lock.extend(Duration::MAX));
+        assert!(lock.is_held());
+        drop(lock);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn expired_locks_are_pruned() {
+        let (root, backend) = sysfs("prune");
+        let lock = Wakelock::with_backend_and_timeout(
+            "prune",
+            WakelockLevel::Partial,
+            backend,
+            Duration::from_millis(10),
+        )
+        .unwrap();
+        assert_eq!(Wakelock::held_count("prune"), 1);
+        thread::sleep(Duration::from_millis(20));
+
+        // The kernel released it, nothing else has to.
+        assert!(!lock.is_held());
+        assert!(!registry().entries.lock().unwrap().contains_key("prune"));
+        assert!(dump_held_locks().iter().all(|held| held.name != "prune"));
+        drop(lock);
+        assert_eq!(
+            fs::read_to_string(root.join("power/wake_unlock")).unwrap(),
+            ""
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn clones_of_released_locks_are_inert() {
+        let (root, backend) = sysfs("inert");
+        let released =
+            Wakelock::with_backend("inert", WakelockLevel::Partial, backend.clone()).unwrap();
+        released.release();
+        let (first, second) = (released.clone(), released.clone());
+        assert!(first.id != second.id);
+
+        let held = Wakelock::with_backend("inert", WakelockLevel::Partial, backend).unwrap();
+        assert!(!first.is_held());
+        drop(first);
+        drop(second);
+        assert!(held.is_held());
+        assert_eq!(Wakelock::held_count("inert"), 1);
+        drop(held);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn watchdog_reports_traced_leaks() {
+        use std::sync::mpsc::channel;
+
+        let (root, backend) = sysfs("watchdog");
+        let (sender, receiver) = channel();
+        let watchdog = WakelockWatchdog::with_reporter(
+            Duration::from_millis(0),
+            Duration::from_millis(0),
+            move |lock| {
+                if lock.name == "watchdog" {
+                    let _ = sender.send(lock.clone());
+                }
+            },
+        )
+        .unwrap();
+        assert!(wakelock_tracing());
+
+        let lock = Wakelock::with_backend("watchdog", WakelockLevel::Full, backend).unwrap();
+        let leak = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(leak.level, WakelockLevel::Full);
+        assert!(leak.thread.is_some() && leak.backtrace.is_some());
+        // Each holder is only reported once.
+        assert!(receiver.recv_timeout(Duration::from_millis(250)).is_err());
+
+        drop(watchdog);
+        drop(lock);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,365 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use std::time::Duration;
+
+/// Where a wake lock record comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StatsSource {
+    /// `/sys/kernel/debug/wakeup_sources`, with times in milliseconds.
+    WakeupSources,
+    /// The legacy Android `/proc/wakelocks`, with times in nanoseconds.
+    ProcWakelocks,
+}
+
+/// The statistics of one wake lock or wakeup source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WakelockStats {
+    pub name: String,
+    /// How many times the lock was acquired.
+    pub active_count: u64,
+    /// How many times it was signaled. Same as `active_count` for
+    /// `/proc/wakelocks`.
+    pub event_count: u64,
+    /// How many times it aborted a suspend.
+    pub wakeup_count: u64,
+    /// How many times it timed out.
+    pub expire_count: u64,
+    /// How long it has been active, zero if it's not.
+    pub active_since: Duration,
+    pub total_time: Duration,
+    pub max_time: Duration,
+    /// When it last changed state, since boot.
+    pub last_change: Duration,
+    /// How long it prevented the system from suspending. This is the
+    /// `sleep_time` column of `/proc/wakelocks`.
+    pub prevent_suspend_time: Duration,
+    pub source: StatsSource,
+}
+
+impl WakelockStats {
+    /// Checks if this lock was held when the statistics were read.
+    pub fn is_active(&self) -> bool {
+        self.active_since > Duration::from_secs(0)
+    }
+}
+
+fn invalid(line: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed wake lock statistics: {}", line),
+    )
+}
+
+/// Splits a table row into its name and its `count` trailing numbers. Names
+/// are parsed from the left over part so they can contain whitespace.
+fn split_row(line: &str, count: usize) -> io::Result<(String, Vec<u64>)> {
+    let mut fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < count + 1 {
+        return Err(invalid(line));
+    }
+    let values = fields
+        .split_off(fields.len() - count)
+        .iter()
+        .map(|value| value.parse::<u64>().map_err(|_| invalid(line)))
+        .collect::<io::Result<Vec<u64>>>()?;
+    let name = fields.join(" ").trim_matches('"').to_owned();
+    Ok((name, values))
+}
+
+fn is_header(line: &str) -> bool {
+    line.trim().is_empty() || line.starts_with("name\t")
+}
+
+/// Parses the content of `/sys/kernel/debug/wakeup_sources`.
+pub fn parse_wakeup_sources(content: &str) -> io::Result<Vec<WakelockStats>> {
+    let ms = Duration::from_millis;
+    content
+        .lines()
+        .filter(|line| !is_header(line))
+        .map(|line| {
+            let (name, v) = split_row(line, 9)?;
+            Ok(WakelockStats {
+                name,
+                active_count: v[0],
+                event_count: v[1],
+                wakeup_count: v[2],
+                expire_count: v[3],
+                active_since: ms(v[4]),
+                total_time: ms(v[5]),
+                max_time: ms(v[6]),
+                last_change: ms(v[7]),
+                prevent_suspend_time: ms(v[8]),
+                source: StatsSource::WakeupSources,
+            })
+        })
+        .collect()
+}
+
+/// Parses the content of `/proc/wakelocks`.
+pub fn parse_proc_wakelocks(content: &str) -> io::Result<Vec<WakelockStats>> {
+    let ns = Duration::from_nanos;
+    content
+        .lines()
+        .filter(|line| !is_header(line))
+        .map(|line| {
+            // count, expire_count, wake_count, active_since, total_time,
+            // sleep_time, max_time, last_change
+            let (name, v) = split_row(line, 8)?;
+            Ok(WakelockStats {
+                name,
+                active_count: v[0],
+                event_count: v[0],
+                wakeup_count: v[2],
+                expire_count: v[1],
+                active_since: ns(v[3]),
+                total_time: ns(v[4]),
+                max_time: ns(v[6]),
+                last_change: ns(v[7]),
+                prevent_suspend_time: ns(v[5]),
+                source: StatsSource::ProcWakelocks,
+            })
+        })
+        .collect()
+}
+
+fn read_file(path: &Path) -> io::Result<String> {
+    let mut content = String::new();
+    File::open(path)?.read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Reads the wake lock statistics of the system, from `wakeup_sources` when
+/// debugfs is available and from `/proc/wakelocks` otherwise.
+pub fn wakelock_stats() -> io::Result<Vec<WakelockStats>> {
+    wakelock_stats_from("/sys", "/proc")
+}
+
+/// Like `wakelock_stats`, with other sysfs and procfs roots.
+pub fn wakelock_stats_from<S: AsRef<Path>, P: AsRef<Path>>(
+    sys_root: S,
+    proc_root: P,
+) -> io::Result<Vec<WakelockStats>> {
+    match read_file(&sys_root.as_ref().join("kernel/debug/wakeup_sources")) {
+        Ok(content) => parse_wakeup_sources(&content),
+        Err(_) => parse_proc_wakelocks(&read_file(&proc_root.as_ref().join("wakelocks"))?),
+    }
+}
+
+/// How the statistics of a lock changed between two snapshots.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WakelockDelta {
+    pub name: String,
+    pub active_count: u64,
+    pub event_count: u64,
+    pub wakeup_count: u64,
+    pub expire_count: u64,
+    pub total_time: Duration,
+    pub prevent_suspend_time: Duration,
+    /// Whether the lock is held in the second snapshot.
+    pub active: bool,
+}
+
+/// Computes the per-lock changes from `before` to `after`, sorted by
+/// decreasing `total_time` so the locks keeping the device awake come first.
+/// Locks that didn't change and are not held are left out.
+pub fn diff_wakelock_stats(
+    before: &[WakelockStats],
+    after: &[WakelockStats],
+) -> Vec<WakelockDelta> {
+    let zero = Duration::from_secs(0);
+    let previous: HashMap<&str, &WakelockStats> = before
+        .iter()
+        .map(|stats| (stats.name.as_str(), stats))
+        .collect();
+
+    let mut deltas: Vec<WakelockDelta> = after
+        .iter()
+        .map(|stats| {
+            // Counters only grow, unless the lock was destroyed and created
+            // again, in which case everything is new.
+            let old = previous
+                .get(stats.name.as_str())
+                .filter(|old| old.active_count <= stats.active_count);
+            let count =
+                |f: fn(&WakelockStats) -> u64| f(stats).saturating_sub(old.map_or(0, |old| f(old)));
+            let time = |f: fn(&WakelockStats) -> Duration| {
+                f(stats)
+                    .checked_sub(old.map_or(zero, |old| f(old)))
+                    .unwrap_or(zero)
+            };
+            WakelockDelta {
+                name: stats.name.clone(),
+                active_count: count(|s| s.active_count),
+                event_count: count(|s| s.event_count),
+                wakeup_count: count(|s| s.wakeup_count),
+                expire_count: count(|s| s.expire_count),
+                total_time: time(|s| s.total_time),
+                prevent_suspend_time: time(|s| s.prevent_suspend_time),
+                active: stats.is_active(),
+            }
+        })
+        .filter(|delta| {
+            delta.active
+                || delta.active_count > 0
+                || delta.event_count > 0
+                || delta.wakeup_count > 0
+                || delta.expire_count > 0
+                || delta.total_time > zero
+        })
+        .collect();
+
+    deltas.sort_by_key(|delta| Reverse(delta.total_time));
+    deltas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    const WAKEUP_SOURCES: &str = "\
+name\t\tactive_count\tevent_count\twakeup_count\texpire_count\tactive_since\ttotal_time\tmax_time\tlast_change\tprevent_suspend_time
+ipc000000_1234_app\t3\t4\t1\t0\t0\t120\t80\t5000\t10
+radio interface\t\t7\t7\t2\t1\t250\t900\t300\t6000\t0
+";
+
+    const PROC_WAKELOCKS: &str = "\
+name\tcount\texpire_count\twake_count\tactive_since\ttotal_time\tsleep_time\tmax_time\tlast_change
+\"PowerManagerService\"\t5\t1\t2\t0\t3000000000\t1000000\t2000000000\t9000000000
+\"alarm rtc\"\t2\t0\t0\t500000\t4000000\t0\t3000000\t8000000000
+";
+
+    fn stats(name: &str, active_count: u64, total_ms: u64, active_since_ms: u64) -> WakelockStats {
+        WakelockStats {
+            name: name.to_owned(),
+            active_count,
+            event_count: active_count,
+            wakeup_count: 0,
+            expire_count: 0,
+            active_since: Duration::from_millis(active_since_ms),
+            total_time: Duration::from_millis(total_ms),
+            max_time: Duration::from_millis(0),
+            last_change: Duration::from_millis(0),
+            prevent_suspend_time: Duration::from_millis(0),
+            source: StatsSource::WakeupSources,
+        }
+    }
+
+    #[test]
+    fn wakeup_sources() {
+        let stats = parse_wakeup_sources(WAKEUP_SOURCES).unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].name, "ipc000000_1234_app");
+        assert_eq!(stats[0].event_count, 4);
+        assert_eq!(stats[0].total_time, Duration::from_millis(120));
+        assert_eq!(stats[0].prevent_suspend_time, Duration::from_millis(10));
+        assert!(!stats[0].is_active());
+
+        let radio = &stats[1];
+        assert_eq!(radio.name, "radio interface");
+        assert_eq!(
+            (radio.active_count, radio.wakeup_count, radio.expire_count),
+            (7, 2, 1)
+        );
+        assert_eq!(radio.active_since, Duration::from_millis(250));
+        assert_eq!(radio.max_time, Duration::from_millis(300));
+        assert_eq!(radio.last_change, Duration::from_secs(6));
+        assert_eq!(radio.source, StatsSource::WakeupSources);
+        assert!(radio.is_active());
+    }
+
+    #[test]
+    fn proc_wakelocks() {
+        let stats = parse_proc_wakelocks(PROC_WAKELOCKS).unwrap();
+        assert_eq!(stats.len(), 2);
+
+        let power = &stats[0];
+        assert_eq!(power.name, "PowerManagerService");
+        assert_eq!((power.active_count, power.event_count), (5, 5));
+        assert_eq!((power.expire_count, power.wakeup_count), (1, 2));
+        assert_eq!(power.total_time, Duration::from_secs(3));
+        assert_eq!(power.prevent_suspend_time, Duration::from_millis(1));
+        assert_eq!(power.max_time, Duration::from_secs(2));
+        assert_eq!(power.last_change, Duration::from_secs(9));
+        assert_eq!(power.source, StatsSource::ProcWakelocks);
+
+        assert_eq!(stats[1].name, "alarm rtc");
+        assert_eq!(stats[1].active_since, Duration::from_micros(500));
+        assert!(stats[1].is_active());
+    }
+
+    #[test]
+    fn malformed_rows() {
+        for content in &[
+            "wlan\t1\t2\t3",
+            "wlan\t1\t2\t3\t4\t5\t6\t7\t8\tlots",
+            "1\t2\t3\t4\t5\t6\t7\t8\t9",
+        ] {
+            let err = parse_wakeup_sources(content).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+        let err = parse_proc_wakelocks("\"wlan\"\t1\t2\t-3\t4\t5\t6\t7\t8").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn stats_fallback() {
+        let root = env::temp_dir().join(format!("gonkhal-wakelock-stats-{}", process::id()));
+        let (sys, proc_root) = (root.join("sys"), root.join("proc"));
+        fs::create_dir_all(sys.join("kernel/debug")).unwrap();
+        fs::create_dir_all(&proc_root).unwrap();
+        fs::write(proc_root.join("wakelocks"), PROC_WAKELOCKS).unwrap();
+
+        let stats = wakelock_stats_from(&sys, &proc_root).unwrap();
+        assert_eq!(stats[0].source, StatsSource::ProcWakelocks);
+
+        fs::write(sys.join("kernel/debug/wakeup_sources"), WAKEUP_SOURCES).unwrap();
+        let stats = wakelock_stats_from(&sys, &proc_root).unwrap();
+        assert_eq!(stats[0].source, StatsSource::WakeupSources);
+
+        let err = wakelock_stats_from(root.join("none"), root.join("none")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn deltas() {
+        let before = [
+            stats("idle", 2, 100, 0),
+            stats("busy", 1, 100, 0),
+            stats("held", 4, 100, 0),
+            stats("recreated", 10, 5000, 0),
+        ];
+        let after = [
+            stats("idle", 2, 100, 0),
+            stats("busy", 3, 700, 0),
+            stats("held", 4, 100, 20),
+            stats("recreated", 2, 300, 0),
+            stats("new", 1, 50, 0),
+        ];
+        let deltas = diff_wakelock_stats(&before, &after);
+        let names: Vec<_> = deltas.iter().map(|delta| delta.name.as_str()).collect();
+        assert_eq!(names, ["busy", "recreated", "new", "held"]);
+
+        assert_eq!(deltas[0].active_count, 2);
+        assert_eq!(deltas[0].event_count, 2);
+        assert_eq!(deltas[0].total_time, Duration::from_millis(600));
+        // Its counters went backwards, so they all count as new.
+        assert_eq!(deltas[1].active_count, 2);
+        assert_eq!(deltas[1].total_time, Duration::from_millis(300));
+        assert_eq!(deltas[2].active_count, 1);
+        // Held locks are kept even when nothing changed.
+        assert!(deltas[3].active);
+        assert_eq!(deltas[3].active_count, 0);
+        assert_eq!(deltas[3].total_time, Duration::from_millis(0));
+    }
+}
@@ -2,10 +2,20 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+#[cfg(feature = "hardware_legacy")]
 use std::ffi::CString;
-use std::os::raw::{c_char, c_int};
+#[cfg(feature = "hardware_legacy")]
+use std::os::raw::c_char;
+use std::os::raw::c_int;
+use std::sync::mpsc::{channel, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError};
+#[cfg(feature = "hardware_legacy")]
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 
 // Based on hardware/libhardware_legacy/include/hardware_legacy/wifi.h
+#[cfg(feature = "hardware_legacy")]
 #[link(name = "hardware_legacy")]
 extern "C" {
     /**
@@ -94,112 +104,659 @@ extern "C" {
         -> c_int;
 }
 
-/// A stateless Wifi driver.
-pub struct Wifi;
+/// No reply, or a reply larger than the buffer we provided.
+pub const WIFI_NO_REPLY: c_int = -10000;
+/// The reply or event didn't fit in the buffer, and couldn't be read again.
+pub const WIFI_TRUNCATED: c_int = -10002;
+/// The command can't be sent, eg. because it contains a NUL byte.
+pub const WIFI_INVALID_COMMAND: c_int = -10003;
+/// No event was available, for instance because there is no connection.
+pub const WIFI_NO_EVENT: c_int = -10004;
+
+/// The operations used to talk to the Wifi driver and supplicant. This lets
+/// `WifiInterface` use the native implementation, a recorder or a replayed
+/// trace.
+///
+/// The driver and supplicant management methods default to succeeding
+/// without doing anything, which is what test backends usually want.
+pub trait WifiBackend {
+    /// Issues a command to the supplicant and returns the raw reply.
+    ///
+    /// Android extends the standard commands listed at
+    /// http://hostap.epitest.fi/wpa_supplicant/devel/ctrl_iface_page.html
+    /// to include support for sending commands to the driver:
+    ///
+    /// See wifi/java/android/net/wifi/WifiNative.java for the details of
+    /// driver commands that are supported
+    fn command_raw(&self, command: &str) -> Result<Vec<u8>, c_int>;
+
+    /// Performs a blocking call to get a Wi-Fi event and returns its raw
+    /// bytes when it occurs.
+    fn wait_for_event_raw(&self) -> Result<Vec<u8>, c_int>;
+
+    /// Issues a command to the supplicant. The reply is converted lossily
+    /// since we have no guarantee that it will be valid utf8.
+    fn command(&self, command: &str) -> Result<String, c_int> {
+        self.command_raw(command)
+            .map(|reply| String::from_utf8_lossy(&reply).into_owned())
+    }
+
+    /// Performs a blocking call to get a Wi-Fi event and returns a string
+    /// representing a Wi-Fi event when it occurs.
+    fn wait_for_event(&self) -> Result<String, c_int> {
+        self.wait_for_event_raw()
+            .map(|event| String::from_utf8_lossy(&event).into_owned())
+    }
 
-impl Wifi {
     /// Check if the Wifi driver is loaded.
-    pub fn is_driver_loaded() -> bool {
-        unsafe { is_wifi_driver_loaded() > 0 }
+    fn is_driver_loaded(&self) -> bool {
+        true
     }
 
     /// Load the Wifi driver.
-    pub fn load_driver() -> Result<(), c_int> {
-        match unsafe { wifi_load_driver() } {
-            0 => Ok(()),
-            err => Err(err),
-        }
+    fn load_driver(&self) -> Result<(), c_int> {
+        Ok(())
     }
 
     /// Unload the Wifi driver.
-    pub fn unload_driver() -> Result<(), c_int> {
-        match unsafe { wifi_unload_driver() } {
-            0 => Ok(()),
-            err => Err(err),
-        }
+    fn unload_driver(&self) -> Result<(), c_int> {
+        Ok(())
     }
 
     /// Start the supplicant.
-    pub fn start_supplicant(p2p_supported: bool) -> Result<(), c_int> {
-        match unsafe { wifi_start_supplicant(p2p_supported as c_int) } {
-            0 => Ok(()),
-            err => Err(err),
-        }
+    fn start_supplicant(&self, _p2p_supported: bool) -> Result<(), c_int> {
+        Ok(())
     }
 
     /// Stop the supplicant.
-    pub fn stop_supplicant(p2p_supported: bool) -> Result<(), c_int> {
-        match unsafe { wifi_stop_supplicant(p2p_supported as c_int) } {
-            0 => Ok(()),
-            err => Err(err),
-        }
+    fn stop_supplicant(&self, _p2p_supported: bool) -> Result<(), c_int> {
+        Ok(())
     }
 
     /// Open a connection to supplicant.
-    pub fn connect_to_supplicant() -> Result<(), c_int> {
-        match unsafe { wifi_connect_to_supplicant() } {
-            0 => Ok(()),
-            err => Err(err),
-        }
+    fn connect_to_supplicant(&self) -> Result<(), c_int> {
+        Ok(())
     }
 
     /// Close connection to supplicant.
-    pub fn close_supplicant_connection() {
+    fn close_supplicant_connection(&self) {}
+}
+
+/// The backend calling into libhardware_legacy. Its state is global to the
+/// process, so there should only be one user of it: see `WifiInterface::new`.
+#[cfg(feature = "hardware_legacy")]
+pub struct NativeWifi {
+    reply_size: usize,
+    max_reply_size: usize,
+    event_size: usize,
+}
+
+#[cfg(feature = "hardware_legacy")]
+impl Default for NativeWifi {
+    /// 4k buffers, with replies allowed to grow up to 64k.
+    fn default() -> Self {
+        NativeWifi {
+            reply_size: 4096,
+            max_reply_size: 64 * 1024,
+            event_size: 4096,
+        }
+    }
+}
+
+// The commands that only read state, which can safely be sent again to get
+// a reply that didn't fit.
+#[cfg_attr(not(feature = "hardware_legacy"), allow(dead_code))]
+const READ_ONLY_COMMANDS: [&str; 13] = [
+    "PING",
+    "STATUS",
+    "STATUS-VERBOSE",
+    "MIB",
+    "INTERFACES",
+    "LIST_NETWORKS",
+    "GET_NETWORK",
+    "GET",
+    "GET_CAPABILITY",
+    "SCAN_RESULTS",
+    "BSS",
+    "SIGNAL_POLL",
+    "PKTCNT_POLL",
+];
+
+#[cfg_attr(not(feature = "hardware_legacy"), allow(dead_code))]
+fn is_read_only(command: &str) -> bool {
+    let mut words = command.split_whitespace();
+    let name = match words.next() {
+        Some(prefix) if prefix.starts_with("IFNAME=") => words.next(),
+        name => name,
+    };
+    name.is_some_and(|name| READ_ONLY_COMMANDS.contains(&name))
+}
+
+/// What to do with the reply to a command.
+#[cfg_attr(not(feature = "hardware_legacy"), allow(dead_code))]
+#[derive(Debug, PartialEq)]
+enum CommandReply {
+    /// The reply is complete, with this length.
+    Complete(usize),
+    /// The reply was cut, the command has to be sent again with a buffer
+    /// of this size.
+    Retry(usize),
+    Failed(c_int),
+}
+
+/// Checks the result of `wifi_command`, which wrote `len` bytes of reply in
+/// a buffer of `capacity` bytes that may grow up to `max_capacity`.
+#[cfg_attr(not(feature = "hardware_legacy"), allow(dead_code))]
+fn check_reply(
+    command: &str,
+    res: c_int,
+    len: usize,
+    capacity: usize,
+    max_capacity: usize,
+) -> CommandReply {
+    // Some error occured...
+    if res < 0 {
+        return CommandReply::Failed(res);
+    }
+
+    // Either no response, or invalid buffer size. One byte is kept for the
+    // NUL terminator some commands get.
+    if len == 0 || len >= capacity {
+        return CommandReply::Failed(WIFI_NO_REPLY);
+    }
+
+    if len < capacity - 1 {
+        return CommandReply::Complete(len);
+    }
+
+    // The reply filled the buffer and was probably cut: send the command
+    // again with a larger one, unless that would apply it twice.
+    if capacity >= max_capacity || !is_read_only(command) {
+        return CommandReply::Failed(WIFI_TRUNCATED);
+    }
+    CommandReply::Retry((capacity * 2).min(max_capacity))
+}
+
+#[cfg(feature = "hardware_legacy")]
+fn to_result(res: c_int) -> Result<(), c_int> {
+    match res {
+        0 => Ok(()),
+        err => Err(err),
+    }
+}
+
+#[cfg(feature = "hardware_legacy")]
+impl NativeWifi {
+    /// Sets the initial size of command reply buffers, and the size up to
+    /// which they grow when a reply doesn't fit. Growing sends the command
+    /// again, so it's only done for commands reading state, like `STATUS`
+    /// or `SCAN_RESULTS`: other replies that don't fit are reported as
+    /// `WIFI_TRUNCATED`.
+    pub fn with_reply_buffer(mut self, initial: usize, max: usize) -> Self {
+        self.reply_size = initial.max(2);
+        self.max_reply_size = max.max(self.reply_size);
+        self
+    }
+
+    /// Sets the size of the event buffer. Events can't be read again, so
+    /// larger ones are reported as `WIFI_TRUNCATED`.
+    pub fn with_event_buffer(mut self, size: usize) -> Self {
+        self.event_size = size.max(2);
+        self
+    }
+}
+
+#[cfg(feature = "hardware_legacy")]
+impl WifiBackend for NativeWifi {
+    fn is_driver_loaded(&self) -> bool {
+        unsafe { is_wifi_driver_loaded() > 0 }
+    }
+
+    fn load_driver(&self) -> Result<(), c_int> {
+        to_result(unsafe { wifi_load_driver() })
+    }
+
+    fn unload_driver(&self) -> Result<(), c_int> {
+        to_result(unsafe { wifi_unload_driver() })
+    }
+
+    fn start_supplicant(&self, p2p_supported: bool) -> Result<(), c_int> {
+        to_result(unsafe { wifi_start_supplicant(p2p_supported as c_int) })
+    }
+
+    fn stop_supplicant(&self, p2p_supported: bool) -> Result<(), c_int> {
+        to_result(unsafe { wifi_stop_supplicant(p2p_supported as c_int) })
+    }
+
+    fn connect_to_supplicant(&self) -> Result<(), c_int> {
+        to_result(unsafe { wifi_connect_to_supplicant() })
+    }
+
+    fn close_supplicant_connection(&self) {
         unsafe { wifi_close_supplicant_connection() };
     }
 
-    /// Performs a blocking call to get a Wi-Fi event and returns a string
-    /// representing a Wi-Fi event when it occurs.
-    pub fn wait_for_event() -> Result<String, ()> {
-        // Use a 4k buffer.
-        let mut buffer: [c_char; 4096] = [0; 4096];
-        let res = unsafe { wifi_wait_for_event(buffer.as_mut_ptr(), 4096) };
+    fn wait_for_event_raw(&self) -> Result<Vec<u8>, c_int> {
+        let mut buffer = vec![0u8; self.event_size];
+        let res = unsafe { wifi_wait_for_event(buffer.as_mut_ptr() as *mut c_char, buffer.len()) };
 
         // Some error occured...
         if res < 0 {
-            return Err(());
+            return Err(res);
         }
 
-        // Either no event, or invalid buffer size.
-        if res == 0 || res > 4096 {
-            return Err(());
+        // No event, for instance no connection.
+        if res == 0 {
+            return Err(WIFI_NO_EVENT);
         }
 
-        // Do a lossy conversion since we have no guarantee that the input
-        // will be valid utf8.
-        Ok(String::from_utf8_lossy(&buffer[..res as usize]).to_string())
+        // libhardware_legacy keeps one byte for the NUL terminator, so a
+        // full buffer means the event was cut.
+        if res as usize >= buffer.len() - 1 {
+            return Err(WIFI_TRUNCATED);
+        }
+
+        buffer.truncate(res as usize);
+        Ok(buffer)
     }
 
-    ///  Issues a command to the Wi-Fi driver.
-    ///
-    ///  Android extends the standard commands listed at
-    ///  /link http://hostap.epitest.fi/wpa_supplicant/devel/ctrl_iface_page.html
-    ///  to include support for sending commands to the driver:
+    fn command_raw(&self, command: &str) -> Result<Vec<u8>, c_int> {
+        // turn command into a C string suitable for ffi.
+        let cmd = CString::new(command).map_err(|_| WIFI_INVALID_COMMAND)?;
+
+        let mut capacity = self.reply_size;
+        loop {
+            let mut buffer = vec![0u8; capacity];
+            // Keep room for the NUL terminator some commands get.
+            let mut buff_size: usize = capacity - 1;
+
+            let res = unsafe {
+                wifi_command(
+                    cmd.as_ptr(),
+                    buffer.as_mut_ptr() as *mut c_char,
+                    &mut buff_size,
+                )
+            };
+
+            match check_reply(command, res, buff_size, capacity, self.max_reply_size) {
+                CommandReply::Complete(len) => {
+                    buffer.truncate(len);
+                    return Ok(buffer);
+                }
+                CommandReply::Retry(larger) => capacity = larger,
+                CommandReply::Failed(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// Returns the interface an event is about, from its `IFNAME=` prefix.
+pub fn event_interface(event: &str) -> Option<&str> {
+    event
+        .strip_prefix("IFNAME=")
+        .and_then(|event| event.split_whitespace().next())
+}
+
+struct Subscriber {
+    ifname: String,
+    sender: Sender<Vec<u8>>,
+}
+
+struct EventHub {
+    subscribers: Vec<Subscriber>,
+    reader_running: bool,
+}
+
+/// The state shared by all the interfaces using the same backend.
+struct Supplicant {
+    backend: Box<dyn WifiBackend + Send + Sync>,
+    // Serializes commands, since the supplicant connection can't be used
+    // concurrently.
+    commands: Mutex<()>,
+    events: Mutex<EventHub>,
+}
+
+impl Supplicant {
+    fn new(backend: Box<dyn WifiBackend + Send + Sync>) -> Arc<Self> {
+        Arc::new(Supplicant {
+            backend,
+            commands: Mutex::new(()),
+            events: Mutex::new(EventHub {
+                subscribers: vec![],
+                reader_running: false,
+            }),
+        })
+    }
+
+    /// Reads events and dispatches them until the connection fails or
+    /// closes, or nobody listens anymore.
+    fn read_events(&self) {
+        loop {
+            let event = self.backend.wait_for_event_raw();
+            let mut hub = self.events.lock().unwrap();
+            let done = match event {
+                Ok(event) => {
+                    let text = String::from_utf8_lossy(&event);
+                    let target = event_interface(&text);
+                    hub.subscribers.retain(|subscriber| match target {
+                        Some(ifname) if ifname != subscriber.ifname => true,
+                        _ => subscriber.sender.send(event.clone()).is_ok(),
+                    });
+                    hub.subscribers.is_empty() || text.contains("CTRL-EVENT-TERMINATING")
+                }
+                // We can't tell who a cut event was for, so drop it.
+                Err(WIFI_TRUNCATED) => false,
+                Err(_) => true,
+            };
+            if done {
+                // Dropping the senders ends the subscriptions.
+                hub.subscribers.clear();
+                hub.reader_running = false;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "hardware_legacy")]
+fn native_supplicant() -> Arc<Supplicant> {
+    static NATIVE: OnceLock<Arc<Supplicant>> = OnceLock::new();
+    NATIVE
+        .get_or_init(|| Supplicant::new(Box::new(NativeWifi::default())))
+        .clone()
+}
+
+/// A stream of supplicant events for one interface, see
+/// `WifiInterface::subscribe`.
+pub struct WifiEvents {
+    receiver: Receiver<Vec<u8>>,
+}
+
+fn lossy(event: Vec<u8>) -> String {
+    String::from_utf8_lossy(&event).into_owned()
+}
+
+impl WifiEvents {
+    /// Blocks until the next event. Fails once the supplicant connection
+    /// is closed.
+    pub fn recv(&self) -> Result<String, RecvError> {
+        self.recv_raw().map(lossy)
+    }
+
+    /// Like `recv`, but returns the raw bytes of the event.
+    pub fn recv_raw(&self) -> Result<Vec<u8>, RecvError> {
+        self.receiver.recv()
+    }
+
+    /// Waits up to `timeout` for the next event. Returns `Ok(None)` on
+    /// timeout, and fails once the supplicant connection is closed.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Option<String>, RecvError> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(event) => Ok(Some(lossy(event))),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+        }
+    }
+
+    /// Returns a pending event without blocking.
+    pub fn try_recv(&self) -> Result<Option<String>, RecvError> {
+        match self.receiver.try_recv() {
+            Ok(event) => Ok(Some(lossy(event))),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(RecvError),
+        }
+    }
+}
+
+impl Iterator for WifiEvents {
+    type Item = String;
+
+    fn next(&mut self) -> Option<String> {
+        self.recv().ok()
+    }
+}
+
+/// A handle to one Wifi interface, like `wlan0` or `p2p0`.
+///
+/// Handles are cheap to clone and can be shared between threads. Commands
+/// from all the handles using the same backend are serialized, and a single
+/// thread reads supplicant events and dispatches them to subscribers.
+#[derive(Clone)]
+pub struct WifiInterface {
+    ifname: String,
+    supplicant: Arc<Supplicant>,
+}
+
+impl WifiInterface {
+    /// Creates a handle to an interface using the native libhardware_legacy
+    /// backend. All the native handles share the same supplicant connection.
+    #[cfg(feature = "hardware_legacy")]
+    pub fn new(ifname: &str) -> Self {
+        WifiInterface {
+            ifname: ifname.to_owned(),
+            supplicant: native_supplicant(),
+        }
+    }
+
+    /// Creates a handle to an interface using a custom backend.
+    pub fn with_backend<B: WifiBackend + Send + Sync + 'static>(ifname: &str, backend: B) -> Self {
+        WifiInterface {
+            ifname: ifname.to_owned(),
+            supplicant: Supplicant::new(Box::new(backend)),
+        }
+    }
+
+    /// Creates a handle to another interface sharing this handle's backend.
+    pub fn interface(&self, ifname: &str) -> Self {
+        WifiInterface {
+            ifname: ifname.to_owned(),
+            supplicant: self.supplicant.clone(),
+        }
+    }
+
+    /// The name of this interface.
+    pub fn name(&self) -> &str {
+        &self.ifname
+    }
+
+    /// Check if the Wifi driver is loaded.
+    pub fn is_driver_loaded(&self) -> bool {
+        let _lock = self.supplicant.commands.lock().unwrap();
+        self.supplicant.backend.is_driver_loaded()
+    }
+
+    /// Load the Wifi driver.
+    pub fn load_driver(&self) -> Result<(), c_int> {
+        let _lock = self.supplicant.commands.lock().unwrap();
+        self.supplicant.backend.load_driver()
+    }
+
+    /// Unload the Wifi driver.
+    pub fn unload_driver(&self) -> Result<(), c_int> {
+        let _lock = self.supplicant.commands.lock().unwrap();
+        self.supplicant.backend.unload_driver()
+    }
+
+    /// Start the supplicant.
+    pub fn start_supplicant(&self, p2p_supported: bool) -> Result<(), c_int> {
+        let _lock = self.supplicant.commands.lock().unwrap();
+        self.supplicant.backend.start_supplicant(p2p_supported)
+    }
+
+    /// Stop the supplicant.
+    pub fn stop_supplicant(&self, p2p_supported: bool) -> Result<(), c_int> {
+        let _lock = self.supplicant.commands.lock().unwrap();
+        self.supplicant.backend.stop_supplicant(p2p_supported)
+    }
+
+    /// Open a connection to supplicant.
+    pub fn connect_to_supplicant(&self) -> Result<(), c_int> {
+        let _lock = self.supplicant.commands.lock().unwrap();
+        self.supplicant.backend.connect_to_supplicant()
+    }
+
+    /// Close connection to supplicant.
+    pub fn close_supplicant_connection(&self) {
+        let _lock = self.supplicant.commands.lock().unwrap();
+        self.supplicant.backend.close_supplicant_connection()
+    }
+
+    /// Issues a command for this interface. The `IFNAME=` prefix is added
+    /// for you.
+    pub fn command(&self, command: &str) -> Result<String, c_int> {
+        self.command_raw(command).map(lossy)
+    }
+
+    /// Like `command`, but returns the raw bytes of the reply. Useful when
+    /// it contains non utf8 SSIDs.
+    pub fn command_raw(&self, command: &str) -> Result<Vec<u8>, c_int> {
+        if command.contains('\0') || self.ifname.contains(char::is_whitespace) {
+            return Err(WIFI_INVALID_COMMAND);
+        }
+        let command = format!("IFNAME={} {}", self.ifname, command);
+        let _lock = self.supplicant.commands.lock().unwrap();
+        self.supplicant.backend.command_raw(&command)
+    }
+
+    /// Subscribes to the events of this interface, and to the events that
+    /// are not specific to an interface. Events are delivered as sent by
+    /// the supplicant, including their `IFNAME=` prefix.
     ///
-    ///  See wifi/java/android/net/wifi/WifiNative.java for the details of
-    ///  driver commands that are supported
-    pub fn command(command: &str) -> Result<String, c_int> {
-        let mut buffer: [c_char; 4096] = [0; 4096];
-        let mut buff_size: usize = 4096;
+    /// The event reader thread is started as needed, and stops when the
+    /// supplicant connection closes or all the subscriptions are dropped.
+    pub fn subscribe(&self) -> WifiEvents {
+        let (sender, receiver) = channel();
+        let mut hub = self.supplicant.events.lock().unwrap();
+        hub.subscribers.push(Subscriber {
+            ifname: self.ifname.clone(),
+            sender,
+        });
+        if !hub.reader_running {
+            let supplicant = self.supplicant.clone();
+            thread::Builder::new()
+                .name("wifi events".to_owned())
+                .spawn(move || supplicant.read_events())
+                .expect("Failed to start wifi events thread!");
+            hub.reader_running = true;
+        }
+        WifiEvents { receiver }
+    }
+}
 
-        // turn command into a C string suitable for ffi.
-        let cmd = CString::new(command).expect(&format!("Malformed wifi command: {}", command));
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        let res = unsafe { wifi_command(cmd.as_ptr(), buffer.as_mut_ptr(), &mut buff_size) };
+    /// A supplicant sending the events it's given, until the sender of
+    /// `events` is dropped.
+    struct FakeSupplicant {
+        events: Mutex<Receiver<Result<&'static [u8], c_int>>>,
+    }
 
-        // Some error occured...
-        if res < 0 {
-            return Err(res);
+    impl WifiBackend for FakeSupplicant {
+        fn command_raw(&self, _command: &str) -> Result<Vec<u8>, c_int> {
+            Err(-1)
+        }
+
+        fn wait_for_event_raw(&self) -> Result<Vec<u8>, c_int> {
+            match self.events.lock().unwrap().recv() {
+                Ok(event) => event.map(|event| event.to_vec()),
+                Err(_) => Err(WIFI_NO_EVENT),
+            }
         }
+    }
+
+    #[test]
+    fn read_only_commands() {
+        assert!(is_read_only("STATUS"));
+        assert!(is_read_only("IFNAME=wlan0 SCAN_RESULTS"));
+        assert!(is_read_only("IFNAME=wlan0 GET_NETWORK 0 ssid"));
+        assert!(!is_read_only("IFNAME=wlan0 SET_NETWORK 0 ssid \"GET\""));
+        assert!(!is_read_only("STATUSX"));
+        assert!(!is_read_only("IFNAME=wlan0"));
+        assert!(!is_read_only(""));
+    }
+
+    #[test]
+    fn replies_grow_when_they_can_be_sent_again() {
+        let check = |command, res, len, capacity| check_reply(command, res, len, capacity, 64);
+        assert_eq!(check("STATUS", 0, 10, 16), CommandReply::Complete(10));
+        assert_eq!(check("STATUS", -1, 10, 16), CommandReply::Failed(-1));
+        assert_eq!(
+            check("STATUS", 0, 0, 16),
+            CommandReply::Failed(WIFI_NO_REPLY)
+        );
+        assert_eq!(
+            check("STATUS", 0, 16, 16),
+            CommandReply::Failed(WIFI_NO_REPLY)
+        );
 
-        // Either no response, or invalid buffer size.
-        if buff_size == 0 || buff_size > 4096 {
-            println!("buff_size error {}", buff_size);
-            return Err(-10000); // TODO: check if that's actually safe to use a custom error value.
+        // A full buffer means the reply was cut.
+        assert_eq!(check("STATUS", 0, 15, 16), CommandReply::Retry(32));
+        assert_eq!(
+            check("IFNAME=wlan0 BSS 0", 0, 47, 48),
+            CommandReply::Retry(64)
+        );
+        assert_eq!(
+            check("STATUS", 0, 63, 64),
+            CommandReply::Failed(WIFI_TRUNCATED)
+        );
+        assert_eq!(
+            check("IFNAME=wlan0 SCAN", 0, 15, 16),
+            CommandReply::Failed(WIFI_TRUNCATED)
+        );
+    }
+
+    #[test]
+    fn events_are_routed_by_interface() {
+        let (sender, receiver) = channel();
+        let wlan0 = WifiInterface::with_backend(
+            "wlan0",
+            FakeSupplicant {
+                events: Mutex::new(receiver),
+            },
+        );
+        let p2p0 = wlan0.interface("p2p0");
+        // Both are subscribed before the first event is sent.
+        let wlan0_events = wlan0.subscribe();
+        let p2p0_events = p2p0.subscribe();
+
+        let events: [Result<&'static [u8], c_int>; 5] = [
+            Ok(b"<3>CTRL-EVENT-SCAN-STARTED "),
+            Ok(b"IFNAME=p2p0 <3>P2P-DEVICE-FOUND 02:00:00:00:00:01"),
+            Ok(b"IFNAME=wlan1 <3>CTRL-EVENT-SCAN-RESULTS "),
+            // A cut event is dropped, and the reader goes on.
+            Err(WIFI_TRUNCATED),
+            Ok(b"IFNAME=wlan0 <3>CTRL-EVENT-CONNECTED - Connection to 02:00:00:00:00:02"),
+        ];
+        for event in events.iter() {
+            sender.send(*event).unwrap();
         }
+        drop(sender);
+
+        assert_eq!(
+            wlan0_events.collect::<Vec<_>>(),
+            [
+                "<3>CTRL-EVENT-SCAN-STARTED ",
+                "IFNAME=wlan0 <3>CTRL-EVENT-CONNECTED - Connection to 02:00:00:00:00:02",
+            ]
+        );
+        assert_eq!(
+            p2p0_events.collect::<Vec<_>>(),
+            [
+                "<3>CTRL-EVENT-SCAN-STARTED ",
+                "IFNAME=p2p0 <3>P2P-DEVICE-FOUND 02:00:00:00:00:01",
+            ]
+        );
+    }
 
-        // Do a lossy conversion since we have no guarantee that the input
-        // will be valid utf8.
-        Ok(String::from_utf8_lossy(&buffer[..buff_size]).to_string())
+    #[test]
+    fn event_interfaces() {
+        assert_eq!(
+            event_interface("IFNAME=wlan0 <3>CTRL-EVENT-TERMINATING"),
+            Some("wlan0")
+        );
+        assert_eq!(event_interface("<3>CTRL-EVENT-TERMINATING"), None);
+        assert_eq!(event_interface("<3>IFNAME=wlan0"), None);
     }
 }
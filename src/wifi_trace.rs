@@ -0,0 +1,640 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! Recording and replay of supplicant sessions.
+//!
+//! Traces are JSON-lines files with one object per command or event:
+//!
+//! ```text
+//! {"t":12,"type":"command","request":"STATUS","reply":"wpa_state=SCANNING\n"}
+//! {"t":15,"type":"command","request":"BOGUS","error":-1}
+//! {"t":40,"type":"event","event":"<3>CTRL-EVENT-SCAN-RESULTS "}
+//! {"t":47,"type":"event","event_hex":"49464e414d45...ff"}
+//! {"t":52,"type":"event","error":-10004}
+//! ```
+//!
+//! `t` is the number of milliseconds since the recording started. Payloads
+//! that are not valid utf8, like some SSIDs, are stored in hexadecimal.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::raw::c_int;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+use wifi::{WifiBackend, WIFI_NO_EVENT};
+
+/// Returned by `Replay::command` when the trace has no matching command.
+pub const REPLAY_DIVERGED: c_int = -10001;
+
+fn escape(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[derive(Debug, PartialEq)]
+enum Value {
+    Str(String),
+    Int(i64),
+}
+
+fn invalid(line: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Malformed trace entry at line {}", line),
+    )
+}
+
+/// Parses a flat JSON object whose values are strings or integers, which is
+/// all our traces contain.
+fn parse_object(input: &str) -> Option<HashMap<String, Value>> {
+    fn skip_ws(chars: &[char], pos: &mut usize) {
+        while *pos < chars.len() && chars[*pos].is_whitespace() {
+            *pos += 1;
+        }
+    }
+
+    fn hex4(chars: &[char], pos: &mut usize) -> Option<u32> {
+        if *pos + 4 > chars.len() {
+            return None;
+        }
+        let digits: String = chars[*pos..*pos + 4].iter().collect();
+        *pos += 4;
+        u32::from_str_radix(&digits, 16).ok()
+    }
+
+    fn string(chars: &[char], pos: &mut usize) -> Option<String> {
+        if chars.get(*pos) != Some(&'"') {
+            return None;
+        }
+        *pos += 1;
+        let mut out = String::new();
+        loop {
+            let c = *chars.get(*pos)?;
+            *pos += 1;
+            match c {
+                '"' => return Some(out),
+                '\\' => {
+                    let escaped = *chars.get(*pos)?;
+                    *pos += 1;
+                    match escaped {
+                        '"' => out.push('"'),
+                        '\\' => out.push('\\'),
+                        '/' => out.push('/'),
+                        'b' => out.push('\u{8}'),
+                        'f' => out.push('\u{c}'),
+                        'n' => out.push('\n'),
+                        'r' => out.push('\r'),
+                        't' => out.push('\t'),
+                        'u' => {
+                            let mut code = hex4(chars, pos)?;
+                            // Surrogate pair.
+                            if (0xd800..0xdc00).contains(&code) {
+                                if chars.get(*pos) != Some(&'\\')
+                                    || chars.get(*pos + 1) != Some(&'u')
+                                {
+                                    return None;
+                                }
+                                *pos += 2;
+                                let low = hex4(chars, pos)?;
+                                code = 0x10000
+                                    + ((code - 0xd800) << 10)
+                                    + (low.checked_sub(0xdc00)?);
+                            }
+                            out.push(::std::char::from_u32(code)?);
+                        }
+                        _ => return None,
+                    }
+                }
+                c => out.push(c),
+            }
+        }
+    }
+
+    let chars: Vec<char> = input.chars().collect();
+    let mut pos = 0;
+    let mut object = HashMap::new();
+
+    skip_ws(&chars, &mut pos);
+    if chars.get(pos) != Some(&'{') {
+        return None;
+    }
+    pos += 1;
+    skip_ws(&chars, &mut pos);
+    if chars.get(pos) == Some(&'}') {
+        return Some(object);
+    }
+
+    loop {
+        skip_ws(&chars, &mut pos);
+        let key = string(&chars, &mut pos)?;
+        skip_ws(&chars, &mut pos);
+        if chars.get(pos) != Some(&':') {
+            return None;
+        }
+        pos += 1;
+        skip_ws(&chars, &mut pos);
+        let value = if chars.get(pos) == Some(&'"') {
+            Value::Str(string(&chars, &mut pos)?)
+        } else {
+            let start = pos;
+            while pos < chars.len() && (chars[pos] == '-' || chars[pos].is_ascii_digit()) {
+                pos += 1;
+            }
+            let number: String = chars[start..pos].iter().collect();
+            Value::Int(number.parse().ok()?)
+        };
+        object.insert(key, value);
+        skip_ws(&chars, &mut pos);
+        match chars.get(pos) {
+            Some(&',') => pos += 1,
+            Some(&'}') => return Some(object),
+            _ => return None,
+        }
+    }
+}
+
+/// Serializes a payload as a `"key":"..."` JSON member, or as hexadecimal
+/// in `"key_hex"` when it's not valid utf8.
+fn payload(key: &str, value: &[u8]) -> String {
+    match ::std::str::from_utf8(value) {
+        Ok(text) => format!("\"{}\":{}", key, escape(text)),
+        Err(_) => {
+            let hex: String = value.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("\"{}_hex\":\"{}\"", key, hex)
+        }
+    }
+}
+
+fn take_payload(object: &mut HashMap<String, Value>, key: &str) -> Option<Vec<u8>> {
+    if let Some(Value::Str(text)) = object.remove(key) {
+        return Some(text.into_bytes());
+    }
+    match object.remove(&format!("{}_hex", key)) {
+        Some(Value::Str(ref hex)) if hex.len() % 2 == 0 && hex.is_ascii() => (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+            .collect(),
+        _ => None,
+    }
+}
+
+/// One entry of a supplicant trace.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TraceEntry {
+    Command {
+        /// Milliseconds since the start of the recording.
+        time_ms: u64,
+        request: String,
+        reply: Result<Vec<u8>, c_int>,
+    },
+    Event {
+        time_ms: u64,
+        event: Result<Vec<u8>, c_int>,
+    },
+}
+
+impl TraceEntry {
+    /// Milliseconds since the start of the recording.
+    pub fn time_ms(&self) -> u64 {
+        match *self {
+            TraceEntry::Command { time_ms, .. } | TraceEntry::Event { time_ms, .. } => time_ms,
+        }
+    }
+
+    /// Serializes this entry as a single JSON line, without the newline.
+    pub fn to_json(&self) -> String {
+        match *self {
+            TraceEntry::Command {
+                time_ms,
+                ref request,
+                ref reply,
+            } => match *reply {
+                Ok(ref reply) => format!(
+                    "{{\"t\":{},\"type\":\"command\",\"request\":{},{}}}",
+                    time_ms,
+                    escape(request),
+                    payload("reply", reply)
+                ),
+                Err(code) => format!(
+                    "{{\"t\":{},\"type\":\"command\",\"request\":{},\"error\":{}}}",
+                    time_ms,
+                    escape(request),
+                    code
+                ),
+            },
+            TraceEntry::Event { time_ms, ref event } => match *event {
+                Ok(ref event) => format!(
+                    "{{\"t\":{},\"type\":\"event\",{}}}",
+                    time_ms,
+                    payload("event", event)
+                ),
+                Err(code) => format!(
+                    "{{\"t\":{},\"type\":\"event\",\"error\":{}}}",
+                    time_ms, code
+                ),
+            },
+        }
+    }
+
+    /// Parses a line written by `to_json`.
+    pub fn from_json(line: &str) -> Option<Self> {
+        let mut object = parse_object(line)?;
+        let time_ms = match object.remove("t") {
+            Some(Value::Int(t)) if t >= 0 => t as u64,
+            _ => return None,
+        };
+        let error = match object.remove("error") {
+            Some(Value::Int(code)) => Some(code as c_int),
+            Some(_) => return None,
+            None => None,
+        };
+
+        match object.remove("type") {
+            Some(Value::Str(ref kind)) if kind == "command" => {
+                let request = match object.remove("request") {
+                    Some(Value::Str(request)) => request,
+                    _ => return None,
+                };
+                let reply = match (take_payload(&mut object, "reply"), error) {
+                    (Some(reply), None) => Ok(reply),
+                    (None, Some(code)) => Err(code),
+                    _ => return None,
+                };
+                Some(TraceEntry::Command {
+                    time_ms,
+                    request,
+                    reply,
+                })
+            }
+            Some(Value::Str(ref kind)) if kind == "event" => {
+                let event = match (take_payload(&mut object, "event"), error) {
+                    (Some(event), None) => Ok(event),
+                    (None, Some(code)) => Err(code),
+                    _ => return None,
+                };
+                Some(TraceEntry::Event { time_ms, event })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Reads all the entries of a trace file.
+pub fn read_trace<P: AsRef<Path>>(path: P) -> io::Result<Vec<TraceEntry>> {
+    let mut entries = vec![];
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push(TraceEntry::from_json(&line).ok_or_else(|| invalid(i + 1))?);
+    }
+    Ok(entries)
+}
+
+/// A backend that forwards everything to another one and writes each
+/// command and event to a trace file.
+pub struct Recorder<B: WifiBackend> {
+    backend: B,
+    start: Instant,
+    output: Mutex<BufWriter<File>>,
+}
+
+impl<B: WifiBackend> Recorder<B> {
+    /// Starts recording the traffic of `backend` to a new trace file.
+    pub fn new<P: AsRef<Path>>(backend: B, path: P) -> io::Result<Self> {
+        Ok(Recorder {
+            backend,
+            start: Instant::now(),
+            output: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    fn elapsed_ms(&self) -> u64 {
+        let elapsed = self.start.elapsed();
+        elapsed.as_secs() * 1000 + elapsed.subsec_millis() as u64
+    }
+
+    fn write(&self, entry: TraceEntry) {
+        // Recording is best effort and must not change the backend behavior.
+        if let Ok(mut output) = self.output.lock() {
+            let _ = writeln!(output, "{}", entry.to_json());
+            let _ = output.flush();
+        }
+    }
+
+    /// Returns the wrapped backend.
+    pub fn into_inner(self) -> B {
+        self.backend
+    }
+}
+
+impl<B: WifiBackend> WifiBackend for Recorder<B> {
+    fn command_raw(&self, command: &str) -> Result<Vec<u8>, c_int> {
+        let reply = self.backend.command_raw(command);
+        self.write(TraceEntry::Command {
+            time_ms: self.elapsed_ms(),
+            request: command.to_owned(),
+            reply: reply.clone(),
+        });
+        reply
+    }
+
+    fn wait_for_event_raw(&self) -> Result<Vec<u8>, c_int> {
+        let event = self.backend.wait_for_event_raw();
+        self.write(TraceEntry::Event {
+            time_ms: self.elapsed_ms(),
+            event: event.clone(),
+        });
+        event
+    }
+
+    fn is_driver_loaded(&self) -> bool {
+        self.backend.is_driver_loaded()
+    }
+
+    fn load_driver(&self) -> Result<(), c_int> {
+        self.backend.load_driver()
+    }
+
+    fn unload_driver(&self) -> Result<(), c_int> {
+        self.backend.unload_driver()
+    }
+
+    fn start_supplicant(&self, p2p_supported: bool) -> Result<(), c_int> {
+        self.backend.start_supplicant(p2p_supported)
+    }
+
+    fn stop_supplicant(&self, p2p_supported: bool) -> Result<(), c_int> {
+        self.backend.stop_supplicant(p2p_supported)
+    }
+
+    fn connect_to_supplicant(&self) -> Result<(), c_int> {
+        self.backend.connect_to_supplicant()
+    }
+
+    fn close_supplicant_connection(&self) {
+        self.backend.close_supplicant_connection()
+    }
+}
+
+struct ReplayState {
+    commands: VecDeque<(u64, String, Result<Vec<u8>, c_int>)>,
+    events: VecDeque<(u64, Result<Vec<u8>, c_int>)>,
+    divergences: Vec<String>,
+}
+
+/// A backend serving a recorded trace.
+///
+/// Commands and events are replayed in their recorded order, independently
+/// of each other since they usually come from different threads. A command
+/// that doesn't match the next recorded one fails with `REPLAY_DIVERGED`
+/// and is reported by `divergences`. Once all events are consumed,
+/// `wait_for_event_raw` fails with `WIFI_NO_EVENT`, which ends subscriptions.
+pub struct Replay {
+    state: Mutex<ReplayState>,
+    start: Instant,
+    realtime: bool,
+}
+
+impl Replay {
+    /// Creates a replay backend from trace entries.
+    pub fn new(entries: Vec<TraceEntry>) -> Self {
+        let mut commands = VecDeque::new();
+        let mut events = VecDeque::new();
+        for entry in entries {
+            match entry {
+                TraceEntry::Command {
+                    time_ms,
+                    request,
+                    reply,
+                } => commands.push_back((time_ms, request, reply)),
+                TraceEntry::Event { time_ms, event } => events.push_back((time_ms, event)),
+            }
+        }
+
+        Replay {
+            state: Mutex::new(ReplayState {
+                commands,
+                events,
+                divergences: vec![],
+            }),
+            start: Instant::now(),
+            realtime: false,
+        }
+    }
+
+    /// Creates a replay backend from a trace file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Replay::new(read_trace(path)?))
+    }
+
+    /// When enabled, events are not delivered before their recorded time
+    /// relative to the creation of this backend. Disabled by default so
+    /// tests run as fast as possible.
+    pub fn with_realtime(mut self, realtime: bool) -> Self {
+        self.realtime = realtime;
+        self
+    }
+
+    /// Commands that didn't match the trace, in the order they were issued.
+    pub fn divergences(&self) -> Vec<String> {
+        self.state.lock().unwrap().divergences.clone()
+    }
+
+    /// Checks if every recorded command and event has been served.
+    pub fn is_exhausted(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state.commands.is_empty() && state.events.is_empty()
+    }
+}
+
+impl WifiBackend for Replay {
+    fn command_raw(&self, command: &str) -> Result<Vec<u8>, c_int> {
+        let mut state = self.state.lock().unwrap();
+        let matches = state
+            .commands
+            .front()
+            .map(|(_, request, _)| request == command)
+            .unwrap_or(false);
+        if !matches {
+            state.divergences.push(command.to_owned());
+            return Err(REPLAY_DIVERGED);
+        }
+        state.commands.pop_front().unwrap().2
+    }
+
+    fn wait_for_event_raw(&self) -> Result<Vec<u8>, c_int> {
+        let next = self.state.lock().unwrap().events.pop_front();
+        match next {
+            Some((time_ms, event)) => {
+                if self.realtime {
+                    let due = Duration::from_millis(time_ms);
+                    let elapsed = self.start.elapsed();
+                    if due > elapsed {
+                        thread::sleep(due - elapsed);
+                    }
+                }
+                event
+            }
+            None => Err(WIFI_NO_EVENT),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use wifi::WifiInterface;
+
+    /// A supplicant with canned replies, sending a few events.
+    struct FakeSupplicant {
+        events: Mutex<VecDeque<&'static [u8]>>,
+    }
+
+    impl FakeSupplicant {
+        fn new() -> Self {
+            let events: [&'static [u8]; 3] = [
+                b"<3>CTRL-EVENT-SCAN-STARTED ",
+                b"IFNAME=p2p0 <3>P2P-DEVICE-FOUND 02:00:00:00:00:01",
+                b"IFNAME=wlan0 <3>CTRL-EVENT-CONNECTED - Connection to 02:00:00:00:00:02",
+            ];
+            FakeSupplicant {
+                events: Mutex::new(events.iter().cloned().collect()),
+            }
+        }
+    }
+
+    impl WifiBackend for FakeSupplicant {
+        fn command_raw(&self, command: &str) -> Result<Vec<u8>, c_int> {
+            match command {
+                // Not utf8, like some SSIDs.
+                "IFNAME=wlan0 STATUS" => Ok(b"wpa_state=COMPLETED\nssid=caf\xe9\n".to_vec()),
+                "IFNAME=wlan0 SCAN" => Ok(b"OK\n".to_vec()),
+                _ => Err(-1),
+            }
+        }
+
+        fn wait_for_event_raw(&self) -> Result<Vec<u8>, c_int> {
+            match self.events.lock().unwrap().pop_front() {
+                Some(event) => Ok(event.to_vec()),
+                None => Err(WIFI_NO_EVENT),
+            }
+        }
+    }
+
+    /// Issues some commands and collects the events of `wlan0` until the
+    /// connection ends.
+    fn session(wifi: &WifiInterface) -> (Vec<Result<Vec<u8>, c_int>>, Vec<String>) {
+        let events = wifi.subscribe();
+        let replies = vec![
+            wifi.command_raw("STATUS"),
+            wifi.command_raw("SCAN"),
+            wifi.interface("p2p0").command_raw("P2P_FIND"),
+        ];
+        (replies, events.collect())
+    }
+
+    #[test]
+    fn record_and_replay() {
+        let path = env::temp_dir().join(format!("gonkhal-trace-{}.jsonl", process::id()));
+        let recorder = Recorder::new(FakeSupplicant::new(), &path).unwrap();
+        let recorded = session(&WifiInterface::with_backend("wlan0", recorder));
+        assert_eq!(recorded.0[2], Err(-1));
+        assert_eq!(
+            recorded.1,
+            [
+                "<3>CTRL-EVENT-SCAN-STARTED ",
+                "IFNAME=wlan0 <3>CTRL-EVENT-CONNECTED - Connection to 02:00:00:00:00:02",
+            ]
+        );
+
+        let entries = read_trace(&path).unwrap();
+        let commands = entries
+            .iter()
+            .filter(|entry| matches!(**entry, TraceEntry::Command { .. }))
+            .count();
+        // The end of the connection is recorded too.
+        assert_eq!((commands, entries.len() - commands), (3, 4));
+
+        let replayed = session(&WifiInterface::with_backend(
+            "wlan0",
+            Replay::from_file(&path).unwrap(),
+        ));
+        assert_eq!(replayed, recorded);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_divergence() {
+        let replay = Replay::new(vec![
+            TraceEntry::Command {
+                time_ms: 0,
+                request: "STATUS".to_owned(),
+                reply: Ok(b"wpa_state=SCANNING\n".to_vec()),
+            },
+            TraceEntry::Event {
+                time_ms: 5,
+                event: Err(WIFI_NO_EVENT),
+            },
+        ]);
+        assert_eq!(replay.command_raw("PING"), Err(REPLAY_DIVERGED));
+        assert_eq!(
+            replay.command_raw("STATUS"),
+            Ok(b"wpa_state=SCANNING\n".to_vec())
+        );
+        assert_eq!(replay.wait_for_event_raw(), Err(WIFI_NO_EVENT));
+        assert!(replay.is_exhausted());
+        assert_eq!(replay.divergences(), ["PING"]);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let entries = vec![
+            TraceEntry::Command {
+                time_ms: 12,
+                request: "SET_NETWORK 0 ssid \"caf\\u00e9\"".to_owned(),
+                reply: Ok(b"OK\n".to_vec()),
+            },
+            TraceEntry::Command {
+                time_ms: 15,
+                request: "BOGUS".to_owned(),
+                reply: Err(-1),
+            },
+            TraceEntry::Event {
+                time_ms: 47,
+                event: Ok(b"IFNAME=wlan0 \xff\x00\t\xe2\x82".to_vec()),
+            },
+            TraceEntry::Event {
+                time_ms: 52,
+                event: Err(WIFI_NO_EVENT),
+            },
+        ];
+        for entry in entries {
+            let json = entry.to_json();
+            assert!(!json.contains('\n'));
+            assert_eq!(TraceEntry::from_json(&json), Some(entry));
+        }
+        assert_eq!(TraceEntry::from_json("{\"t\":1,\"type\":\"event\"}"), None);
+    }
+}